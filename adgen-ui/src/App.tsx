@@ -246,7 +246,7 @@ function Dashboard({
         setBudget(cfg.budget_limit_usd ?? null);
         const price = cfg.provider.price_usd_per_image ?? 0;
         if (price > 0) {
-          const est = await getCostEstimate(cfg.orchestrator.target_images, price);
+          const est = await getCostEstimate(cfg.orchestrator.target_images, price, cfg.orchestrator.best_of);
           setEstimate(est.estimated_cost);
         }
       } catch { /* ignore on dashboard */ }
@@ -103,10 +103,20 @@ export function ConfigEditor() {
             <Field label="backoff_base_ms"><input type="number" {...register("orchestrator.backoff_base_ms", { valueAsNumber: true })} className={input()} /></Field>
             <Field label="backoff_factor"><input type="number" step="0.1" {...register("orchestrator.backoff_factor", { valueAsNumber: true })} className={input()} /></Field>
             <Field label="backoff_jitter_ms"><input type="number" {...register("orchestrator.backoff_jitter_ms", { valueAsNumber: true })} className={input()} /></Field>
+            <Field label="max_attempts"><input type="number" {...register("orchestrator.max_attempts", { valueAsNumber: true })} className={input()} /></Field>
           </Section>
 
           <Section title="Dedupe">
             <Field label="enabled"><input type="checkbox" {...register("dedupe.enabled")} /></Field>
+            <Field label="alg">
+              <select {...register("dedupe.alg")} className={input()}>
+                <option value="double_gradient">double_gradient</option>
+                <option value="gradient">gradient</option>
+                <option value="vert_gradient">vert_gradient</option>
+                <option value="mean">mean</option>
+                <option value="blockhash">blockhash</option>
+              </select>
+            </Field>
             <Field label="phash_bits"><input type="number" {...register("dedupe.phash_bits", { valueAsNumber: true })} className={input()} /></Field>
             <Field label="phash_thresh"><input type="number" {...register("dedupe.phash_thresh", { valueAsNumber: true })} className={input()} /></Field>
           </Section>
@@ -114,18 +124,31 @@ export function ConfigEditor() {
           <Section title="Post">
             <Field label="thumbnail"><input type="checkbox" {...register("post.thumbnail")} /></Field>
             <Field label="thumb_max"><input type="number" {...register("post.thumb_max", { valueAsNumber: true })} className={input()} /></Field>
+            <Field label="contact_sheet"><input type="checkbox" {...register("post.contact_sheet")} /></Field>
           </Section>
 
           <Section title="Rewrite">
             <Field label="enabled"><input type="checkbox" {...register("rewrite.enabled")} /></Field>
+            <Field label="kind">
+              <select {...register("rewrite.kind")} className={input()}>
+                <option value="openai">openai</option>
+                <option value="ollama">ollama</option>
+                <option value="noop">noop</option>
+              </select>
+            </Field>
+            <Field label="base_url"><input {...register("rewrite.base_url")} placeholder="http://localhost:11434" className={input()} /></Field>
             <Field label="model"><input {...register("rewrite.model")} className={input()} /></Field>
             <Field label="system"><input {...register("rewrite.system")} className={input()} /></Field>
             <Field label="max_tokens"><input type="number" {...register("rewrite.max_tokens", { valueAsNumber: true })} className={input()} /></Field>
+            <Field label="temperature"><input type="number" step="0.1" {...register("rewrite.temperature", { valueAsNumber: true })} className={input()} /></Field>
+            <Field label="top_p"><input type="number" step="0.05" {...register("rewrite.top_p", { valueAsNumber: true })} className={input()} /></Field>
+            <Field label="max_attempts"><input type="number" {...register("rewrite.max_attempts", { valueAsNumber: true })} className={input()} /></Field>
           </Section>
 
           <Section title="Output">
             <Field label="out_dir"><input {...register("out_dir")} className={input()} /></Field>
             <Field label="seed"><input type="number" {...register("seed", { valueAsNumber: true })} className={input()} /></Field>
+            <Field label="gallery"><input type="checkbox" {...register("gallery")} /></Field>
           </Section>
 
           <div className="col-span-2 flex items-center gap-3">
@@ -24,7 +24,7 @@ export function CostDashboard() {
 
         const price = cfg.provider.price_usd_per_image ?? 0;
         if (price > 0) {
-          const est = await getCostEstimate(cfg.orchestrator.target_images, price);
+          const est = await getCostEstimate(cfg.orchestrator.target_images, price, cfg.orchestrator.best_of);
           setEstimate(est.estimated_cost);
         } else {
           setEstimate(null);
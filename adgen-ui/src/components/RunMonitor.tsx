@@ -7,8 +7,20 @@ type ConnectionState = "connecting" | "connected" | "disconnected" | "reconnecti
 type RunEvent =
   | { type: "started"; run_id: string; total: number }
   | { type: "log"; run_id: string; msg: string }
-  | { type: "progress"; run_id: string; done: number; total: number; cost_so_far: number }
-  | { type: "finished"; run_id: string }
+  | {
+      type: "progress";
+      run_id: string;
+      done: number;
+      total: number;
+      cost_so_far: number;
+      queued: number;
+      in_flight: number;
+    }
+  | {
+      type: "finished";
+      run_id: string;
+      outcome?: { produced: number; duplicates: number; filtered: number; failed: number; moderated: number; blocked: number; total_cost: number };
+    }
   | { type: "failed"; run_id: string; error: string };
 
 const MAX_RECONNECT_ATTEMPTS = 5;
@@ -34,6 +46,7 @@ export function RunMonitor({
   const [total, setTotal] = useState(0);
   const [logs, setLogs] = useState<string[]>([]);
   const [costSoFar, setCostSoFar] = useState(0);
+  const [pipeline, setPipeline] = useState<{ queued: number; inFlight: number } | null>(null);
   const [error, setError] = useState<string | null>(null);
 
   const logRef = useRef<HTMLDivElement | null>(null);
@@ -95,13 +108,19 @@ export function RunMonitor({
               setDone(evt.done);
               setTotal(evt.total);
               setCostSoFar(evt.cost_so_far);
+              setPipeline({ queued: evt.queued ?? 0, inFlight: evt.in_flight ?? 0 });
               onImageAdded?.();
               break;
 
             case "finished":
               setState("finished");
               setConnectionState("disconnected");
-              setLogs((prev) => [...prev, "✅ Finished"]);
+              setLogs((prev) => [
+                ...prev,
+                evt.outcome
+                  ? `✅ Finished: ${evt.outcome.produced} produced, ${evt.outcome.duplicates} duplicates, ${evt.outcome.filtered} filtered, ${evt.outcome.failed} failed, ${evt.outcome.moderated} moderated, ${evt.outcome.blocked} blocked, $${evt.outcome.total_cost.toFixed(4)}`
+                  : "✅ Finished",
+              ]);
               es.close();
               break;
 
@@ -185,6 +204,12 @@ export function RunMonitor({
       <div className="rounded-2xl border border-zinc-800 bg-zinc-900/20 p-4">
         <div className="mb-2 flex items-center justify-between">
           <div className="text-xs text-zinc-400">Progress</div>
+          {pipeline && state === "running" && (
+            <div className="text-xs text-zinc-400">
+              Queued: <span className="text-zinc-200">{pipeline.queued}</span> · In flight:{" "}
+              <span className="text-zinc-200">{pipeline.inFlight}</span>
+            </div>
+          )}
           {costSoFar > 0 && (
             <div className="text-xs text-zinc-400">
               Cost: <span className="text-zinc-200">${costSoFar.toFixed(4)}</span>
@@ -2,25 +2,40 @@ import { API_BASE_URL as BASE } from "./config";
 
 export type RunConfig = {
   provider: { kind: "mock" | "openai"; model: string; width: number; height: number; price_usd_per_image: number };
-  orchestrator: { target_images: number; concurrency: number; queue_cap: number; rate_per_min: number; backoff_base_ms: number; backoff_factor: number; backoff_jitter_ms: number };
-  dedupe: { enabled: boolean; phash_bits: number; phash_thresh: number };
-  post: { thumbnail: boolean; thumb_max: number };
-  rewrite: { enabled: boolean; model: string; system: string; max_tokens: number };
+  orchestrator: { target_images: number; concurrency: number; queue_cap: number; rate_per_min: number; backoff_base_ms: number; backoff_factor: number; backoff_jitter_ms: number; max_attempts?: number; best_of?: number; images_per_call?: number };
+  dedupe: { enabled: boolean; alg?: "mean" | "gradient" | "vert_gradient" | "double_gradient" | "blockhash"; phash_bits: number; phash_thresh: number };
+  post: { thumbnail: boolean; thumb_max: number; contact_sheet?: boolean };
+  rewrite: { enabled: boolean; kind?: "openai" | "ollama" | "noop"; base_url?: string; model: string; system: string; max_tokens: number; temperature?: number; top_p?: number; max_attempts?: number; batch_size?: number; examples?: [string, string][] };
   out_dir: string;
   seed: number;
+  gallery?: boolean;
   budget_limit_usd?: number;
 };
 
-export type Template = { brand: string; product: string; styles: string[] };
+export type InitImage = { path: string; strength?: number };
+export type VariantMode = "random" | "unique" | "cartesian";
+export type StyleEntry = string | { value: string; weight: number };
+export type Template = { brand: string; product: string; styles: string[]; style_weights?: Record<string, number>; audiences?: string[]; backgrounds?: string[]; ctas?: string[]; negative_prompt?: string; init_image?: InitImage; variant_mode?: VariantMode; prompts_file?: string };
 
-type AdTemplateYaml = { brand: string; product: string; styles: string[] };
+type AdTemplateYaml = { brand: string; product: string; styles: StyleEntry[]; audiences?: string[]; backgrounds?: string[]; ctas?: string[] };
 type GeneralPromptYaml = { prompt: string };
-type TemplateYaml = { mode: { AdTemplate: AdTemplateYaml } | { GeneralPrompt: GeneralPromptYaml } };
+type TemplateYaml = { mode: { AdTemplate: AdTemplateYaml } | { GeneralPrompt: GeneralPromptYaml }; negative_prompt?: string; init_image?: InitImage; variant_mode?: VariantMode; prompts_file?: string };
 
 function isRecord(v: unknown): v is Record<string, unknown> {
   return typeof v === "object" && v !== null;
 }
 
+function styleValue(s: unknown): string {
+  return isRecord(s) && typeof s.value === "string" ? s.value : String(s);
+}
+
+// Weighted entries come back as `{ value, weight }`; the editor keeps plain names plus a weight map.
+function styleWeights(styles: unknown[]): Record<string, number> | undefined {
+  const weighted = styles.filter((s): s is { value: string; weight: number } =>
+    isRecord(s) && typeof s.value === "string" && typeof s.weight === "number");
+  return weighted.length ? Object.fromEntries(weighted.map((s) => [s.value, s.weight])) : undefined;
+}
+
 function toTemplateForm(v: unknown): Template {
   if (!isRecord(v)) {
     throw new Error("Template payload is not an object");
@@ -50,7 +65,17 @@ function toTemplateForm(v: unknown): Template {
       return {
         brand: ad.brand,
         product: ad.product,
-        styles: ad.styles.map(String),
+        styles: ad.styles.map(styleValue),
+        style_weights: styleWeights(ad.styles),
+        audiences: Array.isArray(ad.audiences) ? ad.audiences.map(String) : undefined,
+        backgrounds: Array.isArray(ad.backgrounds) ? ad.backgrounds.map(String) : undefined,
+        ctas: Array.isArray(ad.ctas) ? ad.ctas.map(String) : undefined,
+        negative_prompt: typeof v.negative_prompt === "string" ? v.negative_prompt : undefined,
+        init_image: isRecord(v.init_image) && typeof v.init_image.path === "string"
+          ? { path: v.init_image.path, strength: typeof v.init_image.strength === "number" ? v.init_image.strength : undefined }
+          : undefined,
+        variant_mode: v.variant_mode === "cartesian" || v.variant_mode === "unique" ? v.variant_mode : undefined,
+        prompts_file: typeof v.prompts_file === "string" ? v.prompts_file : undefined,
       };
     }
     throw new Error("Invalid AdTemplate shape from backend");
@@ -71,9 +96,19 @@ function toTemplateYaml(template: Template): TemplateYaml {
       AdTemplate: {
         brand: template.brand,
         product: template.product,
-        styles: template.styles,
+        styles: template.styles.map((s) => {
+          const weight = template.style_weights?.[s];
+          return weight === undefined ? s : { value: s, weight };
+        }),
+        ...(template.audiences?.length ? { audiences: template.audiences } : {}),
+        ...(template.backgrounds?.length ? { backgrounds: template.backgrounds } : {}),
+        ...(template.ctas?.length ? { ctas: template.ctas } : {}),
       },
     },
+    ...(template.negative_prompt ? { negative_prompt: template.negative_prompt } : {}),
+    ...(template.init_image ? { init_image: template.init_image } : {}),
+    ...(template.variant_mode && template.variant_mode !== "random" ? { variant_mode: template.variant_mode } : {}),
+    ...(template.prompts_file ? { prompts_file: template.prompts_file } : {}),
   };
 }
 
@@ -131,6 +166,21 @@ export async function listImages(): Promise<{ name: string; url: string; created
   return r.json();
 }
 
+export type StatusCounts = { saved: number; duplicate: number; filtered: number; failed: number };
+
+export type RunCoverage = {
+  run_id: string;
+  items: number;
+  /** field (e.g. "style") -> value -> outcome counts */
+  fields: Record<string, Record<string, StatusCounts>>;
+};
+
+export async function getRunCoverage(runId: string): Promise<RunCoverage> {
+  const r = await fetch(`${BASE}/api/run/${encodeURIComponent(runId)}/coverage`);
+  if (!r.ok) throw new Error("Failed to load run coverage");
+  return r.json();
+}
+
 export type ValidationError = {
   field: string;
   message: string;
@@ -222,11 +272,12 @@ export async function getCostSummary(): Promise<CostSummary> {
 export async function getCostEstimate(
   target_images: number,
   price_per_image: number,
+  best_of = 1,
 ): Promise<CostEstimate> {
   const r = await fetch(`${BASE}/api/cost/estimate`, {
     method: "POST",
     headers: { "content-type": "application/json" },
-    body: JSON.stringify({ target_images, price_per_image }),
+    body: JSON.stringify({ target_images, price_per_image, best_of }),
   });
   if (!r.ok) throw new Error("Failed to get cost estimate");
   return r.json();
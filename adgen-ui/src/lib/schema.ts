@@ -17,25 +17,37 @@ export const RunConfigSchema = z.object({
     backoff_base_ms: z.number().int().min(100, "Backoff base must be at least 100ms").max(60000, "Backoff base must be at most 60000ms"),
     backoff_factor: z.number().min(1.1, "Backoff factor must be at least 1.1").max(5.0, "Backoff factor must be at most 5.0"),
     backoff_jitter_ms: z.number().int().nonnegative().max(10000, "Jitter must be at most 10000ms"),
+    max_attempts: z.number().int().min(1, "Max attempts must be at least 1").max(20, "Max attempts must be at most 20").optional(),
   }),
   dedupe: z.object({
     enabled: z.boolean(),
-    phash_bits: z.number().int().min(4, "pHash bits must be at least 4").max(64, "pHash bits must be at most 64"),
+    alg: z.enum(["mean", "gradient", "vert_gradient", "double_gradient", "blockhash"]).optional(),
+    phash_bits: z.number().int().min(4, "pHash bits must be at least 4").max(64, "pHash bits must be at most 64")
+      .refine((n) => Number.isInteger(Math.sqrt(n)), "pHash bits must be a square number (e.g. 16, 36, 64)"),
     phash_thresh: z.number().int().nonnegative().max(32, "pHash threshold must be at most 32"),
   }),
   post: z.object({
     thumbnail: z.boolean(),
     thumb_max: z.number().int().min(16, "Thumbnail size must be at least 16").max(1024, "Thumbnail size must be at most 1024"),
+    contact_sheet: z.boolean().optional(),
   }),
   rewrite: z.object({
     enabled: z.boolean(),
+    kind: z.enum(["openai", "ollama", "noop"]).optional(),
+    base_url: z.string().optional(),
     model: z.string().optional(),
     system: z.string().optional(),
     max_tokens: z.number().int().min(1, "Max tokens must be at least 1").max(4096, "Max tokens must be at most 4096").optional(),
+    temperature: z.number().min(0, "Temperature must be at least 0").max(2, "Temperature must be at most 2").optional(),
+    top_p: z.number().gt(0, "top_p must be above 0").max(1, "top_p must be at most 1").optional(),
     cache_file: z.string().optional(),
+    max_attempts: z.number().int().min(1, "Max attempts must be at least 1").max(20, "Max attempts must be at most 20").optional(),
+    examples: z.array(z.tuple([z.string(), z.string()])).optional(),
+    batch_size: z.number().int().min(1, "Batch size must be at least 1").max(50, "Batch size must be at most 50").optional(),
   }),
   out_dir: z.string().min(1, "Output directory is required"),
   seed: z.number().int().nonnegative(),
+  gallery: z.boolean().optional(),
   budget_limit_usd: z.number().nonnegative().optional(),
 }).refine(
   (data) => data.provider.kind !== "openai" || (data.provider.api_key_env && data.provider.api_key_env.length > 0),
@@ -43,6 +55,17 @@ export const RunConfigSchema = z.object({
     message: "api_key_env is required when using OpenAI provider",
     path: ["provider", "api_key_env"],
   }
+).refine(
+  (data) => {
+    const side = Math.sqrt(data.dedupe.phash_bits);
+    const alg = data.dedupe.alg ?? "double_gradient";
+    const multiple = alg === "double_gradient" ? 2 : alg === "blockhash" ? 4 : 1;
+    return !Number.isInteger(side) || side % multiple === 0;
+  },
+  {
+    message: "pHash bits must be 16, 36 or 64 for double_gradient and 16 or 64 for blockhash",
+    path: ["dedupe", "phash_bits"],
+  }
 );
 
 export type RunConfig = z.infer<typeof RunConfigSchema>;
@@ -51,5 +74,13 @@ export const TemplateSchema = z.object({
   brand: z.string().min(1),
   product: z.string().min(1),
   styles: z.array(z.string().min(1)).min(1),
+  style_weights: z.record(z.string(), z.number().min(0)).optional(),
+  audiences: z.array(z.string().min(1)).optional(),
+  backgrounds: z.array(z.string().min(1)).optional(),
+  ctas: z.array(z.string().min(1)).optional(),
+  negative_prompt: z.string().optional(),
+  init_image: z.object({ path: z.string().min(1), strength: z.number().min(0).max(1).optional() }).optional(),
+  variant_mode: z.enum(["random", "unique", "cartesian"]).optional(),
+  prompts_file: z.string().optional(),
 });
 export type Template = z.infer<typeof TemplateSchema>;
@@ -1,7 +1,7 @@
 use std::{path::{Component, PathBuf}, sync::Arc};
 use anyhow::Result;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::{header, HeaderValue, StatusCode},
     response::{sse::{Event, Sse}, IntoResponse},
     routing::{get, post},
@@ -14,7 +14,7 @@ use tokio_stream::wrappers::BroadcastStream;
 use tower_http::cors::CorsLayer;
 use uuid::Uuid;
 
-use crate::{auth::{self, UserResponse}, config::{Mode, RunCfg, TemplateYaml}, cost_tracking, events::RunEvent, run_once};
+use crate::{auth::{self, UserResponse}, config::{Mode, RunCfg, TemplateYaml}, cost_tracking, events::RunEvent, run_once, RunOverrides};
 use anyhow::Context;
 
 #[derive(Clone)]
@@ -59,6 +59,11 @@ pub async fn serve(bind: String, config_path: PathBuf, template_path: PathBuf, p
         .route("/api/run", post(start_run))
         .route("/api/run/current", get(get_current_run))
         .route("/api/run/{id}/events", get(run_events))
+        .route("/api/run/{id}/reprocess", post(reprocess_run))
+        .route("/api/run/{id}/coverage", get(run_coverage))
+        .route("/api/run/{id}/zip", get(run_zip))
+        .route("/api/runs/{id}/events/replay", get(replay_run_events))
+        .route("/api/stream/thumbnails", get(thumbnail_stream))
         .route("/api/images", get(list_images))
         .route("/images/{name}", get(get_image))
         .route("/api/register", post(register))
@@ -168,13 +173,40 @@ async fn validate_config(
 
     // Validate API key for OpenAI provider
     if req.config.provider.kind == "openai" {
-        let key_env = req.config.provider.api_key_env.as_deref().unwrap_or("OPENAI_API_KEY");
-        if std::env::var(key_env).is_err() {
-            errors.push(ValidationError {
-                field: "provider.api_key_env".to_string(),
-                message: format!("Environment variable {} not set", key_env),
-                suggestion: Some(format!("Run: export {}=sk-...", key_env)),
-            });
+        let needs_key = req.config.provider.auth_style != crate::config::AuthStyle::None;
+        match crate::secrets::from_cfg(&req.config.secrets) {
+            Ok(_) if !needs_key => {}
+            Ok(secrets) => for key_env in req.config.provider.key_envs("OPENAI_API_KEY") {
+                if let Err(e) = secrets.get(key_env) {
+                    errors.push(ValidationError {
+                        field: if req.config.provider.api_key_envs.is_empty() { "provider.api_key_env" } else { "provider.api_key_envs" }.to_string(),
+                        message: format!("{e:#}"),
+                        suggestion: Some(match req.config.secrets.backend {
+                            crate::config::SecretsBackend::Env => format!("Run: export {}=sk-...", key_env),
+                            crate::config::SecretsBackend::File => format!("Write the key to {key_env} in secrets.dir"),
+                        }),
+                    });
+                }
+            },
+            Err(e) => errors.push(ValidationError {
+                field: "secrets".to_string(),
+                message: format!("{e:#}"),
+                suggestion: None,
+            }),
+        }
+        let model = req.config.provider.model.as_deref().and_then(|m| req.config.resolve_model(m).ok()).unwrap_or_else(|| "gpt-image-1.5".into());
+        let (quality, style) = (req.config.provider.quality.as_deref(), req.config.provider.style.as_deref());
+        for (field, check) in [
+            ("provider.quality", crate::providers::OpenAIProvider::check_options(&model, quality, None)),
+            ("provider.style", crate::providers::OpenAIProvider::check_options(&model, None, style)),
+        ] {
+            if let Err(e) = check {
+                errors.push(ValidationError {
+                    field: field.to_string(),
+                    message: format!("{e:#}"),
+                    suggestion: Some("dall-e-3: quality standard|hd, style vivid|natural; gpt-image models: quality low|medium|high|auto".to_string()),
+                });
+            }
         }
     }
 
@@ -188,6 +220,14 @@ async fn validate_config(
                     suggestion: None,
                 });
             }
+            if tpl.styles.iter().any(|s| !s.weight().is_finite() || s.weight() < 0.0)
+                || (!tpl.styles.is_empty() && tpl.styles.iter().all(|s| s.weight() == 0.0)) {
+                errors.push(ValidationError {
+                    field: "mode.AdTemplate.styles".to_string(),
+                    message: "Style weights must be non-negative and at least one must be above 0".to_string(),
+                    suggestion: None,
+                });
+            }
 
             if tpl.brand.trim().is_empty() {
                 errors.push(ValidationError {
@@ -247,6 +287,10 @@ async fn start_run(State(st): State<AppState>) -> Result<Json<StartRunResp>, Api
         }
     }
 
+    // out_dir is needed up front to persist the event log
+    let txt = tokio::fs::read_to_string(&st.config_path).await.map_err(ApiErr::from)?;
+    let cfg: RunCfg = serde_yaml::from_str(&txt).map_err(ApiErr::from)?;
+
     // create run id
     let run_id = format!("run-{}", Uuid::new_v4());
 
@@ -254,6 +298,16 @@ async fn start_run(State(st): State<AppState>) -> Result<Json<StartRunResp>, Api
     *st.current_run.lock().await = Some(run_id.clone());
 
     let tx = st.events_tx.clone();
+
+    // persist this run's events so late clients can replay them
+    let log_rx = tx.subscribe();
+    let log_run_id = run_id.clone();
+    tokio::spawn(async move {
+        if let Err(e) = crate::events::persist_run_events(log_rx, cfg.out_dir, log_run_id).await {
+            eprintln!("event log error: {e:#}");
+        }
+    });
+
     let cfg_path = st.config_path.clone();
     let tpl_path = st.template_path.clone();
     let current_run_ref = st.current_run.clone();
@@ -262,7 +316,7 @@ async fn start_run(State(st): State<AppState>) -> Result<Json<StartRunResp>, Api
     let spawn_run_id = run_id.clone();
     tokio::spawn(async move {
         tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-        let result = run_once(cfg_path, tpl_path, None, false, Some(spawn_run_id), Some(tx)).await;
+        let result = run_once(cfg_path, tpl_path, RunOverrides::default(), Some(spawn_run_id), Some(tx)).await;
 
         // Clear current run on completion or failure
         *current_run_ref.lock().await = None;
@@ -275,6 +329,53 @@ async fn start_run(State(st): State<AppState>) -> Result<Json<StartRunResp>, Api
     Ok(Json(StartRunResp { run_id }))
 }
 
+/// Re-run post-processing over a finished run with new `post` settings, as a
+/// background job whose events stream under the returned `run_id`.
+async fn reprocess_run(
+    State(st): State<AppState>,
+    Path(id): Path<String>,
+    Json(post): Json<crate::config::PostCfg>,
+) -> Result<Json<StartRunResp>, ApiErr> {
+    let baked = crate::reprocess::baked_in(&post);
+    if !baked.is_empty() {
+        return Err(ApiErr::bad_request(format!(
+            "{} can't be reprocessed: saved images already have them applied and the originals aren't kept",
+            baked.join(", "),
+        )));
+    }
+    if st.current_run.lock().await.as_deref() == Some(id.as_str()) {
+        return Err(ApiErr::conflict(format!("run {id} is still generating; reprocess it once it finishes")));
+    }
+    let txt = tokio::fs::read_to_string(&st.config_path).await.map_err(ApiErr::from)?;
+    let cfg: RunCfg = serde_yaml::from_str(&txt).map_err(ApiErr::from)?;
+    let run = crate::reprocess::find_run(&cfg.out_dir, &id).await.map_err(ApiErr::internal)?;
+    if run.total() == 0 {
+        return Err(ApiErr::not_found(format!("no images found for run {id}")));
+    }
+
+    let job_id = format!("reprocess-{}", Uuid::new_v4());
+    let tx = st.events_tx.clone();
+    let log_rx = tx.subscribe();
+    let (log_dir, log_job_id) = (cfg.out_dir.clone(), job_id.clone());
+    tokio::spawn(async move {
+        if let Err(e) = crate::events::persist_run_events(log_rx, log_dir, log_job_id).await {
+            eprintln!("event log error: {e:#}");
+        }
+    });
+
+    let spawn_job_id = job_id.clone();
+    tokio::spawn(async move {
+        // same grace period as start_run so the client can subscribe first
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        if let Err(e) = crate::reprocess::reprocess_run(cfg.out_dir, run, post, spawn_job_id.clone(), tx.clone()).await {
+            let _ = tx.send(RunEvent::Failed { run_id: spawn_job_id, error: format!("{e:#}") });
+            eprintln!("reprocess error: {e:#}");
+        }
+    });
+
+    Ok(Json(StartRunResp { run_id: job_id }))
+}
+
 #[derive(Serialize)]
 struct CurrentRunResp { run_id: Option<String> }
 
@@ -292,15 +393,8 @@ pub async fn run_events(
     let stream = BroadcastStream::new(rx)
         .filter_map(|msg| async move { msg.ok() })
         .filter(move |evt: &RunEvent| {
-            // keep only events for this run_id
-            let ok = match evt {
-                RunEvent::Started { run_id: id, .. } => id == &run_id,
-                RunEvent::Log { run_id: id, .. } => id == &run_id,
-                RunEvent::Progress { run_id: id, .. } => id == &run_id,
-                RunEvent::Finished { run_id: id } => id == &run_id,
-                RunEvent::Failed { run_id: id, .. } => id == &run_id,
-            };
-            futures_util::future::ready(ok)
+            // keep only events for this run_id; previews go to /api/stream/thumbnails
+            futures_util::future::ready(evt.run_id() == run_id && !evt.is_thumbnail())
         })
         .map(|evt| {
             let json = serde_json::to_string(&evt).unwrap();
@@ -309,6 +403,85 @@ pub async fn run_events(
 
     Sse::new(stream)
 }
+
+/// Live feed of inline base64 previews for every image saved by any run.
+async fn thumbnail_stream(
+    State(st): State<AppState>,
+) -> Sse<impl futures_util::Stream<Item = Result<Event, std::convert::Infallible>>> {
+    let rx = st.events_tx.subscribe();
+
+    let stream = BroadcastStream::new(rx)
+        .filter_map(|msg| async move { msg.ok().filter(RunEvent::is_thumbnail) })
+        .map(|evt| {
+            let json = serde_json::to_string(&evt).unwrap();
+            Ok(Event::default().event("thumbnail").data(json))
+        });
+
+    Sse::new(stream)
+}
+
+/// Per-field (style, ...) outcome counts for a run, from the manifest.
+async fn run_coverage(
+    State(st): State<AppState>,
+    Path(run_id): Path<String>,
+) -> Result<Json<crate::manifest::Coverage>, ApiErr> {
+    if !is_safe_filename(&run_id) {
+        return Err(ApiErr::bad_request("invalid run id"));
+    }
+    let txt = tokio::fs::read_to_string(&st.config_path).await.map_err(ApiErr::from)?;
+    let cfg: RunCfg = serde_yaml::from_str(&txt).map_err(ApiErr::from)?;
+    let cov = crate::manifest::coverage(&cfg.out_dir, &run_id).await.map_err(ApiErr::internal)?;
+    if cov.items == 0 {
+        // older runs have no run ids in the manifest: known (empty breakdown) if they left an event log or sidecars
+        let known = crate::events::read_event_log(&cfg.out_dir, &run_id).await.map_err(ApiErr::from)?.is_some()
+            || crate::reprocess::find_run(&cfg.out_dir, &run_id).await.map_err(ApiErr::internal)?.total() > 0;
+        if !known {
+            return Err(ApiErr::not_found(format!("Unknown run: {}", run_id)));
+        }
+    }
+    Ok(Json(cov))
+}
+
+/// The run as a zip download, built (or rebuilt) on request and streamed from disk.
+async fn run_zip(
+    State(st): State<AppState>,
+    Path(run_id): Path<String>,
+) -> Result<axum::response::Response, ApiErr> {
+    if !is_safe_filename(&run_id) {
+        return Err(ApiErr::bad_request("invalid run id"));
+    }
+    let txt = tokio::fs::read_to_string(&st.config_path).await.map_err(ApiErr::from)?;
+    let cfg: RunCfg = serde_yaml::from_str(&txt).map_err(ApiErr::from)?;
+    let Some((path, _)) = crate::io::zip_run(&cfg.out_dir, &run_id).await.map_err(ApiErr::internal)? else {
+        return Err(ApiErr::not_found(format!("Unknown run: {}", run_id)));
+    };
+    let file = tokio::fs::File::open(&path).await.map_err(ApiErr::from)?;
+    let len = file.metadata().await.map_err(ApiErr::from)?.len();
+    let disposition = format!("attachment; filename=\"{run_id}.zip\"");
+    Ok((
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("application/zip")),
+            (header::CONTENT_LENGTH, HeaderValue::from(len)),
+            (header::CONTENT_DISPOSITION, HeaderValue::from_str(&disposition).map_err(ApiErr::internal)?),
+        ],
+        axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+    ).into_response())
+}
+
+async fn replay_run_events(
+    State(st): State<AppState>,
+    Path(run_id): Path<String>,
+) -> Result<Json<Vec<RunEvent>>, ApiErr> {
+    if !is_safe_filename(&run_id) {
+        return Err(ApiErr::bad_request("invalid run id"));
+    }
+    let txt = tokio::fs::read_to_string(&st.config_path).await.map_err(ApiErr::from)?;
+    let cfg: RunCfg = serde_yaml::from_str(&txt).map_err(ApiErr::from)?;
+    match crate::events::read_event_log(&cfg.out_dir, &run_id).await.map_err(ApiErr::from)? {
+        Some(events) => Ok(Json(events)),
+        None => Err(ApiErr::not_found(format!("Unknown run: {}", run_id))),
+    }
+}
 async fn register(
     State(st): State<AppState>,
     Json(req): Json<RegisterReq>,
@@ -380,10 +553,22 @@ async fn login(
     Ok(Json(UserResponse::from(user)))
 }
 
-async fn cost_summary(State(st): State<AppState>) -> Result<Json<cost_tracking::CostSummary>, ApiErr> {
+#[derive(Deserialize)]
+struct CostSummaryQuery {
+    since: Option<String>,
+}
+
+async fn cost_summary(
+    State(st): State<AppState>,
+    Query(q): Query<CostSummaryQuery>,
+) -> Result<Json<cost_tracking::CostSummary>, ApiErr> {
+    let since = match q.since.as_deref() {
+        Some(s) => Some(cost_tracking::parse_since(s).map_err(|e| ApiErr::bad_request(e.to_string()))?),
+        None => None,
+    };
     let txt = tokio::fs::read_to_string(&st.config_path).await.map_err(ApiErr::from)?;
     let cfg: RunCfg = serde_yaml::from_str(&txt).map_err(ApiErr::from)?;
-    let summary = cost_tracking::compute_cost_summary(&cfg.out_dir)
+    let summary = cost_tracking::compute_cost_summary(&cfg.out_dir, since)
         .await
         .map_err(ApiErr::from)?;
     Ok(Json(summary))
@@ -393,6 +578,8 @@ async fn cost_summary(State(st): State<AppState>) -> Result<Json<cost_tracking::
 struct CostEstimateReq {
     target_images: u64,
     price_per_image: f64,
+    #[serde(default)]
+    best_of: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -402,14 +589,35 @@ struct CostEstimateResp {
 
 async fn cost_estimate(Json(req): Json<CostEstimateReq>) -> Json<CostEstimateResp> {
     Json(CostEstimateResp {
-        estimated_cost: cost_tracking::estimate_cost(req.target_images, req.price_per_image),
+        estimated_cost: cost_tracking::estimate_cost(req.target_images * req.best_of.unwrap_or(1).max(1), req.price_per_image),
     })
 }
 
 #[derive(Serialize)]
-struct ImageItem { name: String, url: String, created_ms: u128 }
+struct ImageItem {
+    name: String,
+    url: String,
+    created_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aesthetic_score: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct ListImagesQuery {
+    /// `created` (default, newest first) or `aesthetic` (highest score first, unscored last).
+    sort: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ScoredSidecar { id: u64, #[serde(default)] batch_index: Option<u32>, provider: String, model: String, aesthetic_score: Option<f64> }
+
+async fn list_images(State(st): State<AppState>, Query(q): Query<ListImagesQuery>) -> Result<Json<Vec<ImageItem>>, ApiErr> {
+    let by_aesthetic = match q.sort.as_deref() {
+        None | Some("created") => false,
+        Some("aesthetic") => true,
+        Some(other) => return Err(ApiErr::bad_request(format!("unknown sort `{other}` (expected created or aesthetic)"))),
+    };
 
-async fn list_images(State(st): State<AppState>) -> Result<Json<Vec<ImageItem>>, ApiErr> {
     // read config to know out_dir
     let txt = tokio::fs::read_to_string(&st.config_path).await.map_err(ApiErr::from)?;
     let cfg: RunCfg = serde_yaml::from_str(&txt).map_err(ApiErr::from)?;
@@ -431,10 +639,28 @@ async fn list_images(State(st): State<AppState>) -> Result<Json<Vec<ImageItem>>,
             url: format!("/images/{name}"),
             name,
             created_ms: created,
+            aesthetic_score: None,
         });
     }
 
     items.sort_by_key(|i| std::cmp::Reverse(i.created_ms));
+    if by_aesthetic {
+        let scores: std::collections::HashMap<String, f64> = crate::io::read_sidecars::<ScoredSidecar>(&out_dir).await
+            .map_err(ApiErr::internal)?
+            .into_iter()
+            .filter_map(|s| Some((format!("{}.png", crate::io::image_stem(s.id, s.batch_index, &s.provider, &s.model)), s.aesthetic_score?)))
+            .collect();
+        for item in &mut items {
+            item.aesthetic_score = scores.get(&item.name).copied();
+        }
+        // stable sort: ties (and unscored images) stay newest-first
+        items.sort_by(|a, b| match (a.aesthetic_score, b.aesthetic_score) {
+            (Some(x), Some(y)) => y.total_cmp(&x),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        });
+    }
     Ok(Json(items))
 }
 
@@ -531,6 +757,15 @@ impl ApiErr {
         }
     }
 
+    fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            code: "not_found".to_string(),
+            message: message.into(),
+            suggestion: None,
+        }
+    }
+
     fn unauthorized() -> Self {
         Self {
             status: StatusCode::UNAUTHORIZED,
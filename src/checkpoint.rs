@@ -0,0 +1,289 @@
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+pub const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// Save after this many items finish (and always at the end of a run).
+const SAVE_EVERY: u64 = 8;
+
+/// Where a run can pick up from: every id below `next_id` is finished, and
+/// `source_pos` is the prompt source's position just before drawing `next_id`.
+/// Ids at or above `next_id` that already finished are listed in `completed`
+/// so a resumed run skips them (their prompts are still drawn, to keep the
+/// sequence identical). Finished ids include dropped and failed ones; `saved`
+/// counts only those that saved an image, which is what `target_images` is about.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub run_id: String,
+    pub seed: u64,
+    pub next_id: u64,
+    pub source_pos: Option<u128>,
+    #[serde(default)]
+    pub completed: Vec<u64>,
+    /// Absent in checkpoints from before it was recorded.
+    #[serde(default)]
+    pub saved: Option<u64>,
+    pub updated_at: String,
+}
+
+impl Checkpoint {
+    /// Mark more saved ids finished, e.g. images the manifest recorded after the last save.
+    pub fn absorb(&mut self, ids: impl IntoIterator<Item = u64>) -> usize {
+        let before = self.completed.len();
+        self.completed.extend(ids.into_iter().filter(|&id| id >= self.next_id));
+        self.completed.sort_unstable();
+        self.completed.dedup();
+        let added = self.completed.len() - before;
+        if let Some(saved) = &mut self.saved { *saved += added as u64; }
+        added
+    }
+
+    /// Ids that saved an image; older checkpoints only have finished ids to go on.
+    pub fn saved_count(&self) -> u64 {
+        self.saved.unwrap_or((self.next_id - 1) + self.completed.len() as u64)
+    }
+}
+
+pub async fn load(out_dir: &Path) -> Result<Option<Checkpoint>> {
+    let path = out_dir.join(CHECKPOINT_FILE);
+    match tokio::fs::read(&path).await {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes).with_context(|| format!("parse {}", path.display()))?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Tracks dispatched/finished ids during a run and writes `checkpoint.json`.
+pub struct Checkpointer {
+    path: PathBuf,
+    run_id: String,
+    seed: u64,
+    state: Mutex<State>,
+    write: tokio::sync::Mutex<()>,
+}
+
+struct State {
+    /// Dispatched but not finished, with the source position before each was drawn.
+    pending: BTreeMap<u64, Option<u128>>,
+    /// Finished ids not yet folded into the low-water mark.
+    completed: BTreeSet<u64>,
+    /// The next id the producer will draw, and the source position for it.
+    next: (u64, Option<u128>),
+    /// Finished ids that saved an image, this session and earlier ones.
+    saved: u64,
+    finished_since_save: u64,
+}
+
+impl Checkpointer {
+    pub fn new(out_dir: &Path, run_id: String, seed: u64, resume: Option<&Checkpoint>) -> Self {
+        let (next, completed, saved) = match resume {
+            Some(cp) => ((cp.next_id, cp.source_pos), cp.completed.iter().copied().collect(), cp.saved_count()),
+            None => ((1, None), BTreeSet::new(), 0),
+        };
+        Self {
+            path: out_dir.join(CHECKPOINT_FILE),
+            run_id,
+            seed,
+            state: Mutex::new(State { pending: BTreeMap::new(), completed, next, saved, finished_since_save: 0 }),
+            write: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Producer drew `id` at `pos`; the source is now at `next_pos`.
+    pub fn dispatched(&self, id: u64, pos: Option<u128>, next_pos: Option<u128>) {
+        let mut st = self.state.lock();
+        st.pending.insert(id, pos);
+        st.next = (id + 1, next_pos);
+    }
+
+    /// Producer drew `id` only to advance the source (it finished in an earlier session).
+    pub fn skipped(&self, id: u64, next_pos: Option<u128>) {
+        self.state.lock().next = (id + 1, next_pos);
+    }
+
+    /// `id` reached a final state (saved, dropped or failed). Saves every few items.
+    pub async fn finished(&self, id: u64, saved: bool) -> Result<()> {
+        let due = {
+            let mut st = self.state.lock();
+            st.pending.remove(&id);
+            st.completed.insert(id);
+            if saved { st.saved += 1; }
+            st.finished_since_save += 1;
+            st.finished_since_save >= SAVE_EVERY
+        };
+        if due { self.save().await } else { Ok(()) }
+    }
+
+    fn snapshot(&self) -> Checkpoint {
+        let mut st = self.state.lock();
+        st.finished_since_save = 0;
+        let (next_id, source_pos) = match st.pending.first_key_value() {
+            Some((&id, &pos)) => (id, pos),
+            None => st.next,
+        };
+        // everything below the low-water mark is implied; drop it from the list
+        st.completed = st.completed.split_off(&next_id);
+        Checkpoint {
+            run_id: self.run_id.clone(),
+            seed: self.seed,
+            next_id,
+            source_pos,
+            completed: st.completed.iter().copied().collect(),
+            saved: Some(st.saved),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        let _w = self.write.lock().await;
+        let bytes = serde_json::to_vec_pretty(&self.snapshot())?;
+        let tmp = self.path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, bytes).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::RunEvent;
+    use crate::orchestrator::{run_orchestrator, OrchestratorCfg, OrchestratorExtras, RetryPolicy, RunOutcome};
+    use crate::providers::{GenOpts, ImageProvider, ImageResult, MockProvider};
+    use sha2::{Digest, Sha256};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use tokio::sync::{broadcast, Notify};
+
+    const TEMPLATE: &str = "mode: !AdTemplate\n  brand: Acme\n  product: Soda\n  styles: [neon, retro, pastel]\n  audiences: [teens, parents]\n";
+    const TARGET: u64 = 10;
+
+    /// The mock provider, failing (without retry) every image whose seed is divisible by 3.
+    struct Flaky(MockProvider);
+
+    impl ImageProvider for Flaky {
+        fn generate<'a>(&'a self, prompt: &'a str) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+            self.0.generate(prompt)
+        }
+        fn generate_with_opts<'a>(&'a self, prompt: &'a str, opts: &'a GenOpts) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+            if opts.seed.is_some_and(|s| s % 3 == 0) {
+                return Box::pin(async { anyhow::bail!("flaky") });
+            }
+            self.0.generate_with_opts(prompt, opts)
+        }
+        fn supports_seed(&self) -> bool { true }
+        fn name(&self) -> &str { "mock" }
+        fn model(&self) -> &str { self.0.model() }
+    }
+
+    /// One session over `out_dir`; with `stop_after`, cancelled once that many images are saved.
+    async fn session(out_dir: &Path, run_id: &str, resume: Option<Checkpoint>, stop_after: Option<u64>) -> RunOutcome {
+        let provider = Arc::new(Flaky(MockProvider { model: "mock-v1".into(), w: 32, h: 32, pattern: Default::default(), render_text: false, seed: None }));
+        let source = crate::prompts::variant_source(serde_yaml::from_str(TEMPLATE).unwrap(), 7).await.unwrap();
+        let (events, mut rx) = broadcast::channel(1024);
+        let cancel = Arc::new(Notify::new());
+        if let Some(n) = stop_after {
+            let cancel = cancel.clone();
+            tokio::spawn(async move {
+                while let Ok(evt) = rx.recv().await {
+                    if matches!(evt, RunEvent::Progress { done, .. } if done >= n) { cancel.notify_one(); break; }
+                }
+            });
+        }
+        let post = crate::post::PostProcessor::new(&serde_yaml::from_str("{ thumbnail: false, thumb_max: 16 }").unwrap()).unwrap();
+        run_orchestrator(
+            provider,
+            source,
+            OrchestratorCfg {
+                run_id: run_id.into(),
+                out_dir: out_dir.to_path_buf(),
+                target_images: TARGET,
+                concurrency: 2,
+                queue_cap: 4,
+                rate_per_min: 60_000,
+                price_usd_per_image: 0.0,
+                retry: RetryPolicy { max_attempts: 1, base_ms: 1, factor: 1.0, jitter_ms: 0 },
+                best_of: 1,
+                images_per_call: 1,
+                max_cost_usd: None,
+                ordered_output: false,
+                max_prompts_factor: 3.0,
+                stop_on_ctrl_c: false,
+                cancel: Some(cancel),
+                init_image: None,
+                seed: 7,
+                slow_start_ms: None,
+                sidecar: Default::default(),
+                checkpoint: Arc::new(Checkpointer::new(out_dir, run_id.into(), 7, resume.as_ref())),
+                resume_from: resume,
+                progress: None,
+                events: Some(events),
+            },
+            OrchestratorExtras {
+                rewriter: None,
+                rewriter_model: None,
+                rewriter_system: None,
+                rewriter_examples: vec![],
+                rewriter_sampling: Default::default(),
+                rewrite_cache: None,
+                rewrite_price_per_1k_tokens: 0.0,
+                rewrite_max_cost_usd: None,
+                rewrite_max_attempts: 1,
+                rewrite_batch_size: 1,
+                post: Arc::new(post),
+                dedupe: None,
+                aesthetic: None,
+                on_image: None,
+                sink: Arc::new(crate::sink::LocalSink::new(out_dir)),
+            },
+        ).await.unwrap()
+    }
+
+    /// `(id, prompt, sha256 of the pixels)` of every saved manifest record, across runs
+    /// (the PNG text chunks name the run, so the files themselves differ).
+    async fn saved_set(out_dir: &Path) -> BTreeSet<(u64, String, String)> {
+        let txt = tokio::fs::read_to_string(out_dir.join(crate::manifest::MANIFEST_FILE)).await.unwrap();
+        let mut set = BTreeSet::new();
+        for line in txt.lines() {
+            let v: serde_json::Value = serde_json::from_str(line).unwrap();
+            if v["status"] != "saved" { continue; }
+            let png = tokio::fs::read(out_dir.join(v["path_png"].as_str().unwrap())).await.unwrap();
+            let pixels = image::load_from_memory(&png).unwrap().to_rgba8().into_raw();
+            let sha = Sha256::digest(&pixels).iter().map(|b| format!("{b:02x}")).collect();
+            assert!(set.insert((v["id"].as_u64().unwrap(), v["prompt"].as_str().unwrap().to_string(), sha)), "id saved twice: {line}");
+        }
+        set
+    }
+
+    #[tokio::test]
+    async fn interrupted_and_resumed_run_matches_uninterrupted() {
+        let root = std::env::temp_dir().join(format!("adgen-checkpoint-test-{}", uuid::Uuid::new_v4()));
+        let (straight, resumed) = (root.join("straight"), root.join("resumed"));
+        tokio::fs::create_dir_all(&straight).await.unwrap();
+        tokio::fs::create_dir_all(&resumed).await.unwrap();
+
+        let whole = session(&straight, "run-a", None, None).await;
+        assert_eq!(whole.produced, TARGET);
+        assert!(whole.failed > 0);
+
+        let first = session(&resumed, "run-b", None, Some(4)).await;
+        assert!(first.cancelled);
+        assert!(first.produced < TARGET);
+        // the resumed session must make up for the ids that failed before the stop
+        assert!(first.failed > 0);
+        let mut cp = load(&resumed).await.unwrap().expect("checkpoint written on cancel");
+        if let Some((_, ids)) = crate::manifest::saved_ids(&resumed, Some(&cp.run_id)).await.unwrap() { cp.absorb(ids); }
+        let second = session(&resumed, "run-c", Some(cp), None).await;
+        assert_eq!(first.produced + second.produced, TARGET, "first session {first:?}, second {second:?}");
+
+        let expected = saved_set(&straight).await;
+        assert_eq!(expected.len() as u64, TARGET);
+        assert_eq!(saved_set(&resumed).await, expected);
+
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -1,16 +1,76 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProviderCfg{
-    pub kind: String, // "mock" | "openai"
+    pub kind: String, // "mock" | "openai" | "fal" | "imagen" | "stability" | "replicate"
     pub model: Option<String>,
     pub api_key_env: Option<String>,
+    /// Several keys to rotate through, one per request (openai); replaces `api_key_env`.
+    /// `orchestrator.rate_per_min` then applies per key.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub api_key_envs: Vec<String>,
     pub width: Option<u32>,
     pub height: Option<u32>,
     pub price_usd_per_image: Option<f64>,
+    /// How often to poll a pending prediction (replicate).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll_interval_ms: Option<u64>,
+    /// Give up on (and cancel) a prediction still pending after this long (replicate).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_poll_secs: Option<u64>,
+    /// API root for the openai provider, e.g. an Azure deployment
+    /// `https://{resource}.openai.azure.com/openai/deployments/{deployment}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    /// Sent as the `api-version` query parameter (Azure).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub auth_style: AuthStyle,
+    /// Fail any single provider HTTP request that takes longer than this; 0 or unset means no limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_timeout_secs: Option<u64>,
+    /// Image quality (openai): `standard`/`hd` for dall-e-3, `low`/`medium`/`high`/`auto` for gpt-image models.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+    /// `vivid` or `natural` (openai, dall-e-3 only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub style: Option<String>,
+    /// Tried in order when this provider fails a call (after its own error, before retrying).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<ProviderCfg>,
+    /// Keep every generated image here and answer identical requests from it for free.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_dir: Option<PathBuf>,
 }
 
+/// How the API key is sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthStyle{
+    /// `Authorization: Bearer <key>` (OpenAI).
+    #[default] Bearer,
+    /// `api-key: <key>` (Azure OpenAI).
+    ApiKey,
+    /// No key at all, for local OpenAI-compatible servers.
+    None,
+}
+
+impl ProviderCfg {
+    /// Env/secret names holding this provider's key(s): `api_key_envs`, else `api_key_env`, else `default`.
+    pub fn key_envs<'a>(&'a self, default: &'a str) -> Vec<&'a str> {
+        if self.api_key_envs.is_empty() {
+            vec![self.api_key_env.as_deref().unwrap_or(default)]
+        } else {
+            self.api_key_envs.iter().map(String::as_str).collect()
+        }
+    }
+}
+
+fn is_default<T: Default + PartialEq>(v: &T) -> bool { *v == T::default() }
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OrchestratorCfg{
     pub target_images: u64,
@@ -20,16 +80,390 @@ pub struct OrchestratorCfg{
     pub backoff_base_ms: u64,
     pub backoff_factor: f64,
     pub backoff_jitter_ms: u64,
+    /// Provider calls per image before it counts as failed (retries back off as above).
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Start with one worker and add another every `slow_start_ms` until `concurrency`.
+    #[serde(default)]
+    pub slow_start_ms: Option<u64>,
+    /// Aim for one result per distinct prompt; concurrent identical prompts share a provider call.
+    #[serde(default)]
+    pub unique_prompts: bool,
+    /// Generate this many candidates per image and keep the sharpest. Multiplies image cost.
+    #[serde(default = "default_best_of")]
+    pub best_of: usize,
+    /// Ask the provider for this many images per call and keep them all
+    /// (saved as `{id}_1`, `{id}_2`, ...). Can't be combined with `best_of`.
+    #[serde(default = "default_images_per_call")]
+    pub images_per_call: u32,
+    /// Dropped (duplicate, filtered) and failed ids are replaced with new prompts
+    /// until `target_images` are saved, drawing at most this many times the target.
+    #[serde(default = "default_max_prompts_factor")]
+    pub max_prompts_factor: f64,
+    /// Write `manifest.jsonl` and `failures.jsonl` lines in ascending id order
+    /// (buffering ids that finish early) so runs diff cleanly.
+    #[serde(default)]
+    pub ordered_output: bool,
+    /// Per-provider cap on concurrent calls, keyed by provider kind (e.g. `openai: 4`),
+    /// on top of `concurrency`; applies to `provider.fallbacks` entries too.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub provider_concurrency: HashMap<String, usize>,
+    /// Stop dispatching new images once image and rewrite spend would pass this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_cost_usd: Option<f64>,
+}
+
+fn default_true() -> bool { true }
+fn default_max_attempts() -> u32 { 3 }
+fn default_max_prompts_factor() -> f64 { 3.0 }
+fn default_best_of() -> usize { 1 }
+fn default_images_per_call() -> u32 { 1 }
+
+/// Perceptual hash algorithm (`img_hash::HashAlg`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupeAlg{ Mean, Gradient, VertGradient, #[default] DoubleGradient, Blockhash }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DedupeCfg{
+    pub enabled: bool,
+    #[serde(default)]
+    pub alg: DedupeAlg,
+    pub phash_bits: u32,
+    pub phash_thresh: u32,
+    /// Images decoded and hashed in parallel; defaults to the number of CPUs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_concurrency: Option<usize>,
+    /// Keep seen hashes in `out_dir/dedupe-hashes.json` so later runs dedupe against earlier ones.
+    #[serde(default)]
+    pub persist: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostCfg{
+    pub thumbnail: bool,
+    pub thumb_max: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub augment: Option<AugmentCfg>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame: Option<FrameCfg>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<WatermarkCfg>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aspect_ratio: Option<AspectCfg>,
+    /// Added to every channel (-255..=255).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<i32>,
+    /// Percent change; negative values flatten the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contrast: Option<f32>,
+    /// Unsharp-mask blur sigma in pixels (around 1.0 for a light touch).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sharpen: Option<f32>,
+    /// Round the corners to this radius (in px) with transparency, after the frame.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub corner_radius: Option<u32>,
+    /// Write the prompts, model and run id into each saved PNG as text chunks.
+    #[serde(default = "default_true")]
+    pub embed_metadata: bool,
+    /// Drop text/EXIF metadata from saved images (wins over `embed_metadata`).
+    #[serde(default)]
+    pub strip_metadata: bool,
+    /// Write a labeled thumbnail grid of the run's images to `contact-sheet.png`.
+    #[serde(default)]
+    pub contact_sheet: bool,
+    /// Dominant colors recorded in each sidecar as `palette`; 0 turns it off.
+    #[serde(default = "default_palette_size")]
+    pub palette_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SidecarFormat{ #[default] Pretty, Compact }
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SidecarCfg{
+    #[serde(default)]
+    pub format: SidecarFormat,
+    /// Append every sidecar to `out_dir/sidecars.jsonl` instead of one file per image.
+    #[serde(default)]
+    pub consolidated: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind{ #[default] Local, S3 }
+
+/// Where images, thumbnails, derivatives and per-image sidecars are written.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StorageCfg{
+    #[serde(default)]
+    pub kind: StorageKind,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub bucket: String,
+    /// Key prefix inside the bucket, e.g. `campaigns/spring`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prefix: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// S3-compatible endpoint such as MinIO (`http://localhost:9000`); uses path-style addressing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// Base of the URLs recorded in the manifest (e.g. a CDN); `s3://bucket/key` otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameMode{
+    /// Grow the canvas by `width_px` on every side.
+    #[default] Expand,
+    /// Keep the canvas size and shrink the image to fit inside the border.
+    Inset,
+}
+
+/// Solid border composited around the saved image (and everything derived from it).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameCfg{
+    pub width_px: u32,
+    /// `#rrggbb` or `#rrggbbaa`.
+    pub color: String,
+    #[serde(default)]
+    pub mode: FrameMode,
+}
+
+/// How an image is brought to `post.aspect_ratio`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AspectFit{
+    /// Cut away the excess.
+    #[default] Crop,
+    /// Add bars in `background` around the whole image.
+    Pad,
+}
+
+/// Which part of a too-tall image is kept by a crop (or where it sits when padding);
+/// too-wide images are always centered horizontally.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CropAnchor{ #[default] Center, Top, Bottom }
+
+/// Reshape every image to `ratio` (width, height), e.g. `[16, 9]` or `[4, 5]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AspectCfg{
+    pub ratio: (u32, u32),
+    #[serde(default)]
+    pub fit: AspectFit,
+    #[serde(default)]
+    pub anchor: CropAnchor,
+    /// Bar color for `fit: pad`: `#rrggbb` or `#rrggbbaa`.
+    #[serde(default = "default_aspect_background")]
+    pub background: String,
+}
+
+fn default_palette_size() -> usize { 5 }
+fn default_aspect_background() -> String { "#000000".into() }
+
+/// Corner (or center) of the image a watermark is placed in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkPosition{ TopLeft, TopRight, BottomLeft, #[default] BottomRight, Center }
+
+/// Text and/or a logo drawn onto the saved image (and everything derived from it), inside any frame.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatermarkCfg{
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub text: String,
+    /// Font size in pixels.
+    #[serde(default = "default_watermark_px")]
+    pub px: f32,
+    /// `#rrggbb` or `#rrggbbaa`; the alpha makes the text translucent.
+    #[serde(default = "default_watermark_color")]
+    pub color: String,
+    /// Gap between the text and the nearest edges (ignored on centered axes).
+    #[serde(default = "default_watermark_margin")]
+    pub margin_px: u32,
+    #[serde(default)]
+    pub position: WatermarkPosition,
+    /// Logo drawn as well as (or instead of) the text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<WatermarkImage>,
+}
+
+/// A logo (PNG with alpha works best) blended onto the image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatermarkImage{
+    pub path: PathBuf,
+    /// 0..=1, multiplied into the logo's own alpha.
+    #[serde(default = "default_logo_opacity")]
+    pub opacity: f32,
+    /// Logo width as a fraction of the image width; shrunk further if it wouldn't fit.
+    #[serde(default = "default_logo_scale")]
+    pub scale: f32,
+    #[serde(default)]
+    pub position: WatermarkPosition,
+}
+
+fn default_logo_opacity() -> f32 { 1.0 }
+fn default_logo_scale() -> f32 { 0.2 }
+fn default_watermark_px() -> f32 { 24.0 }
+fn default_watermark_color() -> String { "#ffffffb3".into() }
+fn default_watermark_margin() -> u32 { 16 }
+
+/// Generation-free variant expansion: extra copies saved next to each image.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AugmentCfg{
+    #[serde(default)]
+    pub flip: bool,
+    /// Vertically-flipped copy.
+    #[serde(default)]
+    pub mirror: bool,
+    /// Copy flipped across its main diagonal (width and height swap).
+    #[serde(default)]
+    pub transpose: bool,
+    #[serde(default)]
+    pub crop: bool,
+}
+
+/// Which backend rewrites prompts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RewriteKind{
+    #[default] OpenAI,
+    /// A local Ollama server (`rewrite.base_url`).
+    Ollama,
+    /// Prompts pass through unchanged.
+    Noop,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DedupeCfg{ pub enabled: bool, pub phash_bits: u32, pub phash_thresh: u32 }
+pub struct RewriteCfg{
+    pub enabled: bool,
+    #[serde(default)]
+    pub kind: RewriteKind,
+    /// Server for `kind: ollama`; defaults to `http://localhost:11434`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    pub system: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub cache_file: Option<PathBuf>,
+    /// Used to price rewrite calls from the token usage the API reports.
+    #[serde(default)]
+    pub price_usd_per_1k_tokens: Option<f64>,
+    /// Once rewrite spend reaches this, remaining prompts are used as-is.
+    #[serde(default)]
+    pub max_cost_usd: Option<f64>,
+    /// Sampling for the rewrite model; left to the backend's default when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// Few-shot `[before, after]` pairs shown to the rewrite model ahead of each prompt.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<(String, String)>,
+    /// Prompts rewritten together in one request, drawn ahead of the workers (1 = one call per prompt).
+    #[serde(default = "default_rewrite_batch_size")]
+    pub batch_size: usize,
+    /// Rewrite calls per prompt before falling back to the original prompt.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HooksCfg{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_image: Option<OnImageHookCfg>,
+}
+
+/// Run after each saved image with its sidecar JSON: piped to `command`'s stdin
+/// (run via `sh -c`, with `ADGEN_IMAGE` set to the PNG path, or `ADGEN_IMAGE_URL`
+/// to its URL under remote `storage`) and/or POSTed to `url`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PostCfg{ pub thumbnail: bool, pub thumb_max: u32 }
+pub struct OnImageHookCfg{
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default = "default_hook_concurrency")]
+    pub concurrency: usize,
+    #[serde(default = "default_hook_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_rewrite_batch_size() -> usize { 1 }
+fn default_hook_concurrency() -> usize { 4 }
+fn default_hook_timeout_ms() -> u64 { 30_000 }
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretsBackend{
+    #[default]
+    Env,
+    File,
+}
+
+/// Where API keys named by `*api_key_env` settings are looked up.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecretsCfg{
+    #[serde(default)]
+    pub backend: SecretsBackend,
+    /// Directory holding one file per secret (`backend: file`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+}
+
+/// What `provider.kind: mock` draws, to steer dedupe/QA paths in local runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MockPattern{
+    /// Seeded noise: every image is unique.
+    #[default]
+    Noise,
+    /// The same image every call: everything after the first is a duplicate.
+    Fixed,
+    /// A gradient picked by the prompt plus light noise: same prompt, near-identical images.
+    Gradient,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MockCfg{
+    #[serde(default)]
+    pub pattern: MockPattern,
+    /// Draw the prompt text on a plain background instead of `pattern`.
+    #[serde(default)]
+    pub render_text: bool,
+    /// Mixed into the per-prompt seed, to get a different (but still reproducible) image set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QaCfg{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aesthetic: Option<AestheticCfg>,
+}
 
+/// External aesthetic scorer. The endpoint is POSTed `{"image_b64": "..."}` and
+/// must answer `{"score": <number>}` on a 0..`scale` range.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RewriteCfg{ pub enabled: bool, pub model: Option<String>, pub system: Option<String>, pub max_tokens: Option<u32>, pub cache_file: Option<PathBuf> }
+pub struct AestheticCfg{
+    pub enabled: bool,
+    pub endpoint: String,
+    #[serde(default = "default_aesthetic_scale")]
+    pub scale: f64,
+    /// Drop images scoring below this (on the normalized 0–10 scale).
+    #[serde(default)]
+    pub min_score: Option<f64>,
+    /// Env var holding a bearer token for the endpoint, if it needs one.
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+fn default_aesthetic_scale() -> f64 { 10.0 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RunCfg{
@@ -41,7 +475,42 @@ pub struct RunCfg{
     pub out_dir: PathBuf,
     pub seed: u64,
     #[serde(default)]
+    pub sidecar: SidecarCfg,
+    #[serde(default)]
+    pub storage: StorageCfg,
+    #[serde(default)]
+    pub qa: QaCfg,
+    #[serde(default)]
+    pub mock: MockCfg,
+    #[serde(default)]
+    pub secrets: SecretsCfg,
+    #[serde(default)]
+    pub hooks: HooksCfg,
+    /// Write `out_dir/gallery.html`, a static page of the run's images, at the end of the run.
+    #[serde(default)]
+    pub gallery: bool,
+    #[serde(default)]
     pub budget_limit_usd: Option<f64>,
+    /// Abort the whole run after this many seconds, whatever it is waiting on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_timeout_secs: Option<u64>,
+    /// Model aliases, e.g. `default_image: gpt-image-1`; reference them as `@default_image`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub models: HashMap<String, String>,
+}
+
+impl RunCfg {
+    /// Expand an `@alias` model reference through `models`; plain names pass through.
+    pub fn resolve_model(&self, name: &str) -> anyhow::Result<String> {
+        match name.strip_prefix('@') {
+            Some(alias) => self.models.get(alias).cloned().ok_or_else(|| {
+                let mut known: Vec<&str> = self.models.keys().map(String::as_str).collect();
+                known.sort_unstable();
+                anyhow::anyhow!("unknown model alias `@{alias}` (defined aliases: {})", if known.is_empty() { "none".to_string() } else { known.join(", ") })
+            }),
+            None => Ok(name.to_string()),
+        }
+    }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Mode {
@@ -52,13 +521,67 @@ pub enum Mode {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TemplateYaml {
     pub mode: Mode,
+    /// What images should not contain (text, watermarks, ...), sent with every prompt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<String>,
+    /// Vary this image instead of generating from scratch (image-to-image providers only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub init_image: Option<InitImageCfg>,
+    /// How the template's choices are combined into prompts.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub variant_mode: VariantMode,
+    /// Hand-written prompts (text, CSV or JSONL) used in order instead of `mode`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompts_file: Option<PathBuf>,
+}
+
+/// `random` draws each choice independently (seeded by `seed`); `unique` draws the
+/// same way but skips prompts it has already produced; `cartesian` walks every
+/// combination once, in order. `unique` and `cartesian` end once all are used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VariantMode { #[default] Random, Unique, Cartesian }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitImageCfg {
+    pub path: PathBuf,
+    /// 0 keeps the image as is, 1 ignores it.
+    #[serde(default = "default_init_strength")]
+    pub strength: f32,
 }
 
+fn default_init_strength() -> f32 { 0.35 }
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AdTemplate{ 
     pub brand:String,
     pub product:String, 
-    pub styles:Vec<String> 
+    pub styles:Vec<StyleEntry>,
+    /// Optional lists woven into the prompt (one pick each); empty lists are left out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audiences:Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backgrounds:Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ctas:Vec<String>,
+}
+
+/// A template style: a plain string (weight 1), or `{ value, weight }` to make
+/// it more or less likely to be drawn in random mode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StyleEntry{
+    Plain(String),
+    Weighted{ value: String, weight: f32 },
+}
+
+impl StyleEntry{
+    pub fn value(&self)->&str{
+        match self { Self::Plain(v) | Self::Weighted{ value: v, .. } => v }
+    }
+    pub fn weight(&self)->f32{
+        match self { Self::Plain(_) => 1.0, Self::Weighted{ weight, .. } => *weight }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
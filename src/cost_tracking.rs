@@ -1,4 +1,5 @@
 use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::Path;
@@ -9,6 +10,8 @@ struct SidecarData {
     provider: String,
     model: String,
     cost_usd: f64,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -35,27 +38,20 @@ pub struct ProviderCost {
     pub image_count: u64,
 }
 
-pub async fn compute_cost_summary(out_dir: &Path) -> Result<CostSummary> {
+/// Aggregate sidecar costs in `out_dir`, optionally only those created at or after `since`.
+pub async fn compute_cost_summary(out_dir: &Path, since: Option<DateTime<Utc>>) -> Result<CostSummary> {
     let mut total_cost = 0.0;
     let mut image_count: u64 = 0;
     let mut runs: HashMap<String, (f64, u64)> = HashMap::new();
     let mut providers: HashMap<(String, String), (f64, u64)> = HashMap::new();
 
-    let mut rd = tokio::fs::read_dir(out_dir).await?;
-    while let Some(entry) = rd.next_entry().await? {
-        let path = entry.path();
-        if path.extension().and_then(|s| s.to_str()) != Some("json") {
-            continue;
+    for sidecar in crate::io::read_sidecars::<SidecarData>(out_dir).await? {
+        if let Some(since) = since {
+            match sidecar.created_at {
+                Some(ts) if ts >= since => {}
+                _ => continue,
+            }
         }
-        // Skip non-sidecar JSON (e.g. any config files that might be in out_dir)
-        let bytes = match tokio::fs::read(&path).await {
-            Ok(b) => b,
-            Err(_) => continue,
-        };
-        let sidecar: SidecarData = match serde_json::from_slice(&bytes) {
-            Ok(s) => s,
-            Err(_) => continue, // skip files that don't match sidecar format
-        };
 
         total_cost += sidecar.cost_usd;
         image_count += 1;
@@ -110,3 +106,24 @@ pub async fn compute_cost_summary(out_dir: &Path) -> Result<CostSummary> {
 pub fn estimate_cost(target_images: u64, price_per_image: f64) -> f64 {
     target_images as f64 * price_per_image
 }
+
+/// Parse a `--since` value: an RFC 3339 timestamp, or a duration back from now
+/// such as `30m`, `12h`, `7d` (units: s, m, h, d, w).
+pub fn parse_since(s: &str) -> Result<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(ts) = DateTime::parse_from_rfc3339(s) {
+        return Ok(ts.with_timezone(&Utc));
+    }
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: i64 = num.parse().map_err(|_| anyhow::anyhow!("invalid --since value `{s}`: expected RFC 3339 or a duration like 12h"))?;
+    let dur = match unit {
+        "s" => Duration::seconds(n),
+        "m" => Duration::minutes(n),
+        "h" => Duration::hours(n),
+        "d" => Duration::days(n),
+        "w" => Duration::weeks(n),
+        _ => anyhow::bail!("invalid --since unit `{unit}`: use s, m, h, d or w"),
+    };
+    Ok(Utc::now() - dur)
+}
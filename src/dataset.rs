@@ -0,0 +1,114 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+
+use crate::io::{image_stem, read_sidecars};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum DatasetFormat {
+    /// COCO captions: `images` plus one caption annotation per image
+    Coco,
+    /// One JSON object per image
+    Jsonl,
+}
+
+#[derive(Deserialize)]
+struct SidecarData {
+    id: u64,
+    #[serde(default)]
+    batch_index: Option<u32>,
+    run_id: String,
+    provider: String,
+    model: String,
+    width: u32,
+    height: u32,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    original_prompt: String,
+    #[serde(default)]
+    rewritten_prompt: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JsonlRecord<'a> {
+    file_name: String,
+    caption: &'a str,
+    width: u32,
+    height: u32,
+    tags: Vec<String>,
+}
+
+/// Write a dataset file for the images in `out_dir`, built from their sidecars.
+/// The caption is the prompt the image was generated from (the rewritten one when
+/// present). Returns the written path and the number of images.
+pub async fn export_dataset(out_dir: &Path, format: DatasetFormat, output: Option<PathBuf>) -> Result<(PathBuf, usize)> {
+    let mut sidecars: Vec<SidecarData> = read_sidecars(out_dir).await?;
+    sidecars.sort_by(|a, b| (a.created_at, &a.run_id, a.id).cmp(&(b.created_at, &b.run_id, b.id)));
+
+    let body = match format {
+        DatasetFormat::Coco => {
+            // image ids restart per run, so COCO ids are assigned here instead
+            let images: Vec<_> = sidecars.iter().enumerate().map(|(i, s)| json!({
+                "id": i + 1,
+                "file_name": file_name(s),
+                "width": s.width,
+                "height": s.height,
+                "date_captured": s.created_at.map(|t| t.to_rfc3339()),
+                "tags": tags(s),
+            })).collect();
+            let annotations: Vec<_> = sidecars.iter().enumerate().map(|(i, s)| json!({
+                "id": i + 1,
+                "image_id": i + 1,
+                "caption": caption(s),
+            })).collect();
+            let doc = json!({
+                "info": {
+                    "description": "adgen generated images",
+                    "date_created": Utc::now().to_rfc3339(),
+                },
+                "images": images,
+                "annotations": annotations,
+            });
+            serde_json::to_vec_pretty(&doc)?
+        }
+        DatasetFormat::Jsonl => {
+            let mut buf = Vec::new();
+            for s in &sidecars {
+                serde_json::to_writer(&mut buf, &JsonlRecord {
+                    file_name: file_name(s),
+                    caption: caption(s),
+                    width: s.width,
+                    height: s.height,
+                    tags: tags(s),
+                })?;
+                buf.push(b'\n');
+            }
+            buf
+        }
+    };
+
+    let path = output.unwrap_or_else(|| out_dir.join(match format {
+        DatasetFormat::Coco => "dataset.coco.json",
+        DatasetFormat::Jsonl => "dataset.jsonl",
+    }));
+    tokio::fs::write(&path, body).await?;
+    Ok((path, sidecars.len()))
+}
+
+fn file_name(s: &SidecarData) -> String {
+    format!("{}.png", image_stem(s.id, s.batch_index, &s.provider, &s.model))
+}
+
+fn caption(s: &SidecarData) -> &str {
+    s.rewritten_prompt.as_deref().unwrap_or(&s.original_prompt)
+}
+
+fn tags(s: &SidecarData) -> Vec<String> {
+    vec![
+        format!("provider:{}", s.provider),
+        format!("model:{}", s.model),
+        format!("run:{}", s.run_id),
+    ]
+}
@@ -1,24 +1,507 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use base64::Engine as _;
 
+use futures_util::stream::{self, StreamExt};
 use img_hash::{HasherConfig, HashAlg, ImageHash};
-use std::collections::HashSet;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
+use crate::config::DedupeAlg;
+use std::path::{Path, PathBuf};
+use tokio::sync::Semaphore;
+
+/// Seen hashes kept in `out_dir` between runs with `dedupe.persist`.
+pub const HASHES_FILE: &str = "dedupe-hashes.json";
+
+#[derive(Serialize, Deserialize)]
+struct SavedHashes{
+    // files from before dedupe.alg were all double_gradient
+    #[serde(default)]
+    alg: DedupeAlg,
+    bits: u32,
+    hashes: Vec<String>,
+}
+
+/// Where a seen hash came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin{
+    /// An image of this run.
+    Image{ id: u64, batch_index: Option<u32> },
+    /// A PNG hashed by `prime_from_dir`.
+    File(String),
+    /// A hash loaded from `dedupe-hashes.json`.
+    EarlierRun,
+}
+
+impl std::fmt::Display for Origin{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Image{ id, batch_index: Some(k) } => write!(f, "#{id}.{k}"),
+            Origin::Image{ id, batch_index: None } => write!(f, "#{id}"),
+            Origin::File(name) => f.write_str(name),
+            Origin::EarlierRun => f.write_str("an image from an earlier run"),
+        }
+    }
+}
+
+/// The seen image a duplicate was dropped for.
+#[derive(Debug, Clone)]
+pub struct MatchInfo{ pub matched: Origin, pub distance: u32 }
+
+/// Near-duplicate filter shared by all workers. Decoding and hashing run on the
+/// blocking pool (at most `hash_concurrency` at once); the seen-set lock is only
+/// held for the compare+insert.
 pub struct PerceptualDeduper{
-    hasher: HasherConfig,
-    seen: HashSet<ImageHash>,
+    alg: DedupeAlg,
+    bits: u32,
+    /// Hash grid side, `sqrt(bits)`.
+    side: u32,
+    seen: Mutex<BkTree>,
+    /// Hashes of images being saved, with their claim numbers; locked after `seen`.
+    claimed: Mutex<Vec<(u64, ImageHash, Origin)>>,
+    next_claim: std::sync::atomic::AtomicU64,
     threshold: u32,
+    hash_slots: Semaphore,
+}
+impl PerceptualDeduper{
+    pub fn new(alg: DedupeAlg, bits:u32, threshold:u32, hash_concurrency: Option<usize>)->Result<Self>{
+        // the hash is a side x side grid, so bits has to be a square
+        let side = (bits as f64).sqrt().round() as u32;
+        if side < 2 || side * side != bits {
+            anyhow::bail!("dedupe.phash_bits must be a square number of at least 4 (e.g. 64 or 256), got {bits}");
+        }
+        // img_hash rounds other sides up, which would hash a bigger grid than configured
+        let (name, multiple) = match alg {
+            DedupeAlg::DoubleGradient => ("double_gradient", 2),
+            DedupeAlg::Blockhash => ("blockhash", 4),
+            _ => ("", 1),
+        };
+        if !side.is_multiple_of(multiple) {
+            anyhow::bail!("dedupe.alg {name} needs phash_bits with a side that is a multiple of {multiple} (e.g. 16, 64 or 256), got {bits} ({side}x{side})");
+        }
+        let slots = hash_concurrency
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()))
+            .max(1);
+        Ok(Self{
+            alg, bits, side,
+            seen: Mutex::new(BkTree::default()),
+            claimed: Mutex::new(Vec::new()),
+            next_claim: Default::default(),
+            threshold,
+            hash_slots: Semaphore::new(slots),
+        })
+    }
+    /// The image seen (or being saved) that `bytes` is within the threshold of, if
+    /// any, plus its perceptual hash. Nothing is remembered; see `claim`.
+    pub async fn check(&self, bytes: Vec<u8>)->Result<(Option<MatchInfo>, ImageHash)>{
+        let hash = {
+            let _slot = self.hash_slots.acquire().await?;
+            let (alg, side) = (self.alg, self.side);
+            tokio::task::spawn_blocking(move || hash_image(alg, side, &bytes)).await??
+        };
+        let seen = self.seen.lock();
+        Ok((self.find(&seen, &hash), hash))
+    }
+
+    /// Reserve `hash` for an image about to be saved, unless it is within the
+    /// threshold of one seen or being saved. Later images are compared against
+    /// it from now on; it joins the seen-set once the claim is `kept`, and is
+    /// forgotten if the claim is dropped (the save failed).
+    pub fn claim(&self, hash: ImageHash, origin: Origin)->std::result::Result<Claim<'_>, MatchInfo>{
+        let seen = self.seen.lock();
+        if let Some(m) = self.find(&seen, &hash) { return Err(m); }
+        let n = self.next_claim.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.claimed.lock().push((n, hash, origin));
+        Ok(Claim{ deduper: self, n, kept: false })
+    }
+
+    fn find(&self, seen: &BkTree, hash: &ImageHash)->Option<MatchInfo>{
+        if let Some((node, distance)) = seen.find_within(hash, self.threshold) {
+            return Some(MatchInfo{ matched: node.origin.clone(), distance });
+        }
+        self.claimed.lock().iter()
+            .map(|(_, h, origin)| (origin, h.dist(hash)))
+            .find(|&(_, d)| d <= self.threshold)
+            .map(|(origin, distance)| MatchInfo{ matched: origin.clone(), distance })
+    }
+
+    /// Hash the images saved in `dir` into the seen-set, so later images are
+    /// compared against them: the manifest's saved images when `dir` has one,
+    /// else every PNG directly under it except adgen's thumbnails, augmented
+    /// copies and contact sheet. Returns `(loaded, unreadable)`.
+    pub async fn prime_from_dir(&self, dir: &Path) -> Result<(usize, usize)>{
+        let mut paths: Vec<PathBuf> = Vec::new();
+        if let Some(names) = crate::manifest::saved_pngs(dir).await? {
+            // images saved through an S3 sink aren't here
+            for name in names {
+                let path = dir.join(name);
+                if tokio::fs::try_exists(&path).await? { paths.push(path); }
+            }
+        } else {
+            let mut rd = tokio::fs::read_dir(dir).await?;
+            while let Some(entry) = rd.next_entry().await? {
+                let path = entry.path();
+                let Some(name) = path.file_name().and_then(|s| s.to_str()) else { continue };
+                if is_plain_png(name) { paths.push(path); }
+            }
+        }
+        paths.sort();
+
+        let (alg, side) = (self.alg, self.side);
+        let hashes: Vec<Option<(ImageHash, Origin)>> = stream::iter(paths)
+            .map(|path| async move {
+                let _slot = self.hash_slots.acquire().await.ok()?;
+                let name = path.file_name()?.to_string_lossy().into_owned();
+                let hash = tokio::task::spawn_blocking(move || hash_image(alg, side, &std::fs::read(path).ok()?).ok()).await.ok()??;
+                Some((hash, Origin::File(name)))
+            })
+            .buffer_unordered(self.hash_slots.available_permits().max(1))
+            .collect()
+            .await;
+
+        let mut seen = self.seen.lock();
+        let (mut loaded, mut unreadable) = (0, 0);
+        for hash in hashes {
+            match hash {
+                Some((h, origin)) => { seen.insert(h, origin); loaded += 1; }
+                None => unreadable += 1,
+            }
+        }
+        Ok((loaded, unreadable))
+    }
+}
+
+/// A PNG that isn't something adgen derives from a saved image
+/// (`{stem}_thumb.png`, `{stem}_aug{n}.png`, the contact sheet).
+fn is_plain_png(name: &str) -> bool {
+    let Some(stem) = name.strip_suffix(".png").or_else(|| name.strip_suffix(".PNG")) else { return false };
+    if name == crate::post::CONTACT_SHEET_FILE || stem.ends_with("_thumb") { return false; }
+    !stem.rsplit_once("_aug").is_some_and(|(_, n)| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// A hash reserved by `PerceptualDeduper::claim`.
+pub struct Claim<'a>{ deduper: &'a PerceptualDeduper, n: u64, kept: bool }
+
+impl Claim<'_>{
+    /// The image was saved: move its hash into the seen-set.
+    pub fn keep(mut self){
+        self.kept = true;
+        let mut seen = self.deduper.seen.lock();
+        let mut claimed = self.deduper.claimed.lock();
+        if let Some(i) = claimed.iter().position(|c| c.0 == self.n) {
+            let (_, hash, origin) = claimed.swap_remove(i);
+            seen.insert(hash, origin);
+        }
+    }
+}
+
+impl Drop for Claim<'_>{
+    fn drop(&mut self){
+        if !self.kept { self.deduper.claimed.lock().retain(|c| c.0 != self.n); }
+    }
 }
+
 impl PerceptualDeduper{
-    pub fn new(bits:u32, threshold:u32)->Self{
-        Self{ hasher: HasherConfig::new().hash_alg(HashAlg::DoubleGradient).hash_size(bits/8, bits/8), seen: HashSet::new(), threshold }
+    /// Add the hashes saved at `path` to the seen-set. Returns how many; 0 if
+    /// there is no file yet. A file saved with another `alg` or `phash_bits` is
+    /// an error, since its hashes can't be compared with these.
+    pub async fn load(&self, path: &Path) -> Result<usize>{
+        let bytes = match tokio::fs::read(path).await {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let saved: SavedHashes = serde_json::from_slice(&bytes)?;
+        if saved.alg != self.alg {
+            anyhow::bail!("saved with alg {:?}, not {:?}", saved.alg, self.alg);
+        }
+        if saved.bits != self.bits {
+            anyhow::bail!("saved with phash_bits {}, not {}", saved.bits, self.bits);
+        }
+        let hashes = saved.hashes.iter()
+            .map(|h| {
+                let raw = base64::engine::general_purpose::STANDARD.decode(h).with_context(|| format!("bad hash {h:?}"))?;
+                ImageHash::from_bytes(&raw).map_err(|e| anyhow::anyhow!("bad hash {h:?}: {e:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let n = hashes.len();
+        let mut seen = self.seen.lock();
+        for h in hashes { seen.insert(h, Origin::EarlierRun); }
+        Ok(n)
+    }
+
+    /// Write every hash seen so far to `path` as base64, replacing what was there.
+    /// Returns how many were written.
+    pub async fn save(&self, path: &Path) -> Result<usize>{
+        let saved = {
+            let seen = self.seen.lock();
+            SavedHashes{ alg: self.alg, bits: self.bits, hashes: seen.nodes.iter().map(|n| base64::engine::general_purpose::STANDARD.encode(n.hash.as_bytes())).collect() }
+        };
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&saved)?).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(saved.hashes.len())
+    }
+}
+
+/// `hash` as lowercase hex, as the manifest records it.
+pub fn hex(hash: &ImageHash) -> String {
+    hash.as_bytes().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hash_image(alg: DedupeAlg, side: u32, bytes: &[u8]) -> Result<ImageHash>{
+    // img_hash bundles image 0.23 without any codecs: decode with ours and hand
+    // it the raw pixels
+    let rgba = image::load_from_memory(bytes)?.into_rgba8();
+    let (w, h) = rgba.dimensions();
+    let img = img_hash::image::RgbaImage::from_raw(w, h, rgba.into_raw()).context("converting decoded image for hashing")?;
+    let alg = match alg {
+        DedupeAlg::Mean => HashAlg::Mean,
+        DedupeAlg::Gradient => HashAlg::Gradient,
+        DedupeAlg::VertGradient => HashAlg::VertGradient,
+        DedupeAlg::DoubleGradient => HashAlg::DoubleGradient,
+        DedupeAlg::Blockhash => HashAlg::Blockhash,
+    };
+    let hasher = HasherConfig::new().hash_alg(alg).hash_size(side, side).to_hasher();
+    Ok(hasher.hash_image(&img))
+}
+
+/// BK-tree over perceptual hashes keyed on Hamming distance, so near-duplicate
+/// lookups only visit subtrees whose edge distance is within the threshold
+/// (triangle inequality) instead of scanning every stored hash.
+#[derive(Default)]
+struct BkTree{ nodes: Vec<BkNode> }
+
+struct BkNode{ hash: ImageHash, origin: Origin, children: HashMap<u32, usize> }
+
+impl BkTree{
+    fn insert(&mut self, hash: ImageHash, origin: Origin){
+        let new_idx = self.nodes.len();
+        if new_idx == 0 {
+            self.nodes.push(BkNode{ hash, origin, children: HashMap::new() });
+            return;
+        }
+        let mut cur = 0;
+        loop {
+            let d = hash.dist(&self.nodes[cur].hash);
+            match self.nodes[cur].children.get(&d) {
+                Some(&next) => cur = next,
+                None => {
+                    self.nodes[cur].children.insert(d, new_idx);
+                    self.nodes.push(BkNode{ hash, origin, children: HashMap::new() });
+                    return;
+                }
+            }
+        }
+    }
+
+    /// First stored node within `threshold` of `hash`, with its distance.
+    fn find_within(&self, hash: &ImageHash, threshold: u32) -> Option<(&BkNode, u32)>{
+        if self.nodes.is_empty() { return None; }
+        let mut stack = vec![0usize];
+        while let Some(idx) = stack.pop() {
+            let node = &self.nodes[idx];
+            let d = hash.dist(&node.hash);
+            if d <= threshold { return Some((node, d)); }
+            let (lo, hi) = (d.saturating_sub(threshold), d + threshold);
+            stack.extend(node.children.iter().filter(|(k, _)| (lo..=hi).contains(*k)).map(|(_, &c)| c));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha12Rng;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn random_hash(rng: &mut ChaCha12Rng, bits: u32) -> ImageHash {
+        let bytes: Vec<u8> = (0..bits / 8).map(|_| rng.random()).collect();
+        ImageHash::from_bytes(&bytes).unwrap()
+    }
+
+    /// `hash` with `flips` random bits flipped (possibly the same bit twice).
+    fn near(rng: &mut ChaCha12Rng, hash: &ImageHash, flips: u32) -> ImageHash {
+        let mut bytes = hash.as_bytes().to_vec();
+        for _ in 0..flips {
+            let bit = rng.random_range(0..bytes.len() * 8);
+            bytes[bit / 8] ^= 1 << (bit % 8);
+        }
+        ImageHash::from_bytes(&bytes).unwrap()
+    }
+
+    /// Look up `queries` hashes (half near copies of stored ones, half random)
+    /// against `n` stored hashes with the BK-tree and with a linear scan, check
+    /// both agree on every query, and print how long each took.
+    fn lookup_vs_linear_scan(n: usize, bits: u32, threshold: u32, queries: usize) -> (Duration, Duration) {
+        let mut rng = ChaCha12Rng::seed_from_u64(n as u64);
+        let stored: Vec<ImageHash> = (0..n).map(|_| random_hash(&mut rng, bits)).collect();
+        let mut tree = BkTree::default();
+        for (i, h) in stored.iter().enumerate() { tree.insert(h.clone(), Origin::Image{ id: i as u64, batch_index: None }); }
+        let queries: Vec<ImageHash> = (0..queries)
+            .map(|q| if q % 2 == 0 {
+                let (base, flips) = (&stored[rng.random_range(0..n)], rng.random_range(0..=threshold * 2));
+                near(&mut rng, base, flips)
+            } else {
+                random_hash(&mut rng, bits)
+            })
+            .collect();
+
+        let t = Instant::now();
+        let linear: Vec<bool> = queries.iter().map(|q| stored.iter().any(|h| h.dist(q) <= threshold)).collect();
+        let linear_time = t.elapsed();
+
+        let t = Instant::now();
+        let found: Vec<Option<(u64, u32)>> = queries.iter()
+            .map(|q| tree.find_within(q, threshold).map(|(node, d)| match node.origin { Origin::Image{ id, .. } => (id, d), _ => unreachable!() }))
+            .collect();
+        let tree_time = t.elapsed();
+
+        for (i, (q, f)) in queries.iter().zip(&found).enumerate() {
+            assert_eq!(f.is_some(), linear[i], "query {i}: BK-tree and linear scan disagree");
+            if let Some((id, d)) = *f {
+                assert_eq!(stored[id as usize].dist(q), d);
+                assert!(d <= threshold);
+            }
+        }
+        let hits = linear.iter().filter(|&&h| h).count();
+        assert!(hits > 0 && hits < queries.len(), "want a mix of hits and misses, got {hits}/{}", queries.len());
+        println!("{n} hashes x {bits} bits, threshold {threshold}, {} lookups ({hits} hits): linear {linear_time:?}, BK-tree {tree_time:?}", queries.len());
+        (linear_time, tree_time)
+    }
+
+    #[test]
+    fn phash_bits_must_fit_the_alg() {
+        for (alg, bits) in [(DedupeAlg::DoubleGradient, 64), (DedupeAlg::DoubleGradient, 36), (DedupeAlg::Blockhash, 16), (DedupeAlg::Mean, 25)] {
+            assert!(PerceptualDeduper::new(alg, bits, 10, None).is_ok(), "{alg:?} {bits}");
+        }
+        for (alg, bits) in [(DedupeAlg::DoubleGradient, 9), (DedupeAlg::DoubleGradient, 25), (DedupeAlg::Blockhash, 36), (DedupeAlg::Gradient, 60), (DedupeAlg::Mean, 1)] {
+            assert!(PerceptualDeduper::new(alg, bits, 10, None).is_err(), "{alg:?} {bits}");
+        }
+    }
+
+    #[test]
+    fn bk_tree_matches_linear_scan() {
+        lookup_vs_linear_scan(500, 64, 10, 200);
+    }
+
+    // cargo test --release bk_tree_vs_linear_scan -- --ignored --nocapture
+    // On uniformly random hashes the tree prunes well at small thresholds; near
+    // a quarter of the bits almost every edge is in range and it visits most nodes.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bk_tree_vs_linear_scan_few_thousand() {
+        for n in [1_000, 2_000, 5_000] {
+            for threshold in [4, 10] { lookup_vs_linear_scan(n, 64, threshold, 2_000); }
+        }
     }
-    pub fn is_duplicate(&mut self, bytes:&[u8])->Result<bool>{
-        let img = img_hash::image::load_from_memory(bytes)?;
-        let hash = self.hasher.to_hasher().hash_image(&img);
-        for h in &self.seen {
-            if hash.dist(h) <= self.threshold { return Ok(true); }
+
+    #[test]
+    #[ignore = "benchmark"]
+    fn bk_tree_vs_linear_scan_10k() {
+        for (bits, threshold) in [(64, 4), (64, 10), (256, 10), (256, 40)] { lookup_vs_linear_scan(10_000, bits, threshold, 2_000); }
+    }
+
+    /// `copies` PNGs each of `clusters` random 8x8 block patterns, with per-pixel
+    /// noise that doesn't move the hash, shuffled. Returns `(cluster, png)`.
+    fn cluster_pngs(clusters: usize, copies: usize, side: u32) -> Vec<(usize, Vec<u8>)> {
+        let mut rng = ChaCha12Rng::seed_from_u64(236);
+        let mut out = vec![];
+        for c in 0..clusters {
+            let pattern: Vec<bool> = (0..64).map(|_| rng.random()).collect();
+            for _ in 0..copies {
+                let img = image::RgbImage::from_fn(side, side, |x, y| {
+                    let base: i32 = if pattern[(y * 8 / side * 8 + x * 8 / side) as usize] { 220 } else { 30 };
+                    let v = (base + rng.random_range(-6..=6)) as u8;
+                    image::Rgb([v, v, v])
+                });
+                let mut png = std::io::Cursor::new(Vec::new());
+                img.write_to(&mut png, image::ImageFormat::Png).unwrap();
+                out.push((c, png.into_inner()));
+            }
         }
-        self.seen.insert(hash);
-        Ok(false)
+        for i in (1..out.len()).rev() { out.swap(i, rng.random_range(0..=i)); }
+        out
+    }
+
+    /// Run every image through check+claim+keep as the orchestrator does, either
+    /// one at a time with a single hashing slot (as when the deduper sat behind one
+    /// lock) or all at once. Returns `(hash, kept)` per image, in input order.
+    async fn dedupe_all(images: &[(usize, Vec<u8>)], concurrent: bool) -> Vec<(String, bool)> {
+        async fn one(d: Arc<PerceptualDeduper>, id: u64, bytes: Vec<u8>) -> (String, bool) {
+            let (m, hash) = d.check(bytes).await.unwrap();
+            let hex = hex(&hash);
+            let kept = m.is_none() && d.claim(hash, Origin::Image{ id, batch_index: None }).map(Claim::keep).is_ok();
+            (hex, kept)
+        }
+        let d = Arc::new(PerceptualDeduper::new(DedupeAlg::DoubleGradient, 64, 10, if concurrent { None } else { Some(1) }).unwrap());
+        if !concurrent {
+            let mut out = vec![];
+            for (id, (_, bytes)) in images.iter().enumerate() { out.push(one(d.clone(), id as u64, bytes.clone()).await); }
+            return out;
+        }
+        let tasks: Vec<_> = images.iter().enumerate().map(|(id, (_, bytes))| tokio::spawn(one(d.clone(), id as u64, bytes.clone()))).collect();
+        futures_util::future::join_all(tasks).await.into_iter().map(Result::unwrap).collect()
+    }
+
+    /// Dedupe `images` serialized and concurrently and check both give the same
+    /// hashes and keep exactly one image per cluster.
+    async fn serialized_vs_concurrent(clusters: usize, copies: usize, side: u32) -> (Duration, Duration) {
+        let images = cluster_pngs(clusters, copies, side);
+        let t = Instant::now();
+        let serial = dedupe_all(&images, false).await;
+        let serial_time = t.elapsed();
+        let t = Instant::now();
+        let concurrent = dedupe_all(&images, true).await;
+        let concurrent_time = t.elapsed();
+
+        for (i, (s, c)) in serial.iter().zip(&concurrent).enumerate() { assert_eq!(s.0, c.0, "image {i} hashed differently"); }
+        for results in [&serial, &concurrent] {
+            let mut kept = vec![0; clusters];
+            for ((cluster, _), (_, k)) in images.iter().zip(results) { if *k { kept[*cluster] += 1; } }
+            assert_eq!(kept, vec![1; clusters], "want one image kept per cluster");
+        }
+        println!("{} images of {side}x{side}, {clusters} distinct: serialized {serial_time:?}, concurrent {concurrent_time:?}", images.len());
+        (serial_time, concurrent_time)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_dedupe_matches_serialized() {
+        serialized_vs_concurrent(8, 4, 64).await;
+    }
+
+    // cargo test --release concurrent_dedupe_benchmark -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark"]
+    async fn concurrent_dedupe_benchmark() {
+        serialized_vs_concurrent(50, 8, 1024).await;
+    }
+
+    #[tokio::test]
+    async fn priming_skips_derived_pngs() {
+        let dir = std::env::temp_dir().join(format!("adgen-dedupe-test-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbaImage::from_fn(32, 32, |x, y| image::Rgba([(x * 8) as u8, (y * 8) as u8, 0, 255])).write_to(&mut png, image::ImageFormat::Png).unwrap();
+        let stem = crate::io::image_stem(1, None, "mock", "mock-v1");
+        for name in [format!("{stem}.png"), format!("{stem}_thumb.png"), format!("{stem}_aug1.png"), format!("{stem}_aug12.png"), crate::post::CONTACT_SHEET_FILE.into(), "logo.png".into()] {
+            tokio::fs::write(dir.join(name), png.get_ref()).await.unwrap();
+        }
+
+        // no manifest: every PNG but the derived ones
+        let d = PerceptualDeduper::new(DedupeAlg::default(), 64, 10, None).unwrap();
+        assert_eq!(d.prime_from_dir(&dir).await.unwrap(), (2, 0));
+
+        // with one: just the saved images
+        let manifest = format!("{{\"id\":1,\"run_id\":\"r\",\"status\":\"saved\",\"prompt\":\"p\",\"path_png\":\"{stem}.png\"}}\n{{\"id\":2,\"run_id\":\"r\",\"status\":\"failed\",\"prompt\":\"p\"}}\n");
+        tokio::fs::write(dir.join(crate::manifest::MANIFEST_FILE), manifest).await.unwrap();
+        let d = PerceptualDeduper::new(DedupeAlg::default(), 64, 10, None).unwrap();
+        assert_eq!(d.prime_from_dir(&dir).await.unwrap(), (1, 0));
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
     }
 }
@@ -1,4 +1,9 @@
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::{fs, io::AsyncWriteExt, sync::broadcast};
+
+use crate::orchestrator::RunOutcome;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -6,9 +11,121 @@ use serde::{Deserialize, Serialize};
 pub enum RunEvent {
     Started { run_id: String, total: u64 },
     Log { run_id: String, msg: String },
-    Progress { run_id: String, done: u64, total: u64, cost_so_far: f64 },
-    Finished { run_id: String },
+    /// `queued` prompts wait in the dispatch queue while `in_flight` workers run: a full
+    /// queue means the provider is the bottleneck, an empty one that prompt generation is.
+    Progress {
+        run_id: String,
+        done: u64,
+        total: u64,
+        cost_so_far: f64,
+        #[serde(default)]
+        queued: usize,
+        #[serde(default)]
+        in_flight: usize,
+    },
+    /// `outcome` is absent for jobs that aren't generation runs (reprocessing).
+    Finished {
+        run_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        outcome: Option<RunOutcome>,
+    },
     Failed { run_id: String, error: String },
+    /// Small inline preview of a just-saved image, for the live thumbnail feed.
+    Thumbnail { run_id: String, id: u64, name: String, png_b64: String },
+}
+
+impl RunEvent {
+    pub fn run_id(&self) -> &str {
+        match self {
+            RunEvent::Started { run_id, .. }
+            | RunEvent::Log { run_id, .. }
+            | RunEvent::Progress { run_id, .. }
+            | RunEvent::Finished { run_id, .. }
+            | RunEvent::Failed { run_id, .. }
+            | RunEvent::Thumbnail { run_id, .. } => run_id,
+        }
+    }
+
+    /// Bulky events that only the thumbnail feed carries (not the run stream or log).
+    pub fn is_thumbnail(&self) -> bool {
+        matches!(self, RunEvent::Thumbnail { .. })
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, RunEvent::Finished { .. } | RunEvent::Failed { .. })
+    }
+}
+
+/// Location of a run's persisted event log: `out_dir/<run_id>/events.jsonl`.
+pub fn event_log_path(out_dir: &Path, run_id: &str) -> PathBuf {
+    out_dir.join(run_id).join("events.jsonl")
 }
 
+/// Append every event for `run_id` to its event log until the run finishes or fails.
+/// Events the receiver lagged past are replaced by one `Log` saying how many were lost.
+pub async fn persist_run_events(mut rx: broadcast::Receiver<RunEvent>, out_dir: PathBuf, run_id: String) -> Result<()> {
+    let path = event_log_path(&out_dir, &run_id);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let mut f = fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+    loop {
+        let evt = match rx.recv().await {
+            Ok(evt) => evt,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                // leave a gap marker so a replay doesn't pass for the full run
+                tracing::warn!("event log for {run_id} fell behind and lost {n} events");
+                RunEvent::Log { run_id: run_id.clone(), msg: format!("event log: {n} events lost here (the writer fell behind)") }
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if evt.run_id() != run_id || evt.is_thumbnail() { continue; }
+        let line = serde_json::to_string(&evt)?;
+        f.write_all(line.as_bytes()).await?;
+        f.write_all(b"\n").await?;
+        if evt.is_terminal() { break; }
+    }
+    f.flush().await?;
+    Ok(())
+}
+
+/// Read back a run's full event log in emission order. `None` if the run has no log.
+pub async fn read_event_log(out_dir: &Path, run_id: &str) -> Result<Option<Vec<RunEvent>>> {
+    let txt = match fs::read_to_string(event_log_path(out_dir, run_id)).await {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let events = txt
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .filter_map(|l| serde_json::from_str::<RunEvent>(l).ok())
+        .collect();
+    Ok(Some(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[tokio::test]
+    async fn lagged_events_leave_a_marker() {
+        let out_dir = std::env::temp_dir().join(format!("adgen-events-test-{}", uuid::Uuid::new_v4()));
+        let (tx, rx) = broadcast::channel(2);
+        let run_id = "run-test".to_string();
+        for i in 0..5 {
+            tx.send(RunEvent::Log { run_id: run_id.clone(), msg: format!("line {i}") }).unwrap();
+        }
+        tx.send(RunEvent::Finished { run_id: run_id.clone(), outcome: None }).unwrap();
+        persist_run_events(rx, out_dir.clone(), run_id.clone()).await.unwrap();
+
+        let msgs: Vec<String> = read_event_log(&out_dir, &run_id).await.unwrap().unwrap().into_iter()
+            .map(|e| match e { RunEvent::Log { msg, .. } => msg, other => format!("{other:?}") })
+            .collect();
+        assert_eq!(msgs.len(), 3, "{msgs:?}");
+        assert_eq!(msgs[0], "event log: 4 events lost here (the writer fell behind)");
+        assert_eq!(msgs[1], "line 4");
+
+        fs::remove_dir_all(&out_dir).await.unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::Result;
+use serde_yaml::Value;
+use std::fmt::Write as _;
+
+use crate::config::{RunCfg, TemplateYaml};
+
+/// Render the effective config as YAML, annotating every value with where it
+/// came from: `file` (set in the config file), `default` (filled in by serde)
+/// or `override` (a CLI flag; `overridden` holds dotted paths like `out_dir`).
+pub fn explain(file_yaml: &str, effective: &RunCfg, overridden: &[&str], tpl: &TemplateYaml) -> Result<String> {
+    let file: Value = serde_yaml::from_str(file_yaml)?;
+    let effective_val = serde_yaml::to_value(effective)?;
+    let mut out = String::from("# effective run config\n");
+    write_mapping(&mut out, &effective_val, &file, effective, overridden, &mut Vec::new(), 0)?;
+    out.push_str("\n# template (file)\n");
+    out.push_str(&serde_yaml::to_string(tpl)?);
+    Ok(out)
+}
+
+fn write_mapping(
+    out: &mut String,
+    val: &Value,
+    file: &Value,
+    cfg: &RunCfg,
+    overridden: &[&str],
+    path: &mut Vec<String>,
+    depth: usize,
+) -> Result<()> {
+    let Value::Mapping(map) = val else { return Ok(()) };
+    let indent = "  ".repeat(depth);
+    for (k, v) in map {
+        let key = k.as_str().unwrap_or_default().to_string();
+        path.push(key.clone());
+        match v {
+            Value::Mapping(m) if !m.is_empty() => {
+                writeln!(out, "{indent}{key}:")?;
+                write_mapping(out, v, file, cfg, overridden, path, depth + 1)?;
+            }
+            _ => {
+                let dotted = path.join(".");
+                let source = if overridden.contains(&dotted.as_str()) {
+                    "override"
+                } else if lookup(file, path).is_some() {
+                    "file"
+                } else {
+                    "default"
+                };
+                let mut note = source.to_string();
+                // surface alias expansion, the usual answer to "why this model?"
+                if let Some(alias) = v.as_str().filter(|s| s.starts_with('@')) {
+                    match cfg.resolve_model(alias) {
+                        Ok(model) => write!(note, ", resolves to {model}")?,
+                        Err(e) => write!(note, ", {e}")?,
+                    }
+                }
+                writeln!(out, "{indent}{key}: {}  # {note}", inline(v)?)?;
+            }
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+fn lookup<'a>(root: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(root, |v, k| v.get(k.as_str()))
+}
+
+/// A leaf value on one line: YAML scalars as-is, sequences/maps in flow (JSON) style.
+fn inline(v: &Value) -> Result<String> {
+    Ok(match v {
+        Value::Sequence(_) | Value::Mapping(_) => serde_json::to_string(v)?,
+        _ => serde_yaml::to_string(v)?.trim_end().to_string(),
+    })
+}
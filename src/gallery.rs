@@ -0,0 +1,70 @@
+use anyhow::Result;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::manifest::{saved_images, SavedImage};
+
+/// Written to `out_dir` at the end of a run with `gallery: true`.
+pub const GALLERY_FILE: &str = "gallery.html";
+
+/// Write a self-contained `gallery.html` of every image `run_id` saved, read from
+/// the manifest. Images are linked by file name, so the folder can be moved or
+/// zipped as is; uploaded images with an http(s) URL are linked there instead.
+/// Returns how many images it lists.
+pub async fn write_gallery(out_dir: &Path, run_id: &str) -> Result<usize> {
+    let mut saved = saved_images(out_dir, run_id).await?;
+    saved.sort_by_key(|s| (s.id, s.batch_index));
+    tokio::fs::write(out_dir.join(GALLERY_FILE), render(run_id, &saved)).await?;
+    Ok(saved.len())
+}
+
+fn render(run_id: &str, images: &[SavedImage]) -> String {
+    let total: f64 = images.iter().filter_map(|s| s.cost_usd).sum();
+    let mut html = String::new();
+    let _ = write!(html, r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{run}</title>
+<style>
+body {{ font-family: system-ui, sans-serif; margin: 24px; background: #f6f6f6; color: #222; }}
+header {{ margin-bottom: 16px; }}
+.grid {{ display: grid; grid-template-columns: repeat(auto-fill, minmax(240px, 1fr)); gap: 16px; }}
+figure {{ margin: 0; background: #fff; border-radius: 8px; overflow: hidden; box-shadow: 0 1px 3px rgba(0,0,0,.12); }}
+figure img {{ display: block; width: 100%; height: auto; }}
+figcaption {{ padding: 8px 10px; font-size: 13px; line-height: 1.4; }}
+.meta {{ color: #777; font-size: 12px; margin-bottom: 4px; }}
+</style>
+</head>
+<body>
+<header><h1>{run}</h1><div class="meta">{n} images · ${total:.4}</div></header>
+<main class="grid">
+"#, run = escape(run_id), n = images.len());
+    for s in images {
+        let path = escape(s.url.as_deref().filter(|u| u.starts_with("http")).unwrap_or(&s.path_png));
+        let cost = s.cost_usd.map_or_else(|| "–".to_string(), |c| format!("${c:.4}"));
+        let _ = writeln!(
+            html,
+            r#"<figure><a href="{path}"><img src="{path}" loading="lazy" alt="{label}"></a><figcaption><div class="meta">{label} · {cost}</div>{prompt}</figcaption></figure>"#,
+            label = escape(&s.label()), prompt = escape(&s.prompt),
+        );
+    }
+    html.push_str("</main>\n</body>\n</html>\n");
+    html
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::config::OnImageHookCfg;
+use crate::events::RunEvent;
+
+/// Runs `hooks.on_image` after each saved image, in the background. At most
+/// `concurrency` hooks run at once; failures are logged, never fatal.
+pub struct OnImageHook {
+    cfg: OnImageHookCfg,
+    client: reqwest::Client,
+    slots: Arc<Semaphore>,
+    running: parking_lot::Mutex<JoinSet<()>>,
+}
+
+impl OnImageHook {
+    pub fn new(cfg: OnImageHookCfg) -> Result<Self> {
+        if cfg.command.is_none() && cfg.url.is_none() {
+            anyhow::bail!("hooks.on_image needs a `command` or a `url`");
+        }
+        let client = reqwest::Client::builder().timeout(Duration::from_millis(cfg.timeout_ms)).build()?;
+        let slots = Arc::new(Semaphore::new(cfg.concurrency.max(1)));
+        Ok(Self { cfg, client, slots, running: parking_lot::Mutex::new(JoinSet::new()) })
+    }
+
+    /// Queue the hook for one image; `sidecar` is its sidecar JSON.
+    pub fn fire(&self, image: HookImage, sidecar: Vec<u8>, run_id: String, id: u64, events: Option<broadcast::Sender<RunEvent>>) {
+        let slots = self.slots.clone();
+        let (cfg, client) = (self.cfg.clone(), self.client.clone());
+        let mut running = self.running.lock();
+        while running.try_join_next().is_some() {} // reap finished hooks
+        running.spawn(async move {
+            let Ok(_slot) = slots.acquire_owned().await else { return };
+            if let Err(e) = run_hook(&cfg, &client, &image, &sidecar).await {
+                let msg = format!("#{id} on_image hook: {e:#}");
+                match events {
+                    Some(tx) => { let _ = tx.send(RunEvent::Log { run_id, msg }); }
+                    None => eprintln!("{msg}"),
+                }
+            }
+        });
+    }
+
+    /// Wait for every queued hook to finish.
+    pub async fn drain(&self) {
+        let mut running = std::mem::take(&mut *self.running.lock());
+        while running.join_next().await.is_some() {}
+    }
+}
+
+/// Where a saved image is, as the hook command sees it.
+pub enum HookImage {
+    /// In `out_dir` (`ADGEN_IMAGE`).
+    File(PathBuf),
+    /// Uploaded by `storage` (`ADGEN_IMAGE_URL`).
+    Url(String),
+}
+
+async fn run_hook(cfg: &OnImageHookCfg, client: &reqwest::Client, image: &HookImage, sidecar: &[u8]) -> Result<()> {
+    if let Some(command) = &cfg.command {
+        let mut cmd = tokio::process::Command::new("sh");
+        match image {
+            HookImage::File(path) => cmd.env("ADGEN_IMAGE", path),
+            HookImage::Url(url) => cmd.env("ADGEN_IMAGE_URL", url),
+        };
+        let mut child = cmd
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("spawn hook command")?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // a hook that ignores stdin may close it early; that's fine
+            let _ = stdin.write_all(sidecar).await;
+        }
+        let status = tokio::time::timeout(Duration::from_millis(cfg.timeout_ms), child.wait())
+            .await
+            .context("hook command timed out")??;
+        if !status.success() {
+            anyhow::bail!("hook command exited with {status}");
+        }
+    }
+    if let Some(url) = &cfg.url {
+        client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(sidecar.to_vec())
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+    Ok(())
+}
@@ -1,13 +1,17 @@
 use chrono::Utc;
-use serde::Serialize;
-use std::path::Path;
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::{Path, PathBuf};
 use tokio::{fs, io::AsyncWriteExt};
 
+use crate::config::{RunCfg, SidecarCfg, SidecarFormat, TemplateYaml};
 use crate::providers::ImageResult;
+use crate::sink::ObjectSink;
 
 #[derive(Serialize)]
 struct Sidecar<'a> {
     id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    batch_index: Option<u32>,
     run_id: &'a str,
     provider: &'a str,
     model: &'a str,
@@ -16,11 +20,61 @@ struct Sidecar<'a> {
     created_at: String,
     original_prompt: &'a str,
     rewritten_prompt: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    negative_prompt: Option<&'a str>,
+    /// Seed sent to the provider; regenerating with it reproduces this image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    /// How long the provider call that returned this image took.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
     cost_usd: f64,
     #[serde(skip_serializing_if = "Option::is_none")]
     thumbnail_path: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    derivatives: Vec<Derivative>,
+    /// Score of every best-of-N candidate (aesthetic if configured, else sharpness),
+    /// in generation order; the saved one scored highest.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    candidate_scores: Vec<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aesthetic_score: Option<f64>,
+    /// Dominant colors as `#rrggbb`, most common first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    palette: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Derivative {
+    op: &'static str,
+    path: String,
+}
+
+/// Optional artifacts written next to the image and referenced from its sidecar,
+/// and the sink they are written through.
+pub struct SaveExtras<'a> {
+    pub thumbnail: Option<&'a [u8]>,
+    /// Augmented copies as `(op, png bytes)`, saved as `{stem}_aug1.png`, `{stem}_aug2.png`, ...
+    pub derivatives: Vec<(&'static str, Vec<u8>)>,
+    pub candidate_scores: Vec<f64>,
+    pub aesthetic_score: Option<f64>,
+    pub palette: Vec<String>,
+    /// Set when the image is one of several from a single provider call.
+    pub batch_index: Option<u32>,
+    pub negative_prompt: Option<&'a str>,
+    pub seed: Option<u64>,
+    pub latency_ms: Option<u64>,
+    pub sidecar: SidecarCfg,
+    /// Write the prompts, model and run id into the image as PNG text chunks.
+    pub embed_metadata: bool,
+    pub sink: &'a dyn ObjectSink,
 }
 
+/// Consolidated sidecar log used when `sidecar.consolidated` is set.
+pub const SIDECARS_JSONL: &str = "sidecars.jsonl";
+
+/// Write the image, its extras and its sidecar through `extras.sink`; returns the
+/// sidecar as compact JSON and the image's URL when the sink gives one.
 pub async fn save_image_with_sidecar(
     out_dir: &Path,
     run_id: &str,
@@ -30,52 +84,356 @@ pub async fn save_image_with_sidecar(
     original_prompt: &str,
     rewritten_prompt: Option<&str>,
     cost_usd: f64,
-    thumbnail: Option<&[u8]>,
-) -> anyhow::Result<()> {
+    extras: SaveExtras<'_>,
+) -> anyhow::Result<(Vec<u8>, Option<String>)> {
     fs::create_dir_all(out_dir).await?;
-    let stem = format!("{:08}-{}-{}", id, provider, res.model);
-    let png = out_dir.join(format!("{}.png", stem));
-    let json = out_dir.join(format!("{}.json", stem));
-    let png_tmp = out_dir.join(format!("{}.png.tmp", stem));
-    let json_tmp = out_dir.join(format!("{}.json.tmp", stem));
+    let stem = image_stem(id, extras.batch_index, provider, &res.model);
 
-    {
-        let mut f = fs::File::create(&png_tmp).await?;
-        f.write_all(&res.bytes).await?;
-        let _ = f.sync_all().await;
-    }
-    fs::rename(&png_tmp, &png).await?;
+    let embedded = extras.embed_metadata.then(|| {
+        let mut entries = vec![("prompt", original_prompt)];
+        if let Some(r) = rewritten_prompt { entries.push(("rewritten_prompt", r)); }
+        entries.extend([("provider", provider), ("model", res.model.as_str()), ("run_id", run_id)]);
+        crate::post::embed_png_text(&res.bytes, &entries)
+    }).flatten();
+    let url = extras.sink.put(&format!("{stem}.png"), embedded.unwrap_or_else(|| res.bytes.clone()), "image/png").await?;
 
     // Save thumbnail if provided
-    let thumbnail_path = if let Some(thumb_bytes) = thumbnail {
-        let thumb_name = format!("{}_thumb.png", stem);
-        let thumb_path = out_dir.join(&thumb_name);
-        let thumb_tmp = out_dir.join(format!("{}_thumb.png.tmp", stem));
-        {
-            let mut f = fs::File::create(&thumb_tmp).await?;
-            f.write_all(thumb_bytes).await?;
-            let _ = f.sync_all().await;
-        }
-        fs::rename(&thumb_tmp, &thumb_path).await?;
-        Some(thumb_name)
+    let thumbnail_path = if let Some(thumb_bytes) = extras.thumbnail {
+        let name = format!("{stem}_thumb.png");
+        extras.sink.put(&name, thumb_bytes.to_vec(), "image/png").await?;
+        Some(name)
     } else {
         None
     };
 
+    let mut derivatives = Vec::with_capacity(extras.derivatives.len());
+    for (n, (op, bytes)) in extras.derivatives.into_iter().enumerate() {
+        let path = format!("{stem}_aug{}.png", n + 1);
+        extras.sink.put(&path, bytes, "image/png").await?;
+        derivatives.push(Derivative { op, path });
+    }
+
     let sidecar = Sidecar {
-        id, run_id, provider, model: &res.model, width: res.width, height: res.height,
+        id, batch_index: extras.batch_index, run_id, provider, model: &res.model, width: res.width, height: res.height,
         created_at: Utc::now().to_rfc3339(),
         original_prompt,
         rewritten_prompt,
+        negative_prompt: extras.negative_prompt,
+        seed: extras.seed,
+        latency_ms: extras.latency_ms,
         cost_usd,
         thumbnail_path,
+        derivatives,
+        candidate_scores: extras.candidate_scores,
+        aesthetic_score: extras.aesthetic_score,
+        palette: extras.palette,
     };
-    let bytes = serde_json::to_vec_pretty(&sidecar)?;
+    if extras.sidecar.consolidated {
+        // one write per record so concurrent appends don't interleave
+        let mut line = serde_json::to_vec(&sidecar)?;
+        line.push(b'\n');
+        let mut f = fs::OpenOptions::new().create(true).append(true).open(out_dir.join(SIDECARS_JSONL)).await?;
+        f.write_all(&line).await?;
+        line.pop();
+        return Ok((line, url));
+    }
+    let bytes = match extras.sidecar.format {
+        SidecarFormat::Pretty => serde_json::to_vec_pretty(&sidecar)?,
+        SidecarFormat::Compact => serde_json::to_vec(&sidecar)?,
+    };
+    extras.sink.put(&format!("{stem}.json"), bytes, "application/json").await?;
+    Ok((serde_json::to_vec(&sidecar)?, url))
+}
+
+/// Every sidecar record in `out_dir`, from per-image `*.json` files and/or a
+/// consolidated `sidecars.jsonl`, whichever are present. Other JSON files that
+/// don't parse as `T` are skipped.
+pub async fn read_sidecars<T: DeserializeOwned>(out_dir: &Path) -> anyhow::Result<Vec<T>> {
+    let mut sidecars = Vec::new();
+    let mut rd = fs::read_dir(out_dir).await?;
+    while let Some(entry) = rd.next_entry().await? {
+        let path = entry.path();
+        if path.file_name().and_then(|s| s.to_str()) == Some(SIDECARS_JSONL) {
+            let txt = match fs::read_to_string(&path).await {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            sidecars.extend(txt.lines().filter_map(|l| serde_json::from_str::<T>(l).ok()));
+            continue;
+        }
+        if path.extension().and_then(|s| s.to_str()) != Some("json") {
+            continue;
+        }
+        let bytes = match fs::read(&path).await {
+            Ok(b) => b,
+            Err(_) => continue,
+        };
+        if let Ok(s) = serde_json::from_slice(&bytes) {
+            sidecars.push(s);
+        }
+    }
+    Ok(sidecars)
+}
+
+/// File stem for an image: `{id:08}-{provider}-{model}` (`{id:08}_{k}-...` for the
+/// k-th image of a batch), with path-unsafe characters in the provider/model
+/// names (e.g. `stabilityai/sdxl`) replaced by `_`.
+pub fn image_stem(id: u64, batch_index: Option<u32>, provider: &str, model: &str) -> String {
+    let id = match batch_index {
+        Some(k) => format!("{:08}_{}", id, k),
+        None => format!("{:08}", id),
+    };
+    format!("{}-{}-{}", id, sanitize_filename_part(provider), sanitize_filename_part(model))
+}
+
+fn sanitize_filename_part(s: &str) -> String {
+    let cleaned: String = s
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect();
+    // never let a name collapse to a relative path component
+    if cleaned.chars().all(|c| c == '.') { cleaned.replace('.', "_") } else { cleaned }
+}
+
+/// Atomically write `{stem}_{suffix}.png`, returning the file name.
+pub(crate) async fn write_suffixed(out_dir: &Path, stem: &str, suffix: &str, bytes: &[u8]) -> anyhow::Result<String> {
+    let name = format!("{}_{}.png", stem, suffix);
+    let tmp = out_dir.join(format!("{}.tmp", name));
     {
-        let mut f = fs::File::create(&json_tmp).await?;
-        f.write_all(&bytes).await?;
+        let mut f = fs::File::create(&tmp).await?;
+        f.write_all(bytes).await?;
         let _ = f.sync_all().await;
     }
-    fs::rename(&json_tmp, &json).await?;
+    fs::rename(&tmp, out_dir.join(&name)).await?;
+    Ok(name)
+}
+
+/// Bundle one run into `out_dir/{run_id}.zip` (run ids read `run-...`): its images with their
+/// thumbnails, derivatives and sidecars (found through the manifest, since file
+/// names carry ids rather than run ids), its lines of `manifest.jsonl`,
+/// `failures.jsonl` and `sidecars.jsonl`, and its event log. Files are copied in
+/// a streaming fashion, so memory stays flat however big the run. Returns the
+/// archive and how many images it holds; `None` if the manifest has no record
+/// of the run.
+pub async fn zip_run(out_dir: &Path, run_id: &str) -> anyhow::Result<Option<(PathBuf, usize)>> {
+    let images = crate::manifest::saved_images(out_dir, run_id).await?;
+    if images.is_empty() {
+        // a run can end with nothing saved and still be worth its failures
+        let manifest = fs::read_to_string(out_dir.join(crate::manifest::MANIFEST_FILE)).await.unwrap_or_default();
+        if !manifest.lines().any(|l| line_is_for(l, run_id)) { return Ok(None); }
+    }
+
+    let mut stems: Vec<String> = images.iter().filter_map(|s| s.path_png.strip_suffix(".png").map(str::to_string)).collect();
+    stems.sort();
+    stems.dedup();
+    let mut files: Vec<String> = Vec::new();
+    let mut rd = fs::read_dir(out_dir).await?;
+    while let Some(entry) = rd.next_entry().await? {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else { continue };
+        if name.ends_with(".tmp") || !entry.file_type().await?.is_file() { continue; }
+        let belongs = stems.iter().any(|stem| {
+            name.strip_prefix(stem.as_str()).is_some_and(|rest| rest == ".png" || rest == ".json" || (rest.starts_with('_') && rest.ends_with(".png")))
+        });
+        if belongs { files.push(name); }
+    }
+    files.sort();
+
+    let (out_dir, run_id) = (out_dir.to_path_buf(), run_id.to_string());
+    tokio::task::spawn_blocking(move || -> anyhow::Result<Option<(PathBuf, usize)>> {
+        use std::io::{BufRead, Write};
+        use zip::{write::SimpleFileOptions, CompressionMethod};
+
+        let path = out_dir.join(format!("{run_id}.zip"));
+        // concurrent downloads of one run each build their own archive; the rename keeps whichever lands last
+        let tmp = out_dir.join(format!("{run_id}.zip.{}.tmp", uuid::Uuid::new_v4().simple()));
+        let mut zip = zip::ZipWriter::new(std::io::BufWriter::new(std::fs::File::create(&tmp)?));
+        // PNGs are already compressed
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored).large_file(true);
+        let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated).large_file(true);
+        for name in &files {
+            zip.start_file(name.as_str(), if name.ends_with(".png") { stored } else { deflated })?;
+            std::io::copy(&mut std::fs::File::open(out_dir.join(name))?, &mut zip)?;
+        }
+        for log in [crate::manifest::MANIFEST_FILE, crate::manifest::FAILURES_FILE, SIDECARS_JSONL] {
+            let Ok(f) = std::fs::File::open(out_dir.join(log)) else { continue };
+            let mut started = false;
+            for line in std::io::BufReader::new(f).lines() {
+                let line = line?;
+                if !line_is_for(&line, &run_id) { continue; }
+                if !started { zip.start_file(log, deflated)?; started = true; }
+                zip.write_all(line.as_bytes())?;
+                zip.write_all(b"\n")?;
+            }
+        }
+        let events = crate::events::event_log_path(&out_dir, &run_id);
+        if let Ok(mut f) = std::fs::File::open(&events) {
+            zip.start_file(format!("{run_id}/events.jsonl"), deflated)?;
+            std::io::copy(&mut f, &mut zip)?;
+        }
+        zip.finish()?.flush()?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(Some((path, stems.len())))
+    }).await?
+}
+
+/// Whether a JSONL line is a record of `run_id`.
+fn line_is_for(line: &str, run_id: &str) -> bool {
+    #[derive(serde::Deserialize)]
+    struct RunOnly{ run_id: Option<String> }
+    serde_json::from_str::<RunOnly>(line).is_ok_and(|r| r.run_id.as_deref() == Some(run_id))
+}
+
+/// Write the effective run config and template into `out_dir` so a finished
+/// run can be audited (or resumed) even if the live files were edited since.
+pub async fn save_run_snapshot(out_dir: &Path, cfg: &RunCfg, tpl: &TemplateYaml) -> anyhow::Result<()> {
+    fs::create_dir_all(out_dir).await?;
+    for (name, body) in [
+        ("config.snapshot.yaml", serde_yaml::to_string(cfg)?),
+        ("template.snapshot.yaml", serde_yaml::to_string(tpl)?),
+    ] {
+        let path = out_dir.join(name);
+        let tmp = out_dir.join(format!("{}.tmp", name));
+        fs::write(&tmp, body.as_bytes()).await?;
+        fs::rename(&tmp, &path).await?;
+    }
     Ok(())
 }
+
+/// Delete `*.tmp` files (half-written images, sidecars, checkpoints) that an
+/// interrupted run left in `out_dir`. Only safe while holding the `OutDirLock`.
+pub async fn remove_stale_tmp(out_dir: &Path) -> anyhow::Result<usize> {
+    let mut removed = 0;
+    let mut entries = match fs::read_dir(out_dir).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|e| e == "tmp") && entry.file_type().await?.is_file() {
+            fs::remove_file(&path).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Advisory lock on `out_dir/.adgen.lock`, held for the lifetime of a run so two
+/// processes can't interleave writes into the same directory. The OS drops the
+/// lock if the process dies, so a crashed run never leaves it stuck.
+pub struct OutDirLock{ _file: std::fs::File }
+
+impl OutDirLock{
+    /// Take the lock for `run_id`. With `force`, a held lock is ignored and `None` is returned.
+    pub fn acquire(out_dir: &Path, run_id: &str, force: bool) -> anyhow::Result<Option<Self>> {
+        use fs2::FileExt;
+        use std::io::{Read, Seek, Write};
+
+        let path = out_dir.join(".adgen.lock");
+        let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        if file.try_lock_exclusive().is_err() {
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            let holder = if holder.trim().is_empty() { "unknown" } else { holder.trim() };
+            if force {
+                tracing::warn!("{} is locked by run {holder}; continuing because of --force", out_dir.display());
+                return Ok(None);
+            }
+            anyhow::bail!("{} is in use by run {holder}; wait for it to finish or pass --force", out_dir.display());
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(run_id.as_bytes())?;
+        Ok(Some(Self{ _file: file }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::LocalSink;
+
+    fn png(w: u32, h: u32) -> Vec<u8> {
+        let img = image::RgbaImage::from_pixel(w, h, image::Rgba([200, 40, 40, 255]));
+        let mut out = std::io::Cursor::new(Vec::new());
+        img.write_to(&mut out, image::ImageFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn stem_flattens_path_separators() {
+        assert_eq!(image_stem(7, None, "replicate", "stabilityai/stable-diffusion-xl"), "00000007-replicate-stabilityai_stable-diffusion-xl");
+        assert_eq!(image_stem(7, Some(2), "fal", "fal-ai\\flux/dev"), "00000007_2-fal-fal-ai_flux_dev");
+        assert_eq!(image_stem(1, None, "mock", ".."), "00000001-mock-__");
+    }
+
+    #[tokio::test]
+    async fn slash_in_model_saves_a_flat_file() {
+        let out_dir = std::env::temp_dir().join(format!("adgen-io-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&out_dir).await.unwrap();
+        let sink = LocalSink::new(&out_dir);
+        let res = ImageResult {
+            bytes: png(16, 16), width: 16, height: 16, prompt_used: "a red square".into(),
+            model: "stabilityai/stable-diffusion-xl".into(), cost_usd: None, served_by: None,
+        };
+        let extras = SaveExtras {
+            thumbnail: Some(&png(4, 4)), derivatives: vec![("flip", png(16, 16))], candidate_scores: vec![], aesthetic_score: None,
+            palette: vec![], batch_index: None, negative_prompt: None, seed: None, latency_ms: None,
+            sidecar: SidecarCfg::default(), embed_metadata: false, sink: &sink,
+        };
+        save_image_with_sidecar(&out_dir, "run-test", 3, "replicate", &res, "a red square", None, 0.0, extras).await.unwrap();
+
+        // the orchestrator records `{stem}.png` as the manifest's path_png
+        let path_png = format!("{}.png", image_stem(3, None, "replicate", &res.model));
+        assert!(!path_png.contains('/'));
+        assert!(out_dir.join(&path_png).is_file());
+
+        let mut names = vec![];
+        let mut rd = fs::read_dir(&out_dir).await.unwrap();
+        while let Some(entry) = rd.next_entry().await.unwrap() {
+            assert!(entry.file_type().await.unwrap().is_file(), "{} is not a file", entry.path().display());
+            names.push(entry.file_name().into_string().unwrap());
+        }
+        names.sort();
+        assert_eq!(names, [
+            "00000003-replicate-stabilityai_stable-diffusion-xl.json",
+            "00000003-replicate-stabilityai_stable-diffusion-xl.png",
+            "00000003-replicate-stabilityai_stable-diffusion-xl_aug1.png",
+            "00000003-replicate-stabilityai_stable-diffusion-xl_thumb.png",
+        ]);
+        let sidecar: serde_json::Value = serde_json::from_slice(&fs::read(out_dir.join(&names[0])).await.unwrap()).unwrap();
+        assert_eq!(sidecar["thumbnail_path"], "00000003-replicate-stabilityai_stable-diffusion-xl_thumb.png");
+        assert_eq!(sidecar["derivatives"][0]["path"], "00000003-replicate-stabilityai_stable-diffusion-xl_aug1.png");
+
+        fs::remove_dir_all(&out_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_zips_of_one_run_do_not_clash() {
+        let out_dir = std::env::temp_dir().join(format!("adgen-io-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&out_dir).await.unwrap();
+        // noise doesn't compress, so each build takes long enough to overlap the others
+        let noise: Vec<u8> = (0..4u32 << 20).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let mut manifest = String::new();
+        for id in 1..=4u64 {
+            let stem = image_stem(id, None, "mock", "mock-v1");
+            fs::write(out_dir.join(format!("{stem}.png")), &noise).await.unwrap();
+            manifest += &format!("{{\"id\":{id},\"run_id\":\"run-test\",\"status\":\"saved\",\"prompt\":\"p\",\"path_png\":\"{stem}.png\"}}\n");
+        }
+        fs::write(out_dir.join(crate::manifest::MANIFEST_FILE), manifest).await.unwrap();
+
+        let zips = futures_util::future::join_all((0..4).map(|_| zip_run(&out_dir, "run-test"))).await;
+        for z in zips {
+            let (path, images) = z.unwrap().unwrap();
+            assert_eq!(images, 4);
+            let archive = zip::ZipArchive::new(std::fs::File::open(path).unwrap()).unwrap();
+            assert_eq!(archive.len(), 5);
+        }
+        let mut rd = fs::read_dir(&out_dir).await.unwrap();
+        while let Some(entry) = rd.next_entry().await.unwrap() {
+            assert!(!entry.file_name().to_string_lossy().ends_with(".tmp"), "left {:?} behind", entry.file_name());
+        }
+
+        fs::remove_dir_all(&out_dir).await.unwrap();
+    }
+}
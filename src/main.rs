@@ -6,12 +6,11 @@ use std::sync::Arc;
 use tokio::sync::broadcast;
 use tracing_subscriber::EnvFilter;
 
-mod auth; mod backoff; mod config; mod cost_tracking; mod dedupe; mod events; mod io; mod manifest; mod orchestrator; mod post; mod postgres; mod providers; mod prompts; mod rate_limit; mod rewrite; mod api;
-use config::{Mode, RunCfg, TemplateYaml};
+mod auth; mod backoff; mod config; mod cost_tracking; mod dedupe; mod events; mod io; mod manifest; mod orchestrator; mod post; mod postgres; mod providers; mod prompts; mod rate_limit; mod rewrite; mod api; mod watch; mod explain; mod dataset; mod qa; mod checkpoint; mod reprocess; mod secrets; mod hooks; mod gallery; mod sink;
+use config::{AuthStyle, RewriteKind, RunCfg, TemplateYaml};
 
-use providers::{ImageProvider, MockProvider, OpenAIProvider};
-use prompts::{PromptGeneral, PromptStyle, PromptTemplate, VariantGenerator};
-use rewrite::{OpenAIRewriter, RewriteCache};
+use providers::{ApiKeys, CachingProvider, CoalescingProvider, FallbackProvider, FalProvider, ImageProvider, ImagenProvider, LimitedProvider, MockProvider, OpenAIEndpoint, OpenAIProvider, ReplicateProvider, StabilityProvider};
+use rewrite::{NoopRewriter, OllamaRewriter, OpenAIRewriter, RewriteCache};
 
 #[derive(Parser, Debug)]
 #[command(name = "adgen", version)]
@@ -35,6 +34,71 @@ enum Command {
 
         #[arg(long)]
         resume: bool,
+
+        /// Stop rewriting prompts once rewrite spend reaches this many USD
+        #[arg(long)]
+        max_prompt_cost: Option<f64>,
+
+        /// Run even if another adgen process holds the output directory lock
+        #[arg(long)]
+        force: bool,
+
+        /// Also treat every PNG in this directory as already seen when deduping
+        #[arg(long, value_name = "DIR")]
+        dedupe_existing: Option<PathBuf>,
+
+        /// Print the effective config, annotated with where each value came from, and exit
+        #[arg(long)]
+        explain: bool,
+    },
+
+    /// Regenerate a small sample whenever the config or template changes
+    Watch {
+        #[arg(long)]
+        config: PathBuf,
+
+        #[arg(long)]
+        template: PathBuf,
+
+        /// Images per sample
+        #[arg(long, default_value_t = 4)]
+        sample: u64,
+
+        #[arg(long, default_value = "./adgen-watch")]
+        scratch_dir: PathBuf,
+    },
+
+    /// Summarize spend from the sidecars in an output directory
+    Cost {
+        #[arg(long)]
+        out_dir: PathBuf,
+
+        /// Only count images created since an RFC 3339 time or a duration ago (e.g. 24h, 7d)
+        #[arg(long)]
+        since: Option<String>,
+    },
+
+    /// Write a dataset file (captions, dimensions, tags) for the images in an output directory
+    ExportDataset {
+        #[arg(long)]
+        out_dir: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = dataset::DatasetFormat::Coco)]
+        format: dataset::DatasetFormat,
+
+        /// Defaults to `dataset.coco.json` / `dataset.jsonl` inside `out_dir`
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Bundle one run's images, sidecars and manifest lines into `out_dir/<run_id>.zip`
+    Zip {
+        #[arg(long)]
+        out_dir: PathBuf,
+
+        /// Defaults to the last run in the manifest
+        #[arg(long)]
+        run_id: Option<String>,
     },
 
     /// Start the local HTTP API for the frontend
@@ -87,8 +151,46 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env()).init();
     let cli = Cli::parse();
     match cli.cmd {
-        Command::Run { config, template, out_dir, resume } => {
-            run_once(config, template, out_dir, resume, None, None).await
+        Command::Run { config, template, out_dir, resume, max_prompt_cost, force, dedupe_existing, explain } => {
+            let overrides = RunOverrides { out_dir, resume, max_prompt_cost, force, dedupe_existing, stop_on_ctrl_c: true };
+            if explain {
+                return explain_config(&config, &template, &overrides).await;
+            }
+            run_once(config, template, overrides, None, None).await
+        }
+        Command::Watch { config, template, sample, scratch_dir } => {
+            watch::watch(config, template, sample, scratch_dir).await
+        }
+        Command::Cost { out_dir, since } => {
+            let since = since.as_deref().map(cost_tracking::parse_since).transpose()?;
+            let summary = cost_tracking::compute_cost_summary(&out_dir, since).await?;
+            println!("Total: ${:.4} over {} images (avg ${:.4})", summary.total_cost, summary.image_count, summary.avg_cost_per_image);
+            println!("\nBy run:");
+            for r in &summary.runs {
+                println!("  {:<44} ${:>10.4}  {:>6} images", r.run_id, r.cost, r.image_count);
+            }
+            println!("\nBy provider:");
+            for p in &summary.by_provider {
+                println!("  {:<44} ${:>10.4}  {:>6} images", format!("{}/{}", p.provider, p.model), p.cost, p.image_count);
+            }
+            Ok(())
+        }
+        Command::ExportDataset { out_dir, format, output } => {
+            let (path, n) = dataset::export_dataset(&out_dir, format, output).await?;
+            println!("Wrote {} images to {}", n, path.display());
+            Ok(())
+        }
+        Command::Zip { out_dir, run_id } => {
+            let run_id = match run_id {
+                Some(id) => id,
+                None => manifest::saved_ids(&out_dir, None).await?
+                    .map(|(id, _)| id)
+                    .with_context(|| format!("no runs recorded in {}", out_dir.join(manifest::MANIFEST_FILE).display()))?,
+            };
+            let (path, n) = io::zip_run(&out_dir, &run_id).await?
+                .with_context(|| format!("no records of run {run_id} in {}", out_dir.display()))?;
+            println!("Wrote {} images to {}", n, path.display());
+            Ok(())
         }
         Command::Serve { bind, config_path, template_path, db_path: _ } => {
             let pool = postgres::connect().await?;
@@ -97,112 +199,419 @@ async fn main() -> Result<()> {
     }
 }
 
-fn make_rewriter(key: String, model: String, system: String, max_tokens: u32) -> Arc<dyn rewrite::PromptRewriter> {
-    Arc::new(OpenAIRewriter::new(key, model, system, max_tokens))
+fn make_rewriter(cfg: &config::RewriteCfg, client: reqwest::Client, key: String, model: String, system: String, max_tokens: u32) -> Arc<dyn rewrite::PromptRewriter> {
+    let sampling = rewrite::Sampling { temperature: cfg.temperature, top_p: cfg.top_p };
+    match cfg.kind {
+        RewriteKind::OpenAI => Arc::new(OpenAIRewriter::new(client, key, model, system, max_tokens).with_sampling(sampling).with_examples(cfg.examples.clone())),
+        RewriteKind::Ollama => {
+            let base_url = cfg.base_url.clone().unwrap_or_else(|| "http://localhost:11434".into());
+            Arc::new(OllamaRewriter::new(client, base_url, model, system, max_tokens).with_sampling(sampling).with_examples(cfg.examples.clone()))
+        }
+        RewriteKind::Noop => Arc::new(NoopRewriter),
+    }
+}
+
+/// Command-line overrides applied on top of the loaded run config.
+#[derive(Debug, Clone, Default)]
+pub struct RunOverrides {
+    pub out_dir: Option<PathBuf>,
+    pub resume: bool,
+    pub max_prompt_cost: Option<f64>,
+    pub force: bool,
+    /// Reference images to seed the deduper with; turns dedupe on.
+    pub dedupe_existing: Option<PathBuf>,
+    /// On Ctrl-C, stop starting images and let in-flight ones finish (`adgen run` only).
+    pub stop_on_ctrl_c: bool,
+}
+
+impl RunOverrides {
+    /// Apply to `cfg`, returning the dotted config paths that were overridden.
+    pub fn apply(&self, cfg: &mut RunCfg) -> Vec<&'static str> {
+        let mut applied = Vec::new();
+        if let Some(out_dir) = &self.out_dir {
+            cfg.out_dir = out_dir.clone();
+            applied.push("out_dir");
+        }
+        if let Some(cap) = self.max_prompt_cost {
+            cfg.rewrite.max_cost_usd = Some(cap);
+            applied.push("rewrite.max_cost_usd");
+        }
+        if self.dedupe_existing.is_some() && !cfg.dedupe.enabled {
+            cfg.dedupe.enabled = true;
+            applied.push("dedupe.enabled");
+        }
+        applied
+    }
+}
+
+async fn explain_config(config: &PathBuf, template: &PathBuf, overrides: &RunOverrides) -> Result<()> {
+    let raw = tokio::fs::read_to_string(config).await?;
+    let mut cfg: RunCfg = serde_yaml::from_str(&raw)?;
+    let tpl: TemplateYaml = serde_yaml::from_str(&tokio::fs::read_to_string(template).await?)?;
+    let overridden = overrides.apply(&mut cfg);
+    print!("{}", explain::explain(&raw, &cfg, &overridden, &tpl)?);
+    Ok(())
 }
 
 pub async fn run_once(
     config: PathBuf,
     template: PathBuf,
-    out_dir: Option<PathBuf>,
-    _resume: bool,
+    overrides: RunOverrides,
     run_id: Option<String>,
     events_tx: Option<broadcast::Sender<events::RunEvent>>,
 ) -> Result<()> {
     let run_id = run_id.unwrap_or_else(|| format!("run-{}", chrono::Utc::now().format("%Y%m%d-%H%M%S")));
-    let run_id_for_orch = run_id.clone();
-    let events_for_orch = events_tx.clone();
 
     let result = async {
         let cfg: RunCfg = serde_yaml::from_str(&tokio::fs::read_to_string(&config).await?)?;
         let tpl_yaml: TemplateYaml = serde_yaml::from_str(&tokio::fs::read_to_string(&template).await?)?;
-        let out_dir = out_dir.unwrap_or(cfg.clone().out_dir);
-        validate_output_dir(&out_dir).await?;
+        run_loaded(cfg, tpl_yaml, overrides, run_id.clone(), events_tx.clone()).await
+    }.await;
+
+    if let Err(ref e) = result {
+        if let Some(tx) = &events_tx {
+            let _ = tx.send(events::RunEvent::Failed { run_id: run_id.clone(), error: format!("{e:#}") });
+        }
+    }
 
-        // Provider
-        let provider: Arc<dyn ImageProvider> = match cfg.provider.kind.as_str(){
-            "mock" => {
-                Arc::new(MockProvider{ model: cfg.provider.model.clone().unwrap_or_else(||"mock-v1".into()), w: cfg.provider.width.unwrap_or(512), h: cfg.provider.height.unwrap_or(512) }) as Arc<dyn ImageProvider>
+    result
+}
+
+/// One provider from its config section (`provider` or an entry of `provider.fallbacks`).
+fn build_provider(p: &config::ProviderCfg, cfg: &RunCfg, secrets: &dyn secrets::SecretSource, http: &reqwest::Client) -> Result<Arc<dyn ImageProvider>> {
+    let provider_model = p.model.as_deref().map(|m| cfg.resolve_model(m)).transpose()?;
+    Ok(match p.kind.as_str(){
+        "mock" => {
+            Arc::new(MockProvider{ model: provider_model.clone().unwrap_or_else(||"mock-v1".into()), w: p.width.unwrap_or(512), h: p.height.unwrap_or(512), pattern: cfg.mock.pattern, render_text: cfg.mock.render_text, seed: cfg.mock.seed }) as Arc<dyn ImageProvider>
+        }
+        "openai" => {
+            let keys = match p.auth_style {
+                AuthStyle::None => vec![String::new()],
+                _ => p.key_envs("OPENAI_API_KEY").into_iter().map(|env| secrets.get(env)).collect::<Result<_>>()?,
+            };
+            let mut endpoint = OpenAIEndpoint { api_version: p.api_version.clone(), auth: p.auth_style, ..Default::default() };
+            if let Some(base_url) = &p.base_url { endpoint.base_url = base_url.clone(); }
+            let model = provider_model.clone().unwrap_or_else(||"gpt-image-1.5".into());
+            let (quality, style) = (p.quality.clone(), p.style.clone());
+            OpenAIProvider::check_options(&model, quality.as_deref(), style.as_deref())?;
+            let (w, h) = (p.width.unwrap_or(1024), p.height.unwrap_or(1024));
+            // an explicit price wins; otherwise dall-e-3 is priced from its quality and size
+            let price = p.price_usd_per_image
+                .or_else(|| OpenAIProvider::list_price(&model, quality.as_deref(), w, h))
+                .unwrap_or(0.0);
+            Arc::new(OpenAIProvider{ client: http.clone(), model, api_keys: ApiKeys::new(keys)?, w, h, price, endpoint, quality, style }) as Arc<dyn ImageProvider>
+        }
+        kind if !p.api_key_envs.is_empty() => anyhow::bail!("provider.api_key_envs is only supported by the openai provider, not {kind}"),
+        "fal" => {
+            let key = secrets.get(p.api_key_env.as_deref().unwrap_or("FAL_KEY"))?;
+            Arc::new(FalProvider{ client: http.clone(), model: provider_model.clone().unwrap_or_else(||"fal-ai/flux/dev".into()), api_key: key, w: p.width.unwrap_or(1024), h: p.height.unwrap_or(1024), price: p.price_usd_per_image.unwrap_or(0.0)}) as Arc<dyn ImageProvider>
+        }
+        "imagen" => {
+            let key = secrets.get(p.api_key_env.as_deref().unwrap_or("GEMINI_API_KEY"))?;
+            Arc::new(ImagenProvider{ client: http.clone(), model: provider_model.clone().unwrap_or_else(||"imagen-4.0-generate-001".into()), api_key: key, w: p.width.unwrap_or(1024), h: p.height.unwrap_or(1024), price: p.price_usd_per_image.unwrap_or(0.0)}) as Arc<dyn ImageProvider>
+        }
+        "stability" => {
+            let key = secrets.get(p.api_key_env.as_deref().unwrap_or("STABILITY_API_KEY"))?;
+            Arc::new(StabilityProvider{ client: http.clone(), model: provider_model.clone().unwrap_or_else(||"core".into()), api_key: key, w: p.width.unwrap_or(1024), h: p.height.unwrap_or(1024), price: p.price_usd_per_image.unwrap_or(0.0)}) as Arc<dyn ImageProvider>
+        }
+        "replicate" => {
+            let key = secrets.get(p.api_key_env.as_deref().unwrap_or("REPLICATE_API_TOKEN"))?;
+            let model = provider_model.clone().context("provider.model is required for replicate (a version id or owner/name)")?;
+            Arc::new(ReplicateProvider{
+                client: http.clone(), model, api_key: key,
+                w: p.width.unwrap_or(1024), h: p.height.unwrap_or(1024),
+                price: p.price_usd_per_image.unwrap_or(0.0),
+                poll_every: std::time::Duration::from_millis(p.poll_interval_ms.unwrap_or(1000)),
+                max_wait: std::time::Duration::from_secs(p.max_poll_secs.unwrap_or(300)),
+                init_image_url: Default::default(),
+            }) as Arc<dyn ImageProvider>
+        }
+        other => anyhow::bail!("unknown provider: {other}"),
+    })
+}
+
+/// Run with an already-parsed config and template (the body of `run_once`).
+pub async fn run_loaded(
+    mut cfg: RunCfg,
+    tpl_yaml: TemplateYaml,
+    overrides: RunOverrides,
+    run_id: String,
+    events_tx: Option<broadcast::Sender<events::RunEvent>>,
+) -> Result<()> {
+    overrides.apply(&mut cfg);
+    let out_dir = cfg.out_dir.clone();
+    validate_output_dir(&out_dir).await?;
+    let _lock = io::OutDirLock::acquire(&out_dir, &run_id, overrides.force)?;
+    // with --force another run may still be writing its temp files
+    if _lock.is_some() {
+        let removed = io::remove_stale_tmp(&out_dir).await?;
+        if removed > 0 { println!("Removed {removed} leftover .tmp files from an interrupted run"); }
+    }
+
+    // Snapshot what actually runs (after CLI overrides)
+    io::save_run_snapshot(&out_dir, &cfg, &tpl_yaml).await?;
+
+    let secrets = secrets::from_cfg(&cfg.secrets)?;
+    if cfg.orchestrator.max_cost_usd.is_some_and(|c| c.is_nan() || c < 0.0) {
+        anyhow::bail!("orchestrator.max_cost_usd must be a non-negative number");
+    }
+    if cfg.orchestrator.best_of > 1 && cfg.orchestrator.images_per_call > 1 {
+        anyhow::bail!("orchestrator.best_of and orchestrator.images_per_call can't both be above 1");
+    }
+
+    // One client (and connection pool) for every provider and the rewriter
+    let mut http = reqwest::Client::builder().pool_max_idle_per_host(cfg.orchestrator.concurrency.max(1));
+    if let Some(secs) = cfg.provider.request_timeout_secs.filter(|&s| s > 0) {
+        http = http.timeout(std::time::Duration::from_secs(secs));
+    }
+    let http = http.build()?;
+
+    // Provider
+    // one semaphore per provider name, shared by the primary and any fallback of that kind
+    let mut limits = cfg.orchestrator.provider_concurrency.clone();
+    let mut provider_permits = std::collections::HashMap::new();
+    let mut limited = |p: Arc<dyn ImageProvider>| -> Result<Arc<dyn ImageProvider>> {
+        let name = p.name().to_string();
+        let n = match limits.remove(&name) {
+            Some(0) => anyhow::bail!("orchestrator.provider_concurrency.{name} must be at least 1"),
+            Some(n) => n,
+            None => match provider_permits.get(&name) {
+                Some(permits) => return Ok(Arc::new(LimitedProvider::new(p, Arc::clone(permits)))),
+                None => return Ok(p),
+            },
+        };
+        let permits = Arc::new(tokio::sync::Semaphore::new(n));
+        provider_permits.insert(name, permits.clone());
+        Ok(Arc::new(LimitedProvider::new(p, permits)))
+    };
+    let provider = limited(build_provider(&cfg.provider, &cfg, secrets.as_ref(), &http)?)?;
+    let provider: Arc<dyn ImageProvider> = if cfg.provider.fallbacks.is_empty() {
+        provider
+    } else {
+        let mut chain = vec![provider];
+        for fallback in &cfg.provider.fallbacks {
+            if !fallback.fallbacks.is_empty() {
+                anyhow::bail!("provider.fallbacks entries can't have fallbacks of their own");
             }
-            "openai" => {
-                let key = std::env::var(cfg.provider.api_key_env.clone().unwrap_or_else(||"OPENAI_API_KEY".into()))?;
-                Arc::new(OpenAIProvider{ client:reqwest::Client::new(), model: cfg.provider.model.clone().unwrap_or_else(||"gpt-image-1.5".into()), api_key: key, w: cfg.provider.width.unwrap_or(1024), h: cfg.provider.height.unwrap_or(1024), price: cfg.provider.price_usd_per_image.unwrap_or(0.0)}) as Arc<dyn ImageProvider>
+            if fallback.cache_dir.is_some() {
+                anyhow::bail!("provider.fallbacks entries can't set cache_dir; provider.cache_dir covers the whole chain");
             }
-            other => anyhow::bail!("unknown provider: {other}"),
-        };
+            if fallback.request_timeout_secs.is_some() {
+                anyhow::bail!("provider.fallbacks entries can't set request_timeout_secs; provider.request_timeout_secs covers the whole chain");
+            }
+            chain.push(limited(build_provider(fallback, &cfg, secrets.as_ref(), &http)?)?);
+        }
+        Arc::new(FallbackProvider::new(chain)?)
+    };
+    if let Some(name) = limits.keys().next() {
+        anyhow::bail!("orchestrator.provider_concurrency has `{name}`, which isn't the provider or a fallback");
+    }
+    let provider: Arc<dyn ImageProvider> = match &cfg.provider.cache_dir {
+        Some(dir) => {
+            let size = format!("{}x{}", cfg.provider.width.unwrap_or(0), cfg.provider.height.unwrap_or(0));
+            Arc::new(CachingProvider::new(provider, dir.clone(), size, cfg.provider.quality.clone(), cfg.provider.style.clone()).await?)
+        }
+        None => provider,
+    };
+    let provider: Arc<dyn ImageProvider> = if cfg.orchestrator.unique_prompts {
+        Arc::new(CoalescingProvider::new(provider))
+    } else {
+        provider
+    };
+    provider.validate().await.context("provider credential check failed")?;
 
-        // Prompt generator
-        let style = match tpl_yaml.mode {
-            Mode::AdTemplate(tpl) => PromptStyle::AdTemplate(PromptTemplate {
-                brand: tpl.brand,
-                product: tpl.product,
-                styles: tpl.styles,
-            }),
-            Mode::GeneralPrompt(prompt) => PromptStyle::GeneralPrompt(PromptGeneral {
-                prompt: prompt.prompt,
-            }),
-        };
-        let generator = VariantGenerator::new(style, cfg.seed);
-
-        // Rewriter
-        let rewriter_model = cfg.rewrite.model.clone().unwrap_or_else(||"gpt-4o-mini".into());
-        let rewriter_system = cfg.rewrite.system.clone().unwrap_or_else(||"Polish and improve the ad prompt while preserving its core intent.".into());
-        let rewriter: Option<Arc<dyn rewrite::PromptRewriter>> = if cfg.rewrite.enabled {
-            let key = std::env::var(cfg.provider.api_key_env.clone().unwrap_or_else(||"OPENAI_API_KEY".into())).unwrap_or_default();
-            Some(make_rewriter(key, rewriter_model.clone(), rewriter_system.clone(), cfg.rewrite.max_tokens.unwrap_or(64)))
-        } else { None };
-
-        // Rewrite cache (only when rewriting is enabled and cache_file is set)
-        let rewrite_cache: Option<Arc<RewriteCache>> = if cfg.rewrite.enabled {
-            if let Some(cache_path) = &cfg.rewrite.cache_file {
-                Some(Arc::new(RewriteCache::load(cache_path.clone()).await?))
-            } else {
-                None
+    // Image-to-image: read the init image once; every task shares it
+    let init_image = match &tpl_yaml.init_image {
+        Some(init) => {
+            if !provider.supports_init_image() {
+                anyhow::bail!("init_image is set but provider `{}` can't do image-to-image (use stability, replicate or mock)", provider.name());
+            }
+            if !(0.0..=1.0).contains(&init.strength) {
+                anyhow::bail!("init_image.strength must be between 0 and 1, got {}", init.strength);
+            }
+            let bytes = tokio::fs::read(&init.path).await.with_context(|| format!("reading init_image {}", init.path.display()))?;
+            image::load_from_memory(&bytes).with_context(|| format!("init_image {} is not a readable image", init.path.display()))?;
+            Some(providers::InitImage { bytes: Arc::new(bytes), strength: init.strength })
+        }
+        None => None,
+    };
+
+    // Prompt source
+    let source = prompts::variant_source(tpl_yaml, cfg.seed).await?;
+    // a finite source (cartesian or unique mode, a prompts file) can't fill more ids than it has prompts
+    if let Some(total) = source.total().filter(|&n| n < cfg.orchestrator.target_images) {
+        println!("Capping target_images at {total}: the template only yields {total} prompts");
+        cfg.orchestrator.target_images = total;
+    }
+    let resume_from = if overrides.resume {
+        let cp = match checkpoint::load(&out_dir).await? {
+            Some(mut cp) => {
+                if cp.seed != cfg.seed {
+                    anyhow::bail!("--resume: checkpoint was written with seed {} but the config has seed {}", cp.seed, cfg.seed);
+                }
+                // the manifest gets a line per image, the checkpoint is only saved every few
+                if let Some((_, ids)) = manifest::saved_ids(&out_dir, Some(&cp.run_id)).await? {
+                    let extra = cp.absorb(ids);
+                    if extra > 0 { println!("--resume: {extra} more ids saved after the last checkpoint (from {})", manifest::MANIFEST_FILE); }
+                }
+                cp
+            }
+            // no checkpoint (the run stopped before its first save): replay the prompt
+            // sequence from the start and skip whatever the last run's manifest saved
+            None => {
+                let (run_id, ids) = manifest::saved_ids(&out_dir, None).await?
+                    .with_context(|| format!("--resume: no {} or {} in {}", checkpoint::CHECKPOINT_FILE, manifest::MANIFEST_FILE, out_dir.display()))?;
+                let mut cp = checkpoint::Checkpoint{ run_id, seed: cfg.seed, next_id: 1, source_pos: None, completed: vec![], saved: Some(0), updated_at: chrono::Utc::now().to_rfc3339() };
+                cp.absorb(ids);
+                cp
             }
-        } else {
-            None
         };
+        println!("Resuming {} from id {} ({} later ids already done)", cp.run_id, cp.next_id, cp.completed.len());
+        Some(cp)
+    } else {
+        None
+    };
+    let checkpoint = Arc::new(checkpoint::Checkpointer::new(&out_dir, run_id.clone(), cfg.seed, resume_from.as_ref()));
 
-        let post = post::PostProcessor::new(cfg.post.thumbnail, cfg.post.thumb_max);
-        let dedupe = if cfg.dedupe.enabled { Some(Arc::new(tokio::sync::Mutex::new(dedupe::PerceptualDeduper::new(cfg.dedupe.phash_bits, cfg.dedupe.phash_thresh)))) } else { None };
-        let mp = MultiProgress::new();
-
-        orchestrator::run_orchestrator(
-            provider,
-            generator,
-            orchestrator::OrchestratorCfg{
-                run_id: run_id_for_orch,
-                out_dir,
-                target_images: cfg.orchestrator.target_images,
-                concurrency: cfg.orchestrator.concurrency,
-                queue_cap: cfg.orchestrator.queue_cap,
-                rate_per_min: cfg.orchestrator.rate_per_min,
-                price_usd_per_image: cfg.provider.price_usd_per_image.unwrap_or(0.0),
-                backoff_base_ms: cfg.orchestrator.backoff_base_ms,
-                backoff_factor: cfg.orchestrator.backoff_factor,
-                backoff_jitter_ms: cfg.orchestrator.backoff_jitter_ms,
-                progress: Some(mp.clone()),
-                events: events_for_orch,
-            },
-            orchestrator::OrchestratorExtras{
-                rewriter,
-                rewriter_model: if cfg.rewrite.enabled { Some(rewriter_model) } else { None },
-                rewriter_system: if cfg.rewrite.enabled { Some(rewriter_system) } else { None },
-                rewrite_cache,
-                post: Arc::new(post),
-                dedupe,
-            },
-        ).await?;
+    // Rewriter
+    let default_rewriter_model = match cfg.rewrite.kind { RewriteKind::Ollama => "llama3.2", _ => "gpt-4o-mini" };
+    let rewriter_model = cfg.rewrite.model.as_deref().map(|m| cfg.resolve_model(m)).transpose()?.unwrap_or_else(|| default_rewriter_model.into());
+    let rewriter_system = cfg.rewrite.system.clone().unwrap_or_else(||"Polish and improve the ad prompt while preserving its core intent.".into());
+    let rewriter: Option<Arc<dyn rewrite::PromptRewriter>> = if cfg.rewrite.enabled {
+        let key = if cfg.rewrite.kind == RewriteKind::OpenAI { secrets.get(cfg.provider.key_envs("OPENAI_API_KEY")[0]).unwrap_or_default() } else { String::new() };
+        Some(make_rewriter(&cfg.rewrite, http.clone(), key, rewriter_model.clone(), rewriter_system.clone(), cfg.rewrite.max_tokens.unwrap_or(64)))
+    } else { None };
 
-        println!("\n✅ Run complete.");
-        Ok(())
-    }.await;
+    // Rewrite cache (only when rewriting is enabled and cache_file is set)
+    let rewrite_cache: Option<Arc<RewriteCache>> = if cfg.rewrite.enabled {
+        if let Some(cache_path) = &cfg.rewrite.cache_file {
+            Some(Arc::new(RewriteCache::load(cache_path.clone()).await?))
+        } else {
+            None
+        }
+    } else {
+        None
+    };
 
-    if let Err(ref e) = result {
-        if let Some(tx) = &events_tx {
-            let _ = tx.send(events::RunEvent::Failed { run_id: run_id.clone(), error: format!("{e:#}") });
+    let post = post::PostProcessor::new(&cfg.post)?;
+    let dedupe = if cfg.dedupe.enabled { Some(Arc::new(dedupe::PerceptualDeduper::new(cfg.dedupe.alg, cfg.dedupe.phash_bits, cfg.dedupe.phash_thresh, cfg.dedupe.hash_concurrency)?)) } else { None };
+    if let (Some(d), Some(dir)) = (&dedupe, &overrides.dedupe_existing) {
+        let (loaded, unreadable) = d.prime_from_dir(dir).await.with_context(|| format!("--dedupe-existing {}", dir.display()))?;
+        println!("Loaded {loaded} reference hashes from {}{}", dir.display(),
+            if unreadable > 0 { format!(" ({unreadable} unreadable PNGs skipped)") } else { String::new() });
+    }
+    // images the interrupted run already saved, in case dedupe.persist didn't get to write them
+    if let (Some(d), true) = (&dedupe, overrides.resume) {
+        let (loaded, unreadable) = d.prime_from_dir(&out_dir).await.with_context(|| format!("--resume: hashing images in {}", out_dir.display()))?;
+        if loaded > 0 { println!("--resume: deduping against {loaded} PNGs already in {}", out_dir.display()); }
+        if unreadable > 0 { println!("--resume: {unreadable} unreadable PNGs skipped"); }
+    }
+    let hashes_file = out_dir.join(dedupe::HASHES_FILE);
+    let persisted = dedupe.clone().filter(|_| cfg.dedupe.persist);
+    if let Some(d) = &persisted {
+        match d.load(&hashes_file).await {
+            Ok(0) => {}
+            Ok(n) => println!("Loaded {n} hashes from earlier runs ({})", hashes_file.display()),
+            Err(e) => eprintln!("⚠️  ignoring {}: {e:#}", hashes_file.display()),
         }
     }
+    let aesthetic = qa::AestheticScorer::from_cfg(cfg.qa.aesthetic.as_ref(), secrets.as_ref())?.map(Arc::new);
+    let on_image = cfg.hooks.on_image.clone().map(hooks::OnImageHook::new).transpose()?.map(Arc::new);
+    let sink = sink::make_sink(&cfg.storage, &out_dir).await?;
+    let mp = MultiProgress::new();
+    let price_usd_per_image = cfg.provider.price_usd_per_image.unwrap_or_else(|| provider.price_usd_per_image());
 
-    result
+    let (gallery_dir, gallery_run) = (out_dir.clone(), run_id.clone());
+    let cancel = Arc::new(tokio::sync::Notify::new());
+    let run = orchestrator::run_orchestrator(
+        provider,
+        source,
+        orchestrator::OrchestratorCfg{
+            run_id,
+            out_dir,
+            target_images: cfg.orchestrator.target_images,
+            concurrency: cfg.orchestrator.concurrency,
+            queue_cap: cfg.orchestrator.queue_cap,
+            // with several keys, each one gets the configured rate
+            rate_per_min: cfg.orchestrator.rate_per_min * cfg.provider.api_key_envs.len().max(1) as u32,
+            price_usd_per_image,
+            retry: orchestrator::RetryPolicy{
+                max_attempts: cfg.orchestrator.max_attempts.max(1),
+                base_ms: cfg.orchestrator.backoff_base_ms,
+                factor: cfg.orchestrator.backoff_factor,
+                jitter_ms: cfg.orchestrator.backoff_jitter_ms,
+            },
+            best_of: cfg.orchestrator.best_of,
+            images_per_call: cfg.orchestrator.images_per_call,
+            max_cost_usd: cfg.orchestrator.max_cost_usd,
+            max_prompts_factor: cfg.orchestrator.max_prompts_factor,
+            ordered_output: cfg.orchestrator.ordered_output,
+            stop_on_ctrl_c: overrides.stop_on_ctrl_c,
+            cancel: Some(cancel.clone()),
+            init_image,
+            seed: cfg.seed,
+            slow_start_ms: cfg.orchestrator.slow_start_ms,
+            sidecar: cfg.sidecar,
+            checkpoint,
+            resume_from,
+            progress: Some(mp.clone()),
+            events: events_tx,
+        },
+        orchestrator::OrchestratorExtras{
+            rewriter,
+            rewriter_model: if cfg.rewrite.enabled { Some(rewriter_model) } else { None },
+            rewriter_system: if cfg.rewrite.enabled { Some(rewriter_system) } else { None },
+            rewriter_examples: cfg.rewrite.examples.clone(),
+            rewriter_sampling: rewrite::Sampling { temperature: cfg.rewrite.temperature, top_p: cfg.rewrite.top_p },
+            rewrite_cache,
+            rewrite_price_per_1k_tokens: cfg.rewrite.price_usd_per_1k_tokens.unwrap_or(0.0),
+            rewrite_max_cost_usd: cfg.rewrite.max_cost_usd,
+            rewrite_max_attempts: cfg.rewrite.max_attempts.max(1),
+            rewrite_batch_size: cfg.rewrite.batch_size.max(1),
+            post: Arc::new(post),
+            dedupe,
+            aesthetic,
+            on_image,
+            sink,
+        },
+    );
+    // On timeout the orchestrator aborts in-flight ids but still flushes the
+    // manifest and saves the checkpoint, so --resume picks up from there. It
+    // emits no Finished then; run_once reports the error below as Failed.
+    let outcome = match cfg.run_timeout_secs {
+        Some(secs) => {
+            let timer = tokio::spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_secs(secs)).await;
+                cancel.notify_one();
+            });
+            let outcome = run.await;
+            timer.abort();
+            outcome.and_then(|o| if o.cancelled { Err(anyhow::anyhow!("run timed out")) } else { Ok(o) })
+        }
+        None => run.await,
+    };
+    // saved whatever the outcome, so hashes of images already on disk aren't lost
+    if let Some(d) = &persisted {
+        if let Err(e) = d.save(&hashes_file).await {
+            eprintln!("⚠️  {} not saved: {e:#}", hashes_file.display());
+        }
+    }
+    let outcome = outcome?;
+
+    println!(
+        "\n✅ Run complete: {} produced, {} duplicates, {} filtered, {} failed, {} moderated, {} blocked, ${:.4} (rewrite ${:.4}) in {:.1}s",
+        outcome.produced, outcome.duplicates, outcome.filtered, outcome.failed, outcome.moderated, outcome.blocked, outcome.total_cost, outcome.rewrite_cost, outcome.elapsed.as_secs_f64(),
+    );
+    if cfg.gallery {
+        match gallery::write_gallery(&gallery_dir, &gallery_run).await {
+            Ok(n) => println!("🖼  {} ({n} images)", gallery_dir.join(gallery::GALLERY_FILE).display()),
+            Err(e) => eprintln!("⚠️  {} not written: {e:#}", gallery::GALLERY_FILE),
+        }
+    }
+    if outcome.interrupted {
+        println!("⚠️  Interrupted; pass --resume to finish the run");
+    } else if outcome.budget_capped {
+        println!("⚠️  Stopped early at orchestrator.max_cost_usd; raise it and pass --resume to finish the run");
+    }
+    Ok(())
 }
@@ -1,25 +1,335 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
 use tokio::{fs, io::AsyncWriteExt};
 
+pub const MANIFEST_FILE: &str = "manifest.jsonl";
+/// Dead-letter log: one line per failed item, with what's needed to re-run it.
+pub const FAILURES_FILE: &str = "failures.jsonl";
+
+/// How an item ended. Only `saved` items have an image on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemStatus { Saved, Duplicate, Filtered, Failed }
+
 #[derive(Serialize)]
 pub struct ManifestRecord<'a>{
     pub id: u64,
+    pub run_id: &'a str,
+    pub status: ItemStatus,
     pub created_at: String,
     pub provider: &'a str,
     pub model: &'a str,
     pub prompt: &'a str,
-    pub path_png: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_png: Option<String>,
+    /// Position within a multi-image provider call (`orchestrator.images_per_call`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch_index: Option<u32>,
+    /// Provider call duration, for images that came back from the provider.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// What the saved image cost, as in its sidecar.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+    /// Saved dimensions, after post-processing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Hex perceptual hash dedupe computed for the image as the provider returned
+    /// it; only present with dedupe on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phash: Option<String>,
+    /// Where `storage` uploaded the image, for remote stores.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub variant: &'a BTreeMap<String, String>,
+}
+
+/// A `failures.jsonl` record. `stage` says where the item failed: `provider`
+/// (after `attempts` calls), `aspect_ratio`, `adjust`, `watermark`, `frame`,
+/// `corner_radius`, `strip_metadata` or `save`.
+#[derive(Serialize)]
+pub struct FailureRecord<'a>{
+    pub id: u64,
+    pub run_id: &'a str,
+    pub created_at: String,
+    pub stage: &'static str,
+    pub provider: &'a str,
+    pub model: &'a str,
+    pub prompt: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<&'a str>,
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch_index: Option<u32>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub variant: &'a BTreeMap<String, String>,
+}
+
+/// Appends to `manifest.jsonl` and `failures.jsonl`. Every write goes through one
+/// lock and is a single `write_all` of the whole line, so concurrent workers never
+/// interleave partial lines.
+pub struct Manifest{ path: std::path::PathBuf, failures: std::path::PathBuf, ordered: Option<tokio::sync::Mutex<Reorder>>, write: tokio::sync::Mutex<()> }
+
+/// Lines held back until every lower id has finished (`orchestrator.ordered_output`).
+struct Reorder{
+    /// Lowest id not yet written.
+    next: u64,
+    finished: BTreeSet<u64>,
+    /// Buffered `(is_failure, line)` per id, in append order.
+    lines: BTreeMap<u64, Vec<(bool, String)>>,
+    saved: BTreeSet<u64>,
+    /// Ids with a saved image written so far.
+    saved_written: u64,
 }
 
-pub struct Manifest{ path: std::path::PathBuf }
 impl Manifest{
-    pub fn new(out_dir:&Path)->Self{ Self{ path: out_dir.join("manifest.jsonl") } }
+    pub fn new(out_dir:&Path)->Self{ Self{ path: out_dir.join(MANIFEST_FILE), failures: out_dir.join(FAILURES_FILE), ordered: None, write: tokio::sync::Mutex::new(()) } }
+
+    /// Write lines in ascending id order from `first_id`, whatever order ids finish in.
+    /// `skip` lists ids at or above it that won't run (finished in an earlier session).
+    pub fn ordered(out_dir:&Path, first_id: u64, skip: impl IntoIterator<Item = u64>)->Self{
+        let reorder = Reorder{ next: first_id, finished: skip.into_iter().collect(), lines: BTreeMap::new(), saved: BTreeSet::new(), saved_written: 0 };
+        Self{ ordered: Some(tokio::sync::Mutex::new(reorder)), ..Self::new(out_dir) }
+    }
+
     pub async fn append(&self, rec: ManifestRecord<'_>) -> anyhow::Result<()> {
-        let mut f = fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
-        let line = serde_json::to_string(&rec)?;
+        match &self.ordered {
+            Some(reorder) => {
+                let line = serde_json::to_string(&rec)?;
+                let mut r = reorder.lock().await;
+                if rec.status == ItemStatus::Saved { r.saved.insert(rec.id); }
+                r.lines.entry(rec.id).or_default().push((false, line));
+                Ok(())
+            }
+            None => self.append_raw(&self.path, serde_json::to_string(&rec)?).await,
+        }
+    }
+
+    pub async fn append_failure(&self, rec: FailureRecord<'_>) -> anyhow::Result<()> {
+        match &self.ordered {
+            Some(reorder) => {
+                let line = serde_json::to_string(&rec)?;
+                reorder.lock().await.lines.entry(rec.id).or_default().push((true, line));
+                Ok(())
+            }
+            None => self.append_raw(&self.failures, serde_json::to_string(&rec)?).await,
+        }
+    }
+
+    /// `id` has appended everything it will. With ordered output, writes every
+    /// id that is now next in line and returns how many ids have saved an image
+    /// so far in id order; `None` otherwise.
+    pub async fn finish(&self, id: u64) -> anyhow::Result<Option<u64>> {
+        let Some(reorder) = &self.ordered else { return Ok(None) };
+        let mut r = reorder.lock().await;
+        r.finished.insert(id);
+        while r.finished.contains(&r.next) {
+            let next = r.next;
+            self.write_id(&mut r, next).await?;
+            r.finished.remove(&next);
+            r.next += 1;
+        }
+        Ok(Some(r.saved_written))
+    }
+
+    /// Write whatever is still buffered (ids after a gap left by aborted or
+    /// never-dispatched ids), in id order. Call once the run is over.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let Some(reorder) = &self.ordered else { return Ok(()) };
+        let mut r = reorder.lock().await;
+        let ids: Vec<u64> = r.lines.keys().copied().collect();
+        for id in ids { self.write_id(&mut r, id).await?; }
+        Ok(())
+    }
+
+    async fn write_id(&self, r: &mut Reorder, id: u64) -> anyhow::Result<()> {
+        if r.saved.remove(&id) { r.saved_written += 1; }
+        for (is_failure, line) in r.lines.remove(&id).unwrap_or_default() {
+            self.append_raw(if is_failure { &self.failures } else { &self.path }, line).await?;
+        }
+        Ok(())
+    }
+
+    async fn append_raw(&self, path: &Path, mut line: String) -> anyhow::Result<()> {
+        line.push('\n');
+        let _guard = self.write.lock().await;
+        let mut f = fs::OpenOptions::new().create(true).append(true).open(path).await?;
         f.write_all(line.as_bytes()).await?;
-        f.write_all(b"\n").await?;
         Ok(())
     }
 }
+
+/// Ids that saved at least one image in `run_id`'s records (the last run in the
+/// manifest if `None`), with the run id they were read for. `None` if the
+/// manifest is missing or has no run ids.
+pub async fn saved_ids(out_dir: &Path, run_id: Option<&str>) -> anyhow::Result<Option<(String, BTreeSet<u64>)>> {
+    #[derive(Deserialize)]
+    struct Line{ id: u64, run_id: Option<String>, status: Option<ItemStatus> }
+
+    let txt = match fs::read_to_string(out_dir.join(MANIFEST_FILE)).await {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let recs: Vec<Line> = txt.lines().filter_map(|l| serde_json::from_str(l).ok()).collect();
+    let Some(run_id) = run_id.map(str::to_string).or_else(|| recs.iter().rev().find_map(|r| r.run_id.clone())) else { return Ok(None) };
+    let ids = recs.into_iter()
+        .filter(|r| r.run_id.as_deref() == Some(run_id.as_str()) && r.status.unwrap_or(ItemStatus::Saved) == ItemStatus::Saved)
+        .map(|r| r.id)
+        .collect();
+    Ok(Some((run_id, ids)))
+}
+
+/// A saved item as read back from the manifest.
+#[derive(Debug)]
+pub struct SavedImage{
+    pub id: u64,
+    pub batch_index: Option<u32>,
+    pub path_png: String,
+    pub prompt: String,
+    /// Absent on records from before the manifest carried it.
+    pub cost_usd: Option<f64>,
+    pub url: Option<String>,
+}
+
+impl SavedImage{
+    /// `#12`, or `#12_3` for the third image of a batched call, matching the file name.
+    pub fn label(&self) -> String {
+        match self.batch_index { Some(k) => format!("#{}_{k}", self.id), None => format!("#{}", self.id) }
+    }
+}
+
+/// Every image saved in `run_id`, in manifest order.
+pub async fn saved_images(out_dir: &Path, run_id: &str) -> anyhow::Result<Vec<SavedImage>> {
+    #[derive(Deserialize)]
+    struct Line{ id: u64, run_id: Option<String>, batch_index: Option<u32>, path_png: Option<String>, prompt: String, cost_usd: Option<f64>, url: Option<String> }
+
+    let txt = match fs::read_to_string(out_dir.join(MANIFEST_FILE)).await {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(txt.lines()
+        .filter_map(|l| serde_json::from_str::<Line>(l).ok())
+        .filter(|r| r.run_id.as_deref() == Some(run_id))
+        .filter_map(|r| Some(SavedImage{ id: r.id, batch_index: r.batch_index, path_png: r.path_png?, prompt: r.prompt, cost_usd: r.cost_usd, url: r.url }))
+        .collect())
+}
+
+/// File names of the images saved in `out_dir` by any run, in manifest order;
+/// `None` when the directory has no manifest.
+pub async fn saved_pngs(out_dir: &Path) -> anyhow::Result<Option<Vec<String>>> {
+    #[derive(Deserialize)]
+    struct Line{ status: ItemStatus, path_png: Option<String> }
+
+    let txt = match fs::read_to_string(out_dir.join(MANIFEST_FILE)).await {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names: Vec<String> = txt.lines()
+        .filter_map(|l| serde_json::from_str::<Line>(l).ok())
+        .filter(|r| r.status == ItemStatus::Saved)
+        .filter_map(|r| r.path_png)
+        .filter(|p| !p.contains(['/', '\\']))
+        .collect();
+    // a resumed id is recorded again
+    let mut seen = BTreeSet::new();
+    names.retain(|n| seen.insert(n.clone()));
+    Ok(Some(names))
+}
+
+/// Item counts by status for one value of a variant field.
+#[derive(Debug, Default, Serialize)]
+pub struct StatusCounts{ pub saved: u64, pub duplicate: u64, pub filtered: u64, pub failed: u64 }
+
+/// Per-field, per-value outcome counts for one run, e.g. `style -> "neon" -> {saved: 3, ...}`.
+#[derive(Debug, Default, Serialize)]
+pub struct Coverage{
+    pub run_id: String,
+    /// Manifest records found for the run (0 for runs that predate run ids in the manifest).
+    pub items: u64,
+    pub fields: BTreeMap<String, BTreeMap<String, StatusCounts>>,
+}
+
+/// Tally `run_id`'s manifest records by their variant metadata.
+pub async fn coverage(out_dir: &Path, run_id: &str) -> anyhow::Result<Coverage> {
+    #[derive(Deserialize)]
+    struct Line{ run_id: Option<String>, status: Option<ItemStatus>, #[serde(default)] variant: BTreeMap<String, String> }
+
+    let mut cov = Coverage{ run_id: run_id.to_string(), ..Default::default() };
+    let txt = match fs::read_to_string(out_dir.join(MANIFEST_FILE)).await {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(cov),
+        Err(e) => return Err(e.into()),
+    };
+    for line in txt.lines() {
+        let Ok(rec) = serde_json::from_str::<Line>(line) else { continue };
+        if rec.run_id.as_deref() != Some(run_id) { continue; }
+        cov.items += 1;
+        for (field, value) in rec.variant {
+            let counts = cov.fields.entry(field).or_default().entry(value).or_default();
+            match rec.status.unwrap_or(ItemStatus::Saved) {
+                ItemStatus::Saved => counts.saved += 1,
+                ItemStatus::Duplicate => counts.duplicate += 1,
+                ItemStatus::Filtered => counts.filtered += 1,
+                ItemStatus::Failed => counts.failed += 1,
+            }
+        }
+    }
+    Ok(cov)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn record<'a>(id: u64, prompt: &'a str, variant: &'a BTreeMap<String, String>) -> ManifestRecord<'a> {
+        ManifestRecord{
+            id, run_id: "run-test", status: ItemStatus::Saved, created_at: chrono::Utc::now().to_rfc3339(),
+            provider: "mock", model: "mock-v1", prompt, path_png: Some(format!("{id:08}-mock-mock-v1.png")),
+            batch_index: None, latency_ms: Some(id), cost_usd: None, width: Some(64), height: Some(64),
+            phash: None, url: None, variant,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_appends_write_whole_lines() {
+        let out_dir = std::env::temp_dir().join(format!("adgen-manifest-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&out_dir).await.unwrap();
+        let manifest = Arc::new(Manifest::new(&out_dir));
+
+        // long lines so an unguarded append would have a chance to split them
+        let n = 200u64;
+        let mut tasks = tokio::task::JoinSet::new();
+        for id in 1..=n {
+            let manifest = manifest.clone();
+            tasks.spawn(async move {
+                let prompt = format!("item {id} ").repeat(500);
+                let variant = BTreeMap::from([("style".to_string(), format!("s{}", id % 7))]);
+                manifest.append(record(id, &prompt, &variant)).await.unwrap();
+            });
+        }
+        while let Some(r) = tasks.join_next().await { r.unwrap(); }
+
+        let txt = fs::read_to_string(out_dir.join(MANIFEST_FILE)).await.unwrap();
+        let mut ids = BTreeSet::new();
+        for line in txt.lines() {
+            let v: serde_json::Value = serde_json::from_str(line).unwrap_or_else(|e| panic!("invalid manifest line ({e}): {line:.80}"));
+            let id = v["id"].as_u64().unwrap();
+            assert_eq!(v["prompt"].as_str().unwrap(), format!("item {id} ").repeat(500));
+            ids.insert(id);
+        }
+        assert_eq!(txt.lines().count() as u64, n);
+        assert_eq!(ids, (1..=n).collect());
+
+        fs::remove_dir_all(&out_dir).await.unwrap();
+    }
+}
@@ -12,17 +12,25 @@ pub use io::save_image_with_sidecar;
 pub mod manifest;
 pub use manifest::Manifest;
 pub mod orchestrator;
-pub use orchestrator::{run_orchestrator, OrchestratorExtras};
+pub use orchestrator::{run_orchestrator, OrchestratorExtras, RunOutcome};
 pub mod post;
 pub use post::PostProcessor;
 pub mod postgres;
 pub mod providers;
 pub use providers::ImageProvider;
 pub mod prompts;
-pub use prompts::VariantGenerator;
+pub use prompts::{Variant, VariantGenerator, VariantSource};
 pub mod rate_limit;
 pub mod rewrite;
 pub use rate_limit::SimpleRateLimiter;
 pub use rewrite::PromptRewriter;
 pub mod events;
 pub use events::RunEvent;
+pub mod watch;
+pub mod explain;
+pub mod dataset;
+pub mod qa;
+pub mod checkpoint;
+pub mod reprocess;
+pub mod secrets;
+pub mod hooks;
@@ -1,12 +1,19 @@
 use anyhow::Result;
+use base64::Engine as _;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::{sync::{mpsc, Semaphore}, task::JoinSet};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use tokio::sync::broadcast;
+use serde::{Deserialize, Serialize};
 use crate::events::RunEvent;
-use crate::{providers::ImageProvider, prompts::VariantGenerator, io::save_image_with_sidecar, manifest::{Manifest, ManifestRecord}, rate_limit::SimpleRateLimiter};
+use crate::dedupe::Origin;
+use crate::{providers::{GenOpts, ImageProvider, ImageResult, ProviderError}, prompts::{Variant, VariantSource}, io::{image_stem, save_image_with_sidecar, SaveExtras}, manifest::{FailureRecord, ItemStatus, Manifest, ManifestRecord}, rate_limit::SimpleRateLimiter};
 use crate::backoff::backoff_ms;
+use crate::rewrite::rewrite_cost;
+use crate::checkpoint::{Checkpoint, Checkpointer};
 
 pub struct OrchestratorCfg{
     pub run_id: String,
@@ -16,36 +23,159 @@ pub struct OrchestratorCfg{
     pub queue_cap: usize,
     pub rate_per_min: u32,
     pub price_usd_per_image: f64,
-    pub backoff_base_ms: u64,
-    pub backoff_factor: f64,
-    pub backoff_jitter_ms: u64,
+    pub retry: RetryPolicy,
+    /// Candidates generated per kept image; the sharpest one is saved.
+    pub best_of: usize,
+    /// Images requested per provider call; all of them are kept.
+    pub images_per_call: u32,
+    /// Spend cap: no new image starts once it could push the total past this.
+    pub max_cost_usd: Option<f64>,
+    /// Write manifest and failures lines in id order (and report progress in that order).
+    pub ordered_output: bool,
+    /// Draw at most this many times `target_images` prompts, replacing dropped and failed ids.
+    pub max_prompts_factor: f64,
+    /// Ctrl-C stops dispatching and waits for in-flight ids (a second one aborts them).
+    pub stop_on_ctrl_c: bool,
+    /// Notified to cancel the run (`run_timeout_secs`): no new ids start, in-flight
+    /// ones are aborted, and the manifest and checkpoint are still written. A
+    /// cancelled run emits no `RunEvent::Finished`; the caller reports how it ended.
+    pub cancel: Option<Arc<tokio::sync::Notify>>,
+    /// Image-to-image input sent with every request.
+    pub init_image: Option<crate::providers::InitImage>,
+    /// Run seed; per-image provider seeds are derived from it.
+    pub seed: u64,
+    pub slow_start_ms: Option<u64>,
+    pub sidecar: crate::config::SidecarCfg,
+    pub checkpoint: Arc<Checkpointer>,
+    /// Continue from this checkpoint instead of starting at id 1.
+    pub resume_from: Option<Checkpoint>,
     pub progress: Option<MultiProgress>,
     pub events: Option<broadcast::Sender<RunEvent>>,
 }
 
+/// Backoff between provider attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy{
+    /// Provider calls per image (or candidate) before giving up; at least 1.
+    pub max_attempts: u32,
+    pub base_ms: u64,
+    pub factor: f64,
+    pub jitter_ms: u64,
+}
+
+#[derive(Clone)]
 pub struct OrchestratorExtras{
     pub rewriter: Option<Arc<dyn crate::rewrite::PromptRewriter>>,
     pub rewriter_model: Option<String>,
     pub rewriter_system: Option<String>,
+    pub rewriter_examples: Vec<(String, String)>,
+    /// `rewrite.temperature` / `rewrite.top_p`; like the model, system prompt and examples, part of the cache key.
+    pub rewriter_sampling: crate::rewrite::Sampling,
     pub rewrite_cache: Option<Arc<crate::rewrite::RewriteCache>>,
+    pub rewrite_price_per_1k_tokens: f64,
+    pub rewrite_max_cost_usd: Option<f64>,
+    /// Rewrite calls per prompt (with `retry`'s backoff) before using the original; at least 1.
+    pub rewrite_max_attempts: u32,
+    /// Prompts the producer draws ahead and rewrites in one `rewrite_batch` call; 1 disables it.
+    pub rewrite_batch_size: usize,
     pub post: Arc<crate::post::PostProcessor>,
-    pub dedupe: Option<Arc<tokio::sync::Mutex<crate::dedupe::PerceptualDeduper>>>,
+    pub dedupe: Option<Arc<crate::dedupe::PerceptualDeduper>>,
+    pub aesthetic: Option<Arc<crate::qa::AestheticScorer>>,
+    pub on_image: Option<Arc<crate::hooks::OnImageHook>>,
+    /// Where images and sidecars are written (`storage`).
+    pub sink: Arc<dyn crate::sink::ObjectSink>,
+}
+
+/// What a run produced, accumulated across worker tasks. Also carried by
+/// `RunEvent::Finished`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunOutcome{
+    pub produced: u64,
+    pub duplicates: u64,
+    /// Dropped for scoring below `qa.aesthetic.min_score`.
+    pub filtered: u64,
+    /// Failed for any reason other than `moderated` or `blocked`.
+    pub failed: u64,
+    /// The provider generated images but its moderation withheld them all.
+    pub moderated: u64,
+    /// The provider's safety filter rejected the prompt.
+    pub blocked: u64,
+    /// Image spend plus `rewrite_cost`.
+    pub total_cost: f64,
+    pub rewrite_cost: f64,
+    /// Dispatching stopped early at `max_cost_usd`.
+    pub budget_capped: bool,
+    /// Dispatching stopped early on Ctrl-C.
+    pub interrupted: bool,
+    /// Stopped through `OrchestratorCfg::cancel`.
+    pub cancelled: bool,
+    #[serde(rename = "elapsed_secs", with = "secs_f64")]
+    pub elapsed: Duration,
+}
+
+mod secs_f64 {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> { s.serialize_f64(d.as_secs_f64()) }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_secs_f64(f64::deserialize(d)?.max(0.0)))
+    }
 }
 
 pub async fn run_orchestrator(
     provider: Arc<dyn ImageProvider>,
-    mut generator: VariantGenerator,
+    mut source: Box<dyn VariantSource>,
     cfg: OrchestratorCfg,
     extras: OrchestratorExtras,
-) -> Result<()> {
-    let done = Arc::new(AtomicU64::new(0));
-    let sem = Arc::new(Semaphore::new(cfg.concurrency));
-    let (tx, mut rx) = mpsc::channel::<(u64, String)>(cfg.queue_cap);
+) -> Result<RunOutcome> {
+    let started_at = Instant::now();
+    // ids saved in an earlier session count towards progress, not `produced`; ids
+    // that finished there without saving are skipped but don't count
+    let (start_id, skip, already_done) = match &cfg.resume_from {
+        Some(cp) => {
+            if let Some(pos) = cp.source_pos { source.seek(pos)?; }
+            (cp.next_id, cp.completed.iter().copied().collect::<std::collections::HashSet<u64>>(), cp.saved_count())
+        }
+        None => (1, Default::default(), 0),
+    };
+    let done = Arc::new(AtomicU64::new(already_done));
+    let duplicates = Arc::new(AtomicU64::new(0));
+    let failed = Arc::new(AtomicU64::new(0));
+    let moderated = Arc::new(AtomicU64::new(0));
+    let blocked = Arc::new(AtomicU64::new(0));
+    let filtered = Arc::new(AtomicU64::new(0));
+    // image spend so far: what providers reported per image, else the configured price
+    let spent = Arc::new(parking_lot::Mutex::new(0.0f64));
+    let rewrite_capped = Arc::new(AtomicBool::new(false));
+    // Slow start: open one permit, then add one per interval up to `concurrency`
+    let initial_permits = if cfg.slow_start_ms.is_some() { cfg.concurrency.min(1) } else { cfg.concurrency };
+    let sem = Arc::new(Semaphore::new(initial_permits));
+    let ramp = cfg.slow_start_ms.filter(|_| cfg.concurrency > initial_permits).map(|ms| {
+        let sem = sem.clone();
+        let steps = cfg.concurrency - initial_permits;
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_millis(ms.max(1)));
+            tick.tick().await;
+            for _ in 0..steps {
+                tick.tick().await;
+                sem.add_permits(1);
+            }
+        })
+    });
+    let (tx, mut rx) = mpsc::channel::<(u64, Variant, Option<String>)>(cfg.queue_cap);
+    let queued = Arc::new(AtomicUsize::new(0));
+    let in_flight = Arc::new(AtomicUsize::new(0));
     let limiter = Arc::new(SimpleRateLimiter::per_minute(cfg.rate_per_min));
-    let manifest = Arc::new(Manifest::new(&cfg.out_dir));
+    let manifest = Arc::new(if cfg.ordered_output {
+        Manifest::ordered(&cfg.out_dir, start_id, skip.iter().copied())
+    } else {
+        Manifest::new(&cfg.out_dir)
+    });
     let pb = cfg.progress.as_ref().map(|mp|{
         let pb = mp.add(ProgressBar::new(cfg.target_images));
         pb.set_style(ProgressStyle::with_template("{bar:40.cyan/blue} {pos}/{len} {msg}").unwrap());
+        pb.set_position(already_done);
         pb
     });
     emit(&cfg.events, RunEvent::Started {
@@ -53,23 +183,115 @@ pub async fn run_orchestrator(
         total: cfg.target_images,
     });
 
-    // Producer
+    // Producer: wait for queue space before building each prompt, so nothing is
+    // generated ahead of what the workers can take
+    // `target_images` counts saved ids: the producer keeps at most that many ids open
+    // (queued or in flight) beyond those saved, so each dropped or failed id frees a
+    // slot for a replacement, up to `max_prompts_factor` times the target in all
+    let last_id = (cfg.target_images as f64 * cfg.max_prompts_factor.max(1.0)).ceil() as u64;
+    let open = Arc::new(AtomicU64::new(0));
+    let slot_freed = Arc::new(tokio::sync::Notify::new());
     let producer = {
         let tx = tx.clone();
+        let queued = queued.clone();
+        let checkpoint = cfg.checkpoint.clone();
+        let (done, open, slot_freed) = (done.clone(), open.clone(), slot_freed.clone());
+        let target = cfg.target_images;
+        let mut ahead = Lookahead {
+            source, buf: VecDeque::new(), batch_size: extras.rewrite_batch_size.max(1), extras: extras.clone(),
+            run_id: cfg.run_id.clone(), events: cfg.events.clone(),
+        };
         tokio::spawn(async move {
-            for id in 1..=cfg.target_images {
-                let prompt = generator.next();
-                if tx.send((id, prompt)).await.is_err() { break; }
+            for id in start_id..=last_id {
+                if skip.contains(&id) {
+                    // finished before the resume: draw its prompt only to keep the sequence aligned
+                    let Some(drawn) = ahead.next(id, &skip).await else { break; };
+                    checkpoint.skipped(id, drawn.pos_after);
+                    continue;
+                }
+                // workers notify after every id, so a freed slot is never missed
+                while done.load(Ordering::Relaxed) + open.load(Ordering::Relaxed) >= target {
+                    if open.load(Ordering::Relaxed) == 0 { return; }
+                    slot_freed.notified().await;
+                }
+                let Ok(slot) = tx.reserve().await else { break; };
+                let Some(drawn) = ahead.next(id, &skip).await else { break; };
+                // record before sending so the worker can't finish it first
+                checkpoint.dispatched(id, drawn.pos, drawn.pos_after);
+                open.fetch_add(1, Ordering::Relaxed);
+                slot.send((id, drawn.variant, drawn.rewritten));
+                queued.store(tx.max_capacity() - tx.capacity(), Ordering::Relaxed);
             }
         })
     };
 
-    // Dispatcher: receive jobs and spawn per-item tasks
+    // Dispatcher: take a worker permit, then receive a job and spawn its task.
+    // Holding off on `recv` until a worker is free is what lets the queue fill up.
     let mut set = JoinSet::new();
     drop(tx);
-    while let Some((id, original)) = rx.recv().await {
+    // worst case for one more id: every candidate or batch image billed at the configured price
+    let task_cost = cfg.price_usd_per_image * cfg.best_of.max(1) as f64 * cfg.images_per_call.max(1) as f64;
+    let mut budget_capped = false;
+    let mut interrupted = false;
+    let stop_on_ctrl_c = cfg.stop_on_ctrl_c;
+    let ctrl_c = move || async move {
+        if !stop_on_ctrl_c { return std::future::pending().await; }
+        if tokio::signal::ctrl_c().await.is_err() { std::future::pending::<()>().await; }
+    };
+    let mut cancelled = false;
+    let cancel = cfg.cancel.clone();
+    let cancel_requested = move || {
+        let cancel = cancel.clone();
+        async move {
+            match cancel { Some(n) => n.notified().await, None => std::future::pending().await }
+        }
+    };
+    loop {
+        let next = async {
+            let permit = sem.clone().acquire_owned().await.ok()?;
+            Some((permit, rx.recv().await?))
+        };
+        let (permit, (id, variant, batch_rewritten)) = tokio::select! {
+            next = next => match next { Some(job) => job, None => break },
+            _ = ctrl_c() => {
+                emit(&cfg.events, RunEvent::Log {
+                    run_id: cfg.run_id.clone(),
+                    msg: format!("interrupted: finishing {} in-flight ids (Ctrl-C again to abort them)", in_flight.load(Ordering::Relaxed)),
+                });
+                if let Some(pb) = &pb { pb.println("Interrupted: finishing in-flight images (Ctrl-C again to abort them)"); }
+                interrupted = true;
+                break;
+            }
+            _ = cancel_requested() => {
+                emit(&cfg.events, RunEvent::Log {
+                    run_id: cfg.run_id.clone(),
+                    msg: format!("cancelled: aborting {} in-flight ids", in_flight.load(Ordering::Relaxed)),
+                });
+                cancelled = true;
+                break;
+            }
+        };
+        // budget: count what in-flight ids may still bill, so the cap holds once they finish
+        if let Some(cap) = cfg.max_cost_usd {
+            let running = in_flight.load(Ordering::Relaxed);
+            let so_far = *spent.lock() + extras.rewriter.as_ref().map_or(0.0, |rw| rewrite_cost(rw.as_ref(), extras.rewrite_price_per_1k_tokens));
+            if so_far + (running + 1) as f64 * task_cost > cap {
+                emit(&cfg.events, RunEvent::Log {
+                    run_id: cfg.run_id.clone(),
+                    msg: format!("budget ${cap:.4} reached (${so_far:.4} spent, {running} in flight); not starting #{id} or later ids"),
+                });
+                budget_capped = true;
+                break;
+            }
+        }
+        queued.store(rx.len(), Ordering::Relaxed);
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        let in_flight_guard = InFlight(in_flight.clone());
+        let queued = queued.clone();
+        let original = variant.prompt;
+        let variant_meta = variant.meta;
+        let gen_opts = GenOpts { negative_prompt: variant.negative_prompt, seed: None, init_image: cfg.init_image.clone() };
         let provider = provider.clone();
-        let sem = sem.clone();
         let out_dir = cfg.out_dir.clone();
         let run_id = cfg.run_id.clone();
         let manifest = manifest.clone();
@@ -78,154 +300,712 @@ pub async fn run_orchestrator(
         let events = cfg.events.clone();
         let total = cfg.target_images;
         let done = done.clone();
-        let extras = OrchestratorExtras{
-            rewriter: extras.rewriter.clone(),
-            rewriter_model: extras.rewriter_model.clone(),
-            rewriter_system: extras.rewriter_system.clone(),
-            rewrite_cache: extras.rewrite_cache.clone(),
-            post: extras.post.clone(),
-            dedupe: extras.dedupe.clone(),
-        };
+        let duplicates = duplicates.clone();
+        let failed = failed.clone();
+        let (moderated, blocked) = (moderated.clone(), blocked.clone());
+        let filtered = filtered.clone();
+        let spent = spent.clone();
+        let extras = extras.clone();
+        let rewrite_capped = rewrite_capped.clone();
+        let checkpoint = cfg.checkpoint.clone();
+        let (open, slot_freed) = (open.clone(), slot_freed.clone());
+        let in_flight_count = in_flight.clone();
         let price = cfg.price_usd_per_image;
-        let backoff_base_ms = cfg.backoff_base_ms;
-        let backoff_factor = cfg.backoff_factor;
-        let backoff_jitter_ms = cfg.backoff_jitter_ms;
+        let retry = cfg.retry;
+        let best_of = cfg.best_of.max(1);
+        let ordered_output = cfg.ordered_output;
+        let images_per_call = cfg.images_per_call.max(1);
+        let run_seed = cfg.seed;
+        let sidecar = cfg.sidecar;
         set.spawn(async move {
-            emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} generated prompt") });
-
-            let _permit = sem.acquire().await.unwrap();
-            limiter.wait().await;
-            let mut prompt_used = original.clone();
-            let mut rewritten: Option<String> = None;
-            if let Some(rw) = &extras.rewriter {
-                // Generate cache key
-                let cache_key = crate::rewrite::cache_key(
-                    &original,
-                    rw.name(),
-                    extras.rewriter_model.as_deref().unwrap_or(""),
-                    extras.rewriter_system.as_deref().unwrap_or(""),
-                );
-
-                // Check cache first
-                let cached = if let Some(cache) = &extras.rewrite_cache {
-                    cache.get(&cache_key).await
+            let work = async {
+                emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} generated prompt") });
+
+                let _permit = permit;
+                let in_flight = in_flight_guard;
+                limiter.wait().await;
+                let mut prompt_used = original.clone();
+                let mut rewritten: Option<String> = None;
+                // stop rewriting once the rewrite budget is spent
+                let rewriter = extras.rewriter.as_ref().filter(|rw| {
+                    let Some(cap) = extras.rewrite_max_cost_usd else { return true; };
+                    if rewrite_cost(rw.as_ref(), extras.rewrite_price_per_1k_tokens) < cap { return true; }
+                    if !rewrite_capped.swap(true, Ordering::Relaxed) {
+                        emit(&events, RunEvent::Log {
+                            run_id: run_id.clone(),
+                            msg: format!("rewrite budget ${cap:.4} reached; using original prompts from here on"),
+                        });
+                    }
+                    false
+                });
+                if let Some(r) = batch_rewritten {
+                    // already paid for, so used even if the rewrite budget ran out since
+                    emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} rewrite: batched") });
+                    if r != original {
+                        rewritten = Some(r.clone());
+                        prompt_used = r;
+                        emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} rewrite: changed") });
+                    }
+                } else if let Some(rw) = rewriter {
+                    let cache_key = extras.rewrite_cache_key(rw.as_ref(), &original);
+
+                    // Check cache first
+                    let cached = if let Some(cache) = &extras.rewrite_cache {
+                        cache.get(&cache_key).await
+                    } else {
+                        None
+                    };
+
+                    let maybe = if let Some(cached_val) = cached {
+                        emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} rewrite: cache hit") });
+                        cached_val
+                    } else {
+                        emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} rewrite: calling API") });
+                        match rewrite_with_retry(rw.as_ref(), &original, id, &run_id, &events, extras.rewrite_max_attempts, retry).await {
+                            Ok(result) => {
+                                // Store in cache
+                                if let Some(cache) = &extras.rewrite_cache {
+                                    if let Err(e) = cache.put(&cache_key, &result).await {
+                                        emit(&events, RunEvent::Log {
+                                            run_id: run_id.clone(),
+                                            msg: format!("#{id} rewrite: cache write error: {e:#}")
+                                        });
+                                    }
+                                }
+                                result
+                            }
+                            // not cached, so a later run tries again
+                            Err(e) => {
+                                emit(&events, RunEvent::Log {
+                                    run_id: run_id.clone(),
+                                    msg: format!("#{id} rewrite failed after {} attempts, using the original prompt: {e:#}", extras.rewrite_max_attempts)
+                                });
+                                original.clone()
+                            }
+                        }
+                    };
+
+                    if maybe != original {
+                        rewritten = Some(maybe.clone());
+                        prompt_used = maybe;
+                        emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} rewrite: changed") });
+                    }
+                }
+
+                emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} provider: call") });
+                // dropped images get a manifest record too, so coverage can show where a run fell short
+                let dropped = |status, batch_index, latency_ms| ManifestRecord{
+                    id, run_id: &run_id, status, created_at: chrono::Utc::now().to_rfc3339(), provider: provider.name(),
+                    model: provider.model(), prompt: &prompt_used, path_png: None, batch_index, latency_ms,
+                    cost_usd: None, width: None, height: None, phash: None, url: None, variant: &variant_meta,
+                };
+                let failure = |stage, error: &anyhow::Error, attempts, batch_index| FailureRecord{
+                    id, run_id: &run_id, created_at: chrono::Utc::now().to_rfc3339(), stage, provider: provider.name(),
+                    model: provider.model(), prompt: &prompt_used, negative_prompt: gen_opts.negative_prompt.as_deref(),
+                    error: format!("{error:#}"), attempts, batch_index, variant: &variant_meta,
+                };
+                // one candidate normally; with best_of, keep the sharpest (each one is billed).
+                // with images_per_call, every image of the call is kept under its own batch index
+                // each candidate gets its own seed (when the provider takes one) so reruns reproduce it
+                let mut candidates = Vec::with_capacity(best_of);
+                let mut seeds = Vec::with_capacity(best_of);
+                let mut latencies = Vec::with_capacity(best_of);
+                let mut last_error = None;
+                for k in 0..best_of {
+                    let seed = provider.supports_seed().then(|| image_seed(run_seed, id, k as u64));
+                    let opts = GenOpts { seed, ..gen_opts.clone() };
+                    match generate_with_retry(provider.as_ref(), &prompt_used, &opts, images_per_call, id, &run_id, &events, retry).await {
+                        Ok((r, latency_ms)) => {
+                            // batch images are seeded consecutively (see `ImageProvider::generate_batch`)
+                            seeds.extend((0..r.len() as u64).map(|i| seed.map(|s| s.wrapping_add(i))));
+                            latencies.extend(std::iter::repeat_n(latency_ms, r.len()));
+                            candidates.extend(r);
+                        }
+                        Err(failure) => last_error = Some(failure),
+                    }
+                }
+                // every returned image is billed, kept or not
+                let cost_of = |r: &ImageResult| r.cost_usd.unwrap_or_else(|| r.served_by.as_ref().map_or(price, |s| s.price_usd_per_image));
+                *spent.lock() += candidates.iter().map(cost_of).sum::<f64>();
+                if candidates.is_empty() {
+                    let (e, attempts) = last_error.unwrap();
+                    // a safety block is about the prompt, not the provider: say so instead of "failed after N attempts"
+                    let (msg, counter) = match e.downcast_ref::<ProviderError>() {
+                        Some(pe @ ProviderError::SafetyBlocked { .. }) => (format!("#{id} {pe}"), &blocked),
+                        Some(pe @ ProviderError::Moderated { .. }) => (format!("#{id} {pe}"), &moderated),
+                        _ => (format!("#{id} provider failed after {attempts} attempts: {e:#}"), &failed),
+                    };
+                    emit(&events, RunEvent::Log { run_id: run_id.clone(), msg });
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    append_manifest(&manifest, &events, dropped(ItemStatus::Failed, None, None)).await;
+                    append_failure(&manifest, &events, failure("provider", &e, Some(attempts), None)).await;
+                    return false;
+                }
+                let kept: Vec<Kept> = if images_per_call > 1 {
+                    candidates.into_iter().zip(seeds).zip(latencies).zip(1..)
+                        .map(|(((res, seed), latency_ms), k)| Kept { batch_index: Some(k), cost: cost_of(&res), res, seed, latency_ms, candidate_scores: vec![], aesthetic_score: None })
+                        .collect()
+                } else if candidates.len() == 1 {
+                    let res = candidates.pop().unwrap();
+                    vec![Kept { batch_index: None, cost: cost_of(&res), res, seed: seeds[0], latency_ms: latencies[0], candidate_scores: vec![], aesthetic_score: None }]
                 } else {
-                    None
+                    let cost = candidates.iter().map(cost_of).sum();
+                    let (scores, aesthetic) = score_candidates(&candidates, extras.aesthetic.as_deref()).await;
+                    let best = scores.iter().enumerate()
+                        .max_by(|a, b| a.1.total_cmp(b.1))
+                        .map_or(0, |(i, _)| i);
+                    let aesthetic_score = if aesthetic { scores.get(best).copied() } else { None };
+                    emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} best of {}: kept candidate {}", candidates.len(), best + 1) });
+                    vec![Kept { batch_index: None, res: candidates.swap_remove(best), seed: seeds[best], latency_ms: latencies[best], cost, candidate_scores: scores, aesthetic_score }]
                 };
 
-                let maybe = if let Some(cached_val) = cached {
-                    emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} rewrite: cache hit") });
-                    cached_val
-                } else {
-                    emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} rewrite: calling API") });
-                    let result = rw.rewrite(&original).await.unwrap_or(original.clone());
-                    // Store in cache
-                    if let Some(cache) = &extras.rewrite_cache {
-                        if let Err(e) = cache.put(&cache_key, &result).await {
+                let mut saved_any = false;
+                for Kept { batch_index, mut res, seed, latency_ms, cost: image_cost, candidate_scores, mut aesthetic_score } in kept {
+                    let tag = match batch_index { Some(k) => format!("#{id}.{k}"), None => format!("#{id}") };
+                    // dedupe: the hash joins the seen-set only once the image is saved, so
+                    // filtered or failed images don't make later ones duplicates
+                    let mut seen_hash = None;
+                    if let Some(d) = &extras.dedupe {
+                        match d.check(res.bytes.clone()).await {
+                            Ok((Some(m), _)) => {
+                                emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} dropped as near-duplicate of {} (dist {})", m.matched, m.distance) });
+                                duplicates.fetch_add(1, Ordering::Relaxed);
+                                append_manifest(&manifest, &events, dropped(ItemStatus::Duplicate, batch_index, Some(latency_ms))).await;
+                                continue;
+                            }
+                            Ok((None, hash)) => seen_hash = Some(hash),
+                            Err(e) => emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} dedupe skipped: {e:#}") }),
+                        }
+                    }
+                    let phash = seen_hash.as_ref().map(crate::dedupe::hex);
+
+                    // aesthetic score + threshold; an unreachable scorer never blocks the run
+                    if let Some(scorer) = &extras.aesthetic {
+                        if aesthetic_score.is_none() {
+                            match scorer.score(&res.bytes).await {
+                                Ok(s) => aesthetic_score = Some(s),
+                                Err(e) => emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} aesthetic score skipped: {e:#}") }),
+                            }
+                        }
+                        if let (Some(score), Some(min)) = (aesthetic_score, scorer.min_score) {
+                            if score < min {
+                                emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} aesthetic {score:.2} < {min:.2}: dropped") });
+                                filtered.fetch_add(1, Ordering::Relaxed);
+                                append_manifest(&manifest, &events, dropped(ItemStatus::Filtered, batch_index, Some(latency_ms))).await;
+                                continue;
+                            }
+                        }
+                    }
+
+                    // reshape, watermark and frame before anything derived from the image; the sidecar records the final size
+                    match extras.post.aspect(&res.bytes) {
+                        Ok(Some((bytes, w, h))) => { res.bytes = bytes; res.width = w; res.height = h; }
+                        Ok(None) => {}
+                        Err(e) => {
+                            emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} aspect ratio error: {e:#}") });
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            append_failure(&manifest, &events, failure("aspect_ratio", &e, None, batch_index)).await;
+                            append_manifest(&manifest, &events, dropped(ItemStatus::Failed, batch_index, Some(latency_ms))).await;
+                            continue;
+                        }
+                    }
+                    match extras.post.adjust(&res.bytes) {
+                        Ok(Some(bytes)) => res.bytes = bytes,
+                        Ok(None) => {}
+                        Err(e) => {
+                            emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} adjust error: {e:#}") });
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            append_failure(&manifest, &events, failure("adjust", &e, None, batch_index)).await;
+                            append_manifest(&manifest, &events, dropped(ItemStatus::Failed, batch_index, Some(latency_ms))).await;
+                            continue;
+                        }
+                    }
+                    match extras.post.watermark(&res.bytes) {
+                        Ok(Some(bytes)) => res.bytes = bytes,
+                        Ok(None) => {}
+                        Err(e) => {
+                            emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} watermark error: {e:#}") });
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            append_failure(&manifest, &events, failure("watermark", &e, None, batch_index)).await;
+                            append_manifest(&manifest, &events, dropped(ItemStatus::Failed, batch_index, Some(latency_ms))).await;
+                            continue;
+                        }
+                    }
+                    match extras.post.frame(&res.bytes) {
+                        Ok(Some((bytes, w, h))) => { res.bytes = bytes; res.width = w; res.height = h; }
+                        Ok(None) => {}
+                        Err(e) => {
+                            emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} frame error: {e:#}") });
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            append_failure(&manifest, &events, failure("frame", &e, None, batch_index)).await;
+                            append_manifest(&manifest, &events, dropped(ItemStatus::Failed, batch_index, Some(latency_ms))).await;
+                            continue;
+                        }
+                    }
+                    match extras.post.round_corners(&res.bytes) {
+                        Ok(Some(bytes)) => res.bytes = bytes,
+                        Ok(None) => {}
+                        Err(e) => {
+                            emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} corner_radius error: {e:#}") });
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            append_failure(&manifest, &events, failure("corner_radius", &e, None, batch_index)).await;
+                            append_manifest(&manifest, &events, dropped(ItemStatus::Failed, batch_index, Some(latency_ms))).await;
+                            continue;
+                        }
+                    }
+                    match extras.post.strip_metadata(&res.bytes) {
+                        Ok(Some(bytes)) => res.bytes = bytes,
+                        Ok(None) => {}
+                        Err(e) => {
+                            emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} strip metadata error: {e:#}") });
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            append_failure(&manifest, &events, failure("strip_metadata", &e, None, batch_index)).await;
+                            append_manifest(&manifest, &events, dropped(ItemStatus::Failed, batch_index, Some(latency_ms))).await;
+                            continue;
+                        }
+                    }
+
+                    // generate thumbnail if enabled
+                    let thumbnail = match extras.post.maybe_thumbnail(&res.bytes) {
+                        Ok(thumb) => thumb,
+                        Err(e) => {
                             emit(&events, RunEvent::Log {
                                 run_id: run_id.clone(),
-                                msg: format!("#{id} rewrite: cache write error: {e:#}")
+                                msg: format!("{tag} thumbnail error: {e:#}")
                             });
+                            None
                         }
-                    }
-                    result
-                };
+                    };
 
-                if maybe != original {
-                    rewritten = Some(maybe.clone());
-                    prompt_used = maybe;
-                    emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} rewrite: changed") });
-                }
-            }
+                    // augmented copies (made after dedupe so they never collide with their source)
+                    let derivatives = match extras.post.augment(&res.bytes) {
+                        Ok(d) => d,
+                        Err(e) => {
+                            emit(&events, RunEvent::Log {
+                                run_id: run_id.clone(),
+                                msg: format!("{tag} augment error: {e:#}")
+                            });
+                            vec![]
+                        }
+                    };
+
+                    let palette = crate::post::dominant_colors(&res.bytes, extras.post.palette_size).unwrap_or_else(|e| {
+                        emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} palette error: {e:#}") });
+                        vec![]
+                    });
 
-            emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} provider: call") });
-            // call provider with retry + backoff
-            const MAX_RETRIES: u32 = 3;
-            let mut last_error = None;
-            let mut attempt = 1;
-            let res = loop {
-                match provider.generate(&prompt_used).await {
-                    Ok(r) => break Some(r),
-                    Err(e) => {
-                        last_error = Some(e);
-                        if attempt >= MAX_RETRIES {
-                            break None;
+                    // another id may have saved a near-duplicate while this one was post-processed
+                    let claim = match (&extras.dedupe, seen_hash) {
+                        (Some(d), Some(hash)) => match d.claim(hash, Origin::Image { id, batch_index }) {
+                            Ok(claim) => Some(claim),
+                            Err(m) => {
+                                emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} dropped as near-duplicate of {} (dist {})", m.matched, m.distance) });
+                                duplicates.fetch_add(1, Ordering::Relaxed);
+                                append_manifest(&manifest, &events, dropped(ItemStatus::Duplicate, batch_index, Some(latency_ms))).await;
+                                continue;
+                            }
+                        },
+                        _ => None,
+                    };
+
+                    // save, under the name of whichever provider (primary or fallback) served the image
+                    let provider_name = res.served_by.as_ref().map_or(provider.name(), |s| s.provider.as_str()).to_string();
+                    let save_extras = SaveExtras { thumbnail: thumbnail.as_deref(), derivatives, candidate_scores, aesthetic_score, palette, batch_index, negative_prompt: gen_opts.negative_prompt.as_deref(), seed, latency_ms: Some(latency_ms), sidecar, embed_metadata: extras.post.embed_metadata, sink: extras.sink.as_ref() };
+                    let (sidecar_json, url) = match save_image_with_sidecar(&out_dir, &run_id, id, &provider_name, &res, &original, rewritten.as_deref(), image_cost, save_extras).await {
+                        Ok(json) => json,
+                        Err(e) => {
+                            emit(&events, RunEvent::Log {
+                                run_id: run_id.clone(),
+                                msg: format!("{tag} save error: {e:#}")
+                            });
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            append_failure(&manifest, &events, failure("save", &e, None, batch_index)).await;
+                            append_manifest(&manifest, &events, dropped(ItemStatus::Failed, batch_index, Some(latency_ms))).await;
+                            continue;
                         }
-                        let delay_ms = backoff_ms(attempt, backoff_base_ms, backoff_factor, backoff_jitter_ms);
-                        emit(&events, RunEvent::Log {
+                    };
+                    if let Some(claim) = claim { claim.keep(); }
+                    let png_name = format!("{}.png", image_stem(id, batch_index, &provider_name, &res.model));
+                    if let Some(hook) = &extras.on_image {
+                        // uploaded images aren't in out_dir: the hook gets their URL instead
+                        let image = match &url {
+                            Some(url) => crate::hooks::HookImage::Url(url.clone()),
+                            None => crate::hooks::HookImage::File(out_dir.join(&png_name)),
+                        };
+                        hook.fire(image, sidecar_json, run_id.clone(), id, events.clone());
+                    }
+                    // record the image before counting it: a cancel can abort this task at
+                    // the append, and the resumed run must then redo the id
+                    append_manifest(&manifest, &events, ManifestRecord{
+                        id, run_id: &run_id, status: ItemStatus::Saved, created_at: chrono::Utc::now().to_rfc3339(), provider: &provider_name,
+                        model: &res.model, prompt: &prompt_used, path_png: Some(png_name.clone()), batch_index, latency_ms: Some(latency_ms),
+                        cost_usd: Some(image_cost), width: Some(res.width), height: Some(res.height), phash, url, variant: &variant_meta,
+                    }).await;
+                    // progress counts ids, however many images each one saved
+                    let n = if saved_any { done.load(Ordering::Relaxed) } else { done.fetch_add(1, Ordering::Relaxed) + 1 };
+                    // with ordered output, progress is reported as the manifest catches up (below)
+                    if !ordered_output {
+                        emit(&events, RunEvent::Progress {
                             run_id: run_id.clone(),
-                            msg: format!("#{id} provider error (attempt {}/{}), retrying in {}ms", attempt, MAX_RETRIES, delay_ms)
+                            done: n,
+                            total,
+                            cost_so_far: *spent.lock() + extras.rewriter.as_ref().map_or(0.0, |rw| rewrite_cost(rw.as_ref(), extras.rewrite_price_per_1k_tokens)),
+                            queued: queued.load(Ordering::Relaxed),
+                            in_flight: in_flight.0.load(Ordering::Relaxed),
                         });
-                        tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
-                        attempt += 1;
                     }
+                    emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} saved (done {n}/{total})") });
+                    if events.is_some() {
+                        match crate::post::preview_png(&res.bytes) {
+                            Ok(png) => emit(&events, RunEvent::Thumbnail {
+                                run_id: run_id.clone(),
+                                id,
+                                name: png_name,
+                                png_b64: base64::engine::general_purpose::STANDARD.encode(png),
+                            }),
+                            Err(e) => emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("{tag} preview error: {e:#}") }),
+                        }
+                    }
+
+                    if let Some(pb) = &pb {
+                        pb.set_message(format!("queued {} · in flight {}", queued.load(Ordering::Relaxed), in_flight.0.load(Ordering::Relaxed)));
+                        if !saved_any { pb.inc(1); }
+                    }
+                    saved_any = true;
                 }
+                saved_any
             };
-            let res = match res {
-                Some(r) => r,
-                None => {
-                    emit(&events, RunEvent::Log {
-                        run_id: run_id.clone(),
-                        msg: format!("#{id} provider failed after {} attempts: {:#}", MAX_RETRIES, last_error.unwrap())
-                    });
-                    return;
-                }
-            };
-            // dedupe
-            if let Some(d) = &extras.dedupe {
-                let dup = d.lock().await.is_duplicate(&res.bytes).unwrap_or(false);
-                if dup {
-                    emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} dedupe: dropped") });
-                    return;
-                }
+            let saved_image = work.await;
+            match manifest.finish(id).await {
+                Ok(Some(saved)) => emit(&events, RunEvent::Progress {
+                    run_id: run_id.clone(),
+                    done: already_done + saved,
+                    total,
+                    cost_so_far: *spent.lock() + extras.rewriter.as_ref().map_or(0.0, |rw| rewrite_cost(rw.as_ref(), extras.rewrite_price_per_1k_tokens)),
+                    queued: queued.load(Ordering::Relaxed),
+                    in_flight: in_flight_count.load(Ordering::Relaxed),
+                }),
+                Ok(None) => {}
+                Err(e) => emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} manifest append error: {e:#}") }),
+            }
+            open.fetch_sub(1, Ordering::Relaxed);
+            slot_freed.notify_one();
+            // aborted tasks never get here, so they stay pending in the checkpoint
+            if let Err(e) = checkpoint.finished(id, saved_image).await {
+                emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("checkpoint write error: {e:#}") });
+            }
+        });
+    }
+    // dispatching stopped early (budget or Ctrl-C): the producer may be waiting for a freed slot
+    rx.close();
+    producer.abort();
+    producer.await.ok();
+    if cancelled { set.shutdown().await; }
+    loop {
+        tokio::select! {
+            r = set.join_next() => if r.is_none() { break },
+            _ = cancel_requested(), if !cancelled => {
+                emit(&cfg.events, RunEvent::Log { run_id: cfg.run_id.clone(), msg: format!("cancelled: aborting {} in-flight ids", set.len()) });
+                cancelled = true;
+                set.shutdown().await;
+                break;
             }
+            // aborted ids stay pending in the checkpoint, so --resume redoes them
+            _ = ctrl_c(), if interrupted => {
+                emit(&cfg.events, RunEvent::Log { run_id: cfg.run_id.clone(), msg: format!("aborting {} in-flight ids", set.len()) });
+                set.shutdown().await;
+                break;
+            }
+        }
+    }
+    if let Some(ramp) = ramp { ramp.abort(); }
+    if let Err(e) = manifest.flush().await {
+        emit(&cfg.events, RunEvent::Log { run_id: cfg.run_id.clone(), msg: format!("manifest append error: {e:#}") });
+    }
+    if let Some(hook) = &extras.on_image { hook.drain().await; }
+    if extras.post.contact_sheet {
+        match write_contact_sheet(&cfg.out_dir, &cfg.run_id, extras.post.clone(), extras.sink.as_ref()).await {
+            Ok((0, _)) => {}
+            Ok((n, url)) => emit(&cfg.events, RunEvent::Log { run_id: cfg.run_id.clone(), msg: format!("contact sheet: {n} images in {}", url.as_deref().unwrap_or(crate::post::CONTACT_SHEET_FILE)) }),
+            Err(e) => emit(&cfg.events, RunEvent::Log { run_id: cfg.run_id.clone(), msg: format!("contact sheet error: {e:#}") }),
+        }
+    }
+    if let Err(e) = cfg.checkpoint.save().await {
+        emit(&cfg.events, RunEvent::Log { run_id: cfg.run_id.clone(), msg: format!("checkpoint write error: {e:#}") });
+    }
+    if let Some(pb) = pb { pb.finish_with_message("done"); }
+    if interrupted || cancelled {
+        emit(&cfg.events, RunEvent::Log { run_id: cfg.run_id.clone(), msg: format!("run {}; partial output kept", if cancelled { "cancelled" } else { "interrupted" }) });
+    } else if !budget_capped && done.load(Ordering::Relaxed) < cfg.target_images {
+        emit(&cfg.events, RunEvent::Log {
+            run_id: cfg.run_id.clone(),
+            msg: format!("only {} of {} images saved after drawing up to {last_id} prompts; raise orchestrator.max_prompts_factor or vary the template", done.load(Ordering::Relaxed), cfg.target_images),
+        });
+    }
+    let produced = done.load(Ordering::Relaxed) - already_done;
+    let rewrite_cost = extras.rewriter.as_ref().map_or(0.0, |rw| rewrite_cost(rw.as_ref(), extras.rewrite_price_per_1k_tokens));
+    let image_cost = *spent.lock();
+    let outcome = RunOutcome{
+        produced,
+        duplicates: duplicates.load(Ordering::Relaxed),
+        filtered: filtered.load(Ordering::Relaxed),
+        failed: failed.load(Ordering::Relaxed),
+        moderated: moderated.load(Ordering::Relaxed),
+        blocked: blocked.load(Ordering::Relaxed),
+        total_cost: image_cost + rewrite_cost,
+        rewrite_cost,
+        budget_capped,
+        interrupted,
+        cancelled,
+        elapsed: started_at.elapsed(),
+    };
+    // one terminal event per run: whoever cancelled reports it (a timeout as `Failed`)
+    if !cancelled {
+        emit(&cfg.events, RunEvent::Finished { run_id: cfg.run_id.clone(), outcome: Some(outcome.clone()) });
+    }
+    Ok(outcome)
+}
+
+/// Grid every image `run_id` saved (including ones from before a `--resume`)
+/// into `out_dir/contact-sheet.png`, labeled by id. Returns how many went in;
+/// unreadable files are skipped.
+/// Reads the run's images back through `sink`, so it works with remote storage
+/// too, and stores the sheet there. Returns the image count and the sheet's URL
+/// for remote stores.
+async fn write_contact_sheet(out_dir: &std::path::Path, run_id: &str, post: Arc<crate::post::PostProcessor>, sink: &dyn crate::sink::ObjectSink) -> Result<(usize, Option<String>)> {
+    let mut saved = crate::manifest::saved_images(out_dir, run_id).await?;
+    saved.sort_by_key(|s| (s.id, s.batch_index));
+    let mut images: Vec<(String, image::DynamicImage)> = Vec::with_capacity(saved.len());
+    for s in saved {
+        let Ok(bytes) = sink.get(&s.path_png).await else { continue };
+        // shrink as we go so a big run doesn't hold every full-size image
+        let thumb_max = post.thumb_max;
+        let thumb = tokio::task::spawn_blocking(move || image::load_from_memory(&bytes).map(|img| img.thumbnail(thumb_max, thumb_max))).await?;
+        if let Ok(img) = thumb { images.push((s.label(), img)); }
+    }
+    if images.is_empty() { return Ok((0, None)); }
+    let n = images.len();
+    let png = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let cols = (images.len() as f64).sqrt().ceil() as u32;
+        let mut out = std::io::Cursor::new(Vec::new());
+        post.make_contact_sheet(&images, cols)?.write_to(&mut out, image::ImageFormat::Png)?;
+        Ok(out.into_inner())
+    }).await??;
+    let url = sink.put(crate::post::CONTACT_SHEET_FILE, png, "image/png").await?;
+    Ok((n, url))
+}
 
-            // generate thumbnail if enabled
-            let thumbnail = match extras.post.maybe_thumbnail(&res.bytes) {
-                Ok(thumb) => thumb,
+/// Scores for best-of-N ranking: aesthetic when a scorer is configured and answers
+/// for every candidate, otherwise sharpness. The flag is true for aesthetic scores.
+async fn score_candidates(candidates: &[ImageResult], aesthetic: Option<&crate::qa::AestheticScorer>) -> (Vec<f64>, bool) {
+    if let Some(scorer) = aesthetic {
+        let mut scores = Vec::with_capacity(candidates.len());
+        for c in candidates {
+            match scorer.score(&c.bytes).await {
+                Ok(s) => scores.push(s),
                 Err(e) => {
-                    emit(&events, RunEvent::Log {
-                        run_id: run_id.clone(),
-                        msg: format!("#{id} thumbnail error: {e:#}")
-                    });
-                    None
+                    tracing::warn!("aesthetic scorer unavailable, ranking by sharpness: {e:#}");
+                    break;
                 }
-            };
+            }
+        }
+        if scores.len() == candidates.len() { return (scores, true); }
+    }
+    let bytes: Vec<Vec<u8>> = candidates.iter().map(|c| c.bytes.clone()).collect();
+    let scores = tokio::task::spawn_blocking(move || {
+        bytes.iter().map(|b| crate::qa::sharpness(b).unwrap_or(0.0)).collect::<Vec<f64>>()
+    }).await.unwrap_or_default();
+    (scores, false)
+}
 
-            // save
-            if let Err(e) = save_image_with_sidecar(&out_dir, &run_id, id, provider.name(), &res, &original, rewritten.as_deref(), price, thumbnail.as_deref()).await {
-                emit(&events, RunEvent::Log {
-                    run_id: run_id.clone(),
-                    msg: format!("#{id} save error: {e:#}")
+/// Call the provider, retrying with backoff up to `retry.max_attempts` times.
+/// A failure comes back with the number of attempts made.
+#[allow(clippy::too_many_arguments)]
+async fn generate_with_retry(
+    provider: &dyn ImageProvider,
+    prompt: &str,
+    opts: &GenOpts,
+    n: u32,
+    id: u64,
+    run_id: &str,
+    events: &Option<broadcast::Sender<RunEvent>>,
+    retry: RetryPolicy,
+) -> std::result::Result<(Vec<ImageResult>, u64), (anyhow::Error, u32)> {
+    let mut attempt = 1;
+    loop {
+        let started = Instant::now();
+        let result = if n > 1 { provider.generate_batch(prompt, n, opts).await } else { provider.generate_with_opts(prompt, opts).await.map(|r| vec![r]) };
+        match result {
+            Ok(r) => return Ok((r, started.elapsed().as_millis() as u64)),
+            Err(e) => {
+                let classified = e.downcast_ref::<ProviderError>();
+                if attempt >= retry.max_attempts || classified.is_some_and(|pe| !pe.is_retryable()) {
+                    return Err((e, attempt));
+                }
+                // honor the provider's Retry-After over our own backoff
+                let delay_ms = match classified {
+                    Some(ProviderError::RateLimited { retry_after: Some(d), .. }) => d.as_millis() as u64,
+                    _ => backoff_ms(attempt, retry.base_ms, retry.factor, retry.jitter_ms),
+                };
+                emit(events, RunEvent::Log {
+                    run_id: run_id.to_string(),
+                    msg: format!("#{id} provider error (attempt {}/{}), retrying in {}ms: {e:#}", attempt, retry.max_attempts, delay_ms)
                 });
+                tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+impl OrchestratorExtras{
+    fn rewrite_cache_key(&self, rw: &dyn crate::rewrite::PromptRewriter, original: &str) -> String {
+        crate::rewrite::cache_key(
+            original,
+            rw.name(),
+            self.rewriter_model.as_deref().unwrap_or(""),
+            self.rewriter_system.as_deref().unwrap_or(""),
+            &self.rewriter_examples,
+            self.rewriter_sampling,
+        )
+    }
+}
+
+/// A prompt drawn by the producer, with the source positions around it and its
+/// rewrite when it went out in a batch.
+struct Drawn{ pos: Option<u128>, variant: Variant, pos_after: Option<u128>, rewritten: Option<String> }
+
+/// Draws prompts `batch_size` at a time so the ones to be rewritten can share one
+/// `rewrite_batch` call. Ids take prompts in order, so the id of each drawn prompt is known.
+struct Lookahead{
+    source: Box<dyn VariantSource>,
+    buf: VecDeque<Drawn>,
+    batch_size: usize,
+    extras: OrchestratorExtras,
+    run_id: String,
+    events: Option<broadcast::Sender<RunEvent>>,
+}
+
+impl Lookahead{
+    /// The prompt for `id`, drawing (and batch-rewriting) the next batch when the buffer is empty.
+    async fn next(&mut self, id: u64, skip: &std::collections::HashSet<u64>) -> Option<Drawn> {
+        if self.buf.is_empty() {
+            for _ in 0..self.batch_size {
+                let pos = self.source.position();
+                let Some(variant) = self.source.next() else { break };
+                self.buf.push_back(Drawn{ pos, variant, pos_after: self.source.position(), rewritten: None });
+            }
+            if self.batch_size > 1 { self.rewrite_buffered(id, skip).await; }
+        }
+        self.buf.pop_front()
+    }
+
+    /// Batch-rewrite buffered prompts (the first is `first_id`'s) that will run and
+    /// aren't cached. On failure they're left to the workers' per-prompt rewrite.
+    async fn rewrite_buffered(&mut self, first_id: u64, skip: &std::collections::HashSet<u64>) {
+        let extras = &self.extras;
+        let Some(rw) = &extras.rewriter else { return };
+        if extras.rewrite_max_cost_usd.is_some_and(|cap| rewrite_cost(rw.as_ref(), extras.rewrite_price_per_1k_tokens) >= cap) { return; }
+        let mut todo = Vec::new();
+        for (i, d) in self.buf.iter().enumerate() {
+            if skip.contains(&(first_id + i as u64)) { continue; }
+            if let Some(cache) = &extras.rewrite_cache {
+                if cache.get(&extras.rewrite_cache_key(rw.as_ref(), &d.variant.prompt)).await.is_some() { continue; }
+            }
+            todo.push(i);
+        }
+        if todo.len() < 2 { return; }
+        let originals: Vec<String> = todo.iter().map(|&i| self.buf[i].variant.prompt.clone()).collect();
+        emit(&self.events, RunEvent::Log { run_id: self.run_id.clone(), msg: format!("rewrite: batch of {} prompts from #{first_id}", originals.len()) });
+        let rewritten = match rw.rewrite_batch(&originals).await {
+            Ok(r) => r,
+            Err(e) => {
+                emit(&self.events, RunEvent::Log { run_id: self.run_id.clone(), msg: format!("rewrite: batch failed, rewriting one by one: {e:#}") });
                 return;
             }
-            let n = done.fetch_add(1, Ordering::Relaxed) + 1;
-            emit(&events, RunEvent::Progress {
-                run_id: run_id.clone(),
-                done: n,
-                total,
-                cost_so_far: n as f64 * price,
-            });
-            emit(&events, RunEvent::Log { run_id: run_id.clone(), msg: format!("#{id} saved (done {n}/{total})") });
-
-            if let Err(e) = manifest.append(ManifestRecord{
-                id, created_at: chrono::Utc::now().to_rfc3339(), provider: provider.name(),
-                model: provider.model(), prompt: &prompt_used, path_png: format!("{:08}-{}-{}.png", id, provider.name(), provider.model()),
-            }).await {
-                emit(&events, RunEvent::Log {
-                    run_id: run_id.clone(),
-                    msg: format!("#{id} manifest append error: {e:#}")
+        };
+        for (i, r) in todo.into_iter().zip(rewritten) {
+            if let Some(cache) = &extras.rewrite_cache {
+                if let Err(e) = cache.put(&extras.rewrite_cache_key(rw.as_ref(), &self.buf[i].variant.prompt), &r).await {
+                    emit(&self.events, RunEvent::Log { run_id: self.run_id.clone(), msg: format!("rewrite: cache write error: {e:#}") });
+                }
+            }
+            self.buf[i].rewritten = Some(r);
+        }
+    }
+}
+
+/// Call the rewriter, retrying with `retry`'s backoff up to `max_attempts` times.
+async fn rewrite_with_retry(
+    rw: &dyn crate::rewrite::PromptRewriter,
+    original: &str,
+    id: u64,
+    run_id: &str,
+    events: &Option<broadcast::Sender<RunEvent>>,
+    max_attempts: u32,
+    retry: RetryPolicy,
+) -> anyhow::Result<String> {
+    let mut attempt = 1;
+    loop {
+        match rw.rewrite(original).await {
+            Ok(r) => return Ok(r),
+            Err(e) if attempt >= max_attempts => return Err(e),
+            Err(e) => {
+                let delay_ms = backoff_ms(attempt, retry.base_ms, retry.factor, retry.jitter_ms);
+                emit(events, RunEvent::Log {
+                    run_id: run_id.to_string(),
+                    msg: format!("#{id} rewrite error (attempt {attempt}/{max_attempts}), retrying in {delay_ms}ms: {e:#}")
                 });
+                tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
+                attempt += 1;
             }
-            if let Some(pb) = &pb { pb.inc(1); }
-        });
+        }
+    }
+}
+
+/// Per-image provider seed: a hash of the run seed, the id and the candidate
+/// number, kept below 2^32 since that's what most image APIs accept.
+fn image_seed(run_seed: u64, id: u64, candidate: u64) -> u64 {
+    // splitmix64 finalizer
+    let mut z = run_seed ^ id.wrapping_mul(0x9e3779b97f4a7c15) ^ candidate.wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    (z ^ (z >> 31)) % 4_294_967_295
+}
+
+/// An image kept from the provider call(s) for one id, before dedupe and saving.
+struct Kept{
+    batch_index: Option<u32>,
+    res: ImageResult,
+    seed: Option<u64>,
+    /// Duration of the provider call that returned it (the successful attempt).
+    latency_ms: u64,
+    cost: f64,
+    candidate_scores: Vec<f64>,
+    aesthetic_score: Option<f64>,
+}
+
+async fn append_manifest(manifest: &Manifest, events: &Option<broadcast::Sender<RunEvent>>, rec: ManifestRecord<'_>) {
+    let (id, run_id) = (rec.id, rec.run_id.to_string());
+    if let Err(e) = manifest.append(rec).await {
+        emit(events, RunEvent::Log { run_id, msg: format!("#{id} manifest append error: {e:#}") });
+    }
+}
+
+async fn append_failure(manifest: &Manifest, events: &Option<broadcast::Sender<RunEvent>>, rec: FailureRecord<'_>) {
+    let (id, run_id) = (rec.id, rec.run_id.to_string());
+    if let Err(e) = manifest.append_failure(rec).await {
+        emit(events, RunEvent::Log { run_id, msg: format!("#{id} failures append error: {e:#}") });
+    }
+}
+
+/// Counts a running worker; decremented however the task exits.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
     }
-    producer.await.ok();
-    while let Some(_r) = set.join_next().await {}
-    if let Some(pb) = pb { pb.finish_with_message("done"); }
-    emit(&cfg.events, RunEvent::Finished { run_id: cfg.run_id.clone() });
-    Ok(())
 }
 
 fn emit(events: &Option<broadcast::Sender<RunEvent>>, evt: RunEvent) {
@@ -233,3 +1013,174 @@ fn emit(events: &Option<broadcast::Sender<RunEvent>>, evt: RunEvent) {
         let _ = tx.send(evt); // ignore if no listeners
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dedupe::PerceptualDeduper;
+    use std::future::Future;
+    use std::pin::Pin;
+
+    const TEMPLATE: &str = "mode: !AdTemplate\n  brand: Acme\n  product: Soda\n  styles: [neon, retro, pastel]\n  audiences: [teens, parents]\n";
+
+    /// Returns the same gradient PNG for every prompt.
+    struct Same(Vec<u8>);
+
+    impl ImageProvider for Same {
+        fn generate<'a>(&'a self, prompt: &'a str) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+            Box::pin(async move {
+                Ok(ImageResult { bytes: self.0.clone(), width: 64, height: 64, prompt_used: prompt.to_string(), model: "same-v1".into(), cost_usd: None, served_by: None })
+            })
+        }
+        fn name(&self) -> &str { "same" }
+        fn model(&self) -> &str { "same-v1" }
+    }
+
+    /// Always fails.
+    struct Down;
+
+    impl ImageProvider for Down {
+        fn generate<'a>(&'a self, _prompt: &'a str) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+            Box::pin(async { anyhow::bail!("down") })
+        }
+        fn name(&self) -> &str { "down" }
+        fn model(&self) -> &str { "down-v1" }
+    }
+
+    /// `Same`, as a priced provider of another name that reports no cost.
+    struct Backup(Same);
+
+    impl ImageProvider for Backup {
+        fn generate<'a>(&'a self, prompt: &'a str) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+            Box::pin(async move { Ok(ImageResult { model: "backup-v1".into(), ..self.0.generate(prompt).await? }) })
+        }
+        fn name(&self) -> &str { "backup" }
+        fn model(&self) -> &str { "backup-v1" }
+        fn price_usd_per_image(&self) -> f64 { 0.25 }
+    }
+
+    fn gradient_png() -> Vec<u8> {
+        let img = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, 128]));
+        let mut out = std::io::Cursor::new(Vec::new());
+        img.write_to(&mut out, image::ImageFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    /// Run `provider` over `out_dir` for 3 images; returns the outcome and every event.
+    async fn run(provider: Arc<dyn ImageProvider>, out_dir: &std::path::Path, dedupe: Option<Arc<PerceptualDeduper>>, cancel: Option<Arc<tokio::sync::Notify>>) -> (RunOutcome, Vec<RunEvent>) {
+        let source = crate::prompts::variant_source(serde_yaml::from_str(TEMPLATE).unwrap(), 7).await.unwrap();
+        let (events, mut rx) = broadcast::channel(1024);
+        let collected = tokio::spawn(async move {
+            let mut all = vec![];
+            while let Ok(evt) = rx.recv().await { all.push(evt); }
+            all
+        });
+        let post = crate::post::PostProcessor::new(&serde_yaml::from_str("{ thumbnail: false, thumb_max: 16 }").unwrap()).unwrap();
+        let outcome = run_orchestrator(
+            provider,
+            source,
+            OrchestratorCfg {
+                run_id: "run-test".into(),
+                out_dir: out_dir.to_path_buf(),
+                target_images: 3,
+                concurrency: 2,
+                queue_cap: 4,
+                rate_per_min: 60_000,
+                price_usd_per_image: 0.0,
+                retry: RetryPolicy { max_attempts: 1, base_ms: 1, factor: 1.0, jitter_ms: 0 },
+                best_of: 1,
+                images_per_call: 1,
+                max_cost_usd: None,
+                ordered_output: false,
+                max_prompts_factor: 2.0,
+                stop_on_ctrl_c: false,
+                cancel,
+                init_image: None,
+                seed: 7,
+                slow_start_ms: None,
+                sidecar: Default::default(),
+                checkpoint: Arc::new(Checkpointer::new(out_dir, "run-test".into(), 7, None)),
+                resume_from: None,
+                progress: None,
+                events: Some(events),
+            },
+            OrchestratorExtras {
+                rewriter: None,
+                rewriter_model: None,
+                rewriter_system: None,
+                rewriter_examples: vec![],
+                rewriter_sampling: Default::default(),
+                rewrite_cache: None,
+                rewrite_price_per_1k_tokens: 0.0,
+                rewrite_max_cost_usd: None,
+                rewrite_max_attempts: 1,
+                rewrite_batch_size: 1,
+                post: Arc::new(post),
+                dedupe,
+                aesthetic: None,
+                on_image: None,
+                sink: Arc::new(crate::sink::LocalSink::new(out_dir)),
+            },
+        ).await.unwrap();
+        (outcome, collected.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn duplicate_pngs_are_dropped() {
+        let out_dir = std::env::temp_dir().join(format!("adgen-orchestrator-test-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&out_dir).await.unwrap();
+        let dedupe = PerceptualDeduper::new(Default::default(), 64, 4, None).unwrap();
+        let (outcome, events) = run(Arc::new(Same(gradient_png())), &out_dir, Some(Arc::new(dedupe)), None).await;
+        let logs: Vec<&String> = events.iter().filter_map(|e| match e { RunEvent::Log { msg, .. } => Some(msg), _ => None }).collect();
+
+        assert_eq!(outcome.produced, 1);
+        assert_eq!(outcome.duplicates, 5);
+        assert_eq!(logs.iter().filter(|m| m.contains("dropped as near-duplicate of #")).count(), 5, "{logs:?}");
+        assert!(!logs.iter().any(|m| m.contains("dedupe skipped")), "{logs:?}");
+
+        let txt = tokio::fs::read_to_string(out_dir.join(crate::manifest::MANIFEST_FILE)).await.unwrap();
+        let recs: Vec<serde_json::Value> = txt.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        let saved: Vec<_> = recs.iter().filter(|r| r["status"] == "saved").collect();
+        assert_eq!(saved.len(), 1);
+        assert!(saved[0]["phash"].as_str().is_some_and(|h| !h.is_empty()));
+        assert_eq!(recs.iter().filter(|r| r["status"] == "duplicate").count(), 5);
+
+        tokio::fs::remove_dir_all(&out_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_run_emits_no_finished() {
+        let out_dir = std::env::temp_dir().join(format!("adgen-orchestrator-test-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&out_dir).await.unwrap();
+        let cancel = Arc::new(tokio::sync::Notify::new());
+        cancel.notify_one();
+        let (outcome, events) = run(Arc::new(Same(gradient_png())), &out_dir, None, Some(cancel)).await;
+        assert!(outcome.cancelled);
+        assert!(!events.iter().any(|e| e.is_terminal()), "{events:?}");
+
+        tokio::fs::remove_dir_all(&out_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fallback_images_carry_the_serving_provider() {
+        let out_dir = std::env::temp_dir().join(format!("adgen-orchestrator-test-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&out_dir).await.unwrap();
+        let chain = crate::providers::FallbackProvider::new(vec![Arc::new(Down), Arc::new(Backup(Same(gradient_png())))]).unwrap();
+        let (outcome, _) = run(Arc::new(chain), &out_dir, None, None).await;
+        assert_eq!(outcome.produced, 3);
+        assert!((outcome.total_cost - 0.75).abs() < 1e-9, "{outcome:?}");
+
+        let txt = tokio::fs::read_to_string(out_dir.join(crate::manifest::MANIFEST_FILE)).await.unwrap();
+        for line in txt.lines() {
+            let rec: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!((rec["provider"].as_str(), rec["model"].as_str()), (Some("backup"), Some("backup-v1")));
+            let png = rec["path_png"].as_str().unwrap();
+            assert!(png.ends_with("-backup-backup-v1.png"), "{png}");
+            let sidecar: serde_json::Value = serde_json::from_slice(&tokio::fs::read(out_dir.join(png.replace(".png", ".json"))).await.unwrap()).unwrap();
+            assert_eq!(sidecar["provider"], "backup");
+            assert_eq!(sidecar["cost_usd"], 0.25);
+        }
+
+        tokio::fs::remove_dir_all(&out_dir).await.unwrap();
+    }
+}
@@ -1,16 +1,477 @@
-use anyhow::Result;
-use image::{imageops::FilterType, ImageFormat};
+use ab_glyph::{point, Font, FontRef, OutlinedGlyph, PxScale, Rect, ScaleFont};
+use anyhow::{Context, Result};
+use image::{imageops::{self, FilterType}, DynamicImage, ImageFormat, Rgba, RgbaImage};
 use std::io::Cursor;
 
-pub struct PostProcessor{ pub make_thumb: bool, pub thumb_max: u32 }
+use crate::config::{AspectCfg, AspectFit, AugmentCfg, CropAnchor, FrameMode, PostCfg, WatermarkCfg, WatermarkImage, WatermarkPosition};
+use crate::providers::BUNDLED_FONT;
+
+pub struct PostProcessor{
+    pub make_thumb: bool,
+    pub thumb_max: u32,
+    pub augment: Option<AugmentCfg>,
+    frame: Option<(u32, Rgba<u8>, FrameMode)>,
+    aspect: Option<(AspectCfg, Rgba<u8>)>,
+    /// `post.brightness`, `post.contrast` and `post.sharpen`.
+    adjust: (Option<i32>, Option<f32>, Option<f32>),
+    corner_radius: Option<u32>,
+    watermark: Option<(WatermarkCfg, Rgba<u8>)>,
+    logo: Option<(RgbaImage, WatermarkImage, u32)>,
+    /// Embed provenance text chunks in saved PNGs (`post.embed_metadata`).
+    pub embed_metadata: bool,
+    strip_metadata: bool,
+    pub contact_sheet: bool,
+    pub palette_size: usize,
+}
 impl PostProcessor{
-    pub fn new(make_thumb: bool, thumb_max: u32) -> Self { Self{make_thumb, thumb_max} }
+    pub fn new(cfg: &PostCfg) -> Result<Self> {
+        let frame = cfg.frame.as_ref()
+            .map(|f| parse_hex_color(&f.color).map(|c| (f.width_px, c, f.mode)))
+            .transpose()
+            .context("post.frame.color")?;
+        if cfg.aspect_ratio.as_ref().is_some_and(|a| a.ratio.0 == 0 || a.ratio.1 == 0) {
+            anyhow::bail!("post.aspect_ratio.ratio must be two numbers above 0, e.g. [16, 9]");
+        }
+        let aspect = cfg.aspect_ratio.as_ref()
+            .map(|a| parse_hex_color(&a.background).map(|c| (a.clone(), c)))
+            .transpose()
+            .context("post.aspect_ratio.background")?;
+        if cfg.sharpen.is_some_and(|s| !(s.is_finite() && s > 0.0)) {
+            anyhow::bail!("post.sharpen must be above 0");
+        }
+        if cfg.contrast.is_some_and(|c| !c.is_finite()) {
+            anyhow::bail!("post.contrast must be a number");
+        }
+        let watermark_cfg = cfg.watermark.as_ref();
+        let watermark = watermark_cfg
+            .filter(|w| !w.text.trim().is_empty())
+            .map(|w| parse_hex_color(&w.color).map(|c| (w.clone(), c)))
+            .transpose()
+            .context("post.watermark.color")?;
+        if watermark.as_ref().is_some_and(|(w, _)| !(w.px.is_finite() && w.px > 0.0)) {
+            anyhow::bail!("post.watermark.px must be above 0");
+        }
+        let logo = match watermark_cfg.and_then(|w| w.image.as_ref().map(|i| (i, w.margin_px))) {
+            Some((logo, margin)) => {
+                if !(0.0..=1.0).contains(&logo.opacity) { anyhow::bail!("post.watermark.image.opacity must be between 0 and 1"); }
+                if !(logo.scale > 0.0 && logo.scale <= 1.0) { anyhow::bail!("post.watermark.image.scale must be above 0 and at most 1"); }
+                let img = image::open(&logo.path).with_context(|| format!("post.watermark.image.path {}", logo.path.display()))?.to_rgba8();
+                Some((img, logo.clone(), margin))
+            }
+            None => None,
+        };
+        Ok(Self{make_thumb: cfg.thumbnail, thumb_max: cfg.thumb_max, augment: cfg.augment.clone(), frame, aspect, watermark, logo,
+            adjust: (cfg.brightness, cfg.contrast, cfg.sharpen),
+            corner_radius: cfg.corner_radius.filter(|&r| r > 0),
+            // stripping wins over embedding
+            embed_metadata: cfg.embed_metadata && !cfg.strip_metadata,
+            strip_metadata: cfg.strip_metadata,
+            contact_sheet: cfg.contact_sheet,
+            palette_size: cfg.palette_size,
+        })
+    }
+
+    /// The image with `post.brightness`, `post.contrast` and then `post.sharpen`
+    /// (an unsharp mask) applied; `None` when none of them is set. Runs before
+    /// watermarks so they stay crisp.
+    pub fn adjust(&self, bytes:&[u8]) -> Result<Option<Vec<u8>>> {
+        let (brightness, contrast, sharpen) = self.adjust;
+        if brightness.is_none() && contrast.is_none() && sharpen.is_none() { return Ok(None); }
+        let mut img = image::load_from_memory(bytes)?;
+        if let Some(b) = brightness { img = img.brighten(b); }
+        if let Some(c) = contrast { img = img.adjust_contrast(c); }
+        if let Some(sigma) = sharpen { img = img.unsharpen(sigma, 1); }
+        Ok(Some(encode_png(&img)?))
+    }
+
+    /// The image without text or EXIF metadata; `None` when `post.strip_metadata`
+    /// is off. PNG chunks are filtered as-is; other formats are re-encoded as PNG.
+    pub fn strip_metadata(&self, bytes:&[u8]) -> Result<Option<Vec<u8>>> {
+        if !self.strip_metadata { return Ok(None); }
+        if let Some(png) = strip_png_metadata(bytes) { return Ok(Some(png)); }
+        Ok(Some(encode_png(&image::load_from_memory(bytes)?)?))
+    }
+
+    /// The image cropped or padded to `post.aspect_ratio`, with the new size; `None`
+    /// when no ratio is configured or the image already has it. Runs first, so
+    /// watermarks and the frame are placed on the final shape.
+    pub fn aspect(&self, bytes:&[u8]) -> Result<Option<(Vec<u8>, u32, u32)>> {
+        let Some((cfg, background)) = &self.aspect else { return Ok(None); };
+        let img = image::load_from_memory(bytes)?.to_rgba8();
+        let (w, h) = img.dimensions();
+        let (rw, rh) = (cfg.ratio.0 as u64, cfg.ratio.1 as u64);
+        // compare w/h with rw/rh without rounding
+        let (wide, tall) = (w as u64 * rh, h as u64 * rw);
+        if wide == tall { return Ok(None); }
+        let offset = |room: u32| match cfg.anchor {
+            CropAnchor::Top => 0,
+            CropAnchor::Center => room / 2,
+            CropAnchor::Bottom => room,
+        };
+        let out = match cfg.fit {
+            AspectFit::Crop => {
+                let (cw, ch) = if wide > tall { ((h as u64 * rw / rh) as u32, h) } else { (w, (w as u64 * rh / rw) as u32) };
+                let (cw, ch) = (cw.max(1), ch.max(1));
+                imageops::crop_imm(&img, (w - cw) / 2, offset(h - ch), cw, ch).to_image()
+            }
+            AspectFit::Pad => {
+                let (cw, ch) = if wide > tall { (w, (w as u64 * rh).div_ceil(rw) as u32) } else { ((h as u64 * rw).div_ceil(rh) as u32, h) };
+                let mut canvas = RgbaImage::from_pixel(cw, ch, *background);
+                imageops::overlay(&mut canvas, &img, ((cw - w) / 2) as i64, offset(ch - h) as i64);
+                canvas
+            }
+        };
+        let (ow, oh) = out.dimensions();
+        Ok(Some((encode_png(&DynamicImage::ImageRgba8(out))?, ow, oh)))
+    }
+
+    /// The image with `post.watermark` text and/or logo drawn on it; `None` when no
+    /// watermark is configured. Runs before the frame, so it sits inside the border.
+    pub fn watermark(&self, bytes:&[u8]) -> Result<Option<Vec<u8>>> {
+        if self.watermark.is_none() && self.logo.is_none() { return Ok(None); }
+        let mut img = image::load_from_memory(bytes)?.to_rgba8();
+        if let Some((wm, color)) = &self.watermark { draw_watermark_text(&mut img, wm, *color)?; }
+        if let Some((logo, cfg, margin)) = &self.logo { overlay_logo(&mut img, logo, cfg, *margin); }
+        Ok(Some(encode_png(&DynamicImage::ImageRgba8(img))?))
+    }
+
+    /// The image inside its `post.frame` border, with the new size; `None` when no
+    /// frame is configured. Runs before thumbnails and augmentation so they include it.
+    pub fn frame(&self, bytes:&[u8]) -> Result<Option<(Vec<u8>, u32, u32)>> {
+        let Some((border, color, mode)) = self.frame else { return Ok(None); };
+        if border == 0 { return Ok(None); }
+        let img = image::load_from_memory(bytes)?.to_rgba8();
+        let (w, h) = img.dimensions();
+        let (canvas_w, canvas_h, inner) = match mode {
+            FrameMode::Expand => (w + 2 * border, h + 2 * border, img),
+            FrameMode::Inset => {
+                if w <= 2 * border || h <= 2 * border {
+                    anyhow::bail!("post.frame.width_px {border} leaves no room for a {w}x{h} image");
+                }
+                (w, h, imageops::resize(&img, w - 2 * border, h - 2 * border, FilterType::Lanczos3))
+            }
+        };
+        let mut canvas = RgbaImage::from_pixel(canvas_w, canvas_h, color);
+        imageops::overlay(&mut canvas, &inner, border as i64, border as i64);
+        Ok(Some((encode_png(&DynamicImage::ImageRgba8(canvas))?, canvas_w, canvas_h)))
+    }
+
+    /// The image with its corners cut to `post.corner_radius` (capped at half the
+    /// shorter side) by fading alpha, anti-aliased over one pixel. Goes after the
+    /// frame so the border follows the curve.
+    pub fn round_corners(&self, bytes:&[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(radius) = self.corner_radius else { return Ok(None); };
+        let mut img = image::load_from_memory(bytes)?.to_rgba8();
+        let (w, h) = img.dimensions();
+        let r = radius.min(w / 2).min(h / 2);
+        if r == 0 { return Ok(None); }
+        let rf = r as f32;
+        for (x, y, p) in img.enumerate_pixels_mut() {
+            // distance from the nearest corner's circle center, outside the straight edges only
+            let dx = if x < r { rf - (x as f32 + 0.5) } else if x >= w - r { x as f32 + 0.5 - (w - r) as f32 } else { continue };
+            let dy = if y < r { rf - (y as f32 + 0.5) } else if y >= h - r { y as f32 + 0.5 - (h - r) as f32 } else { continue };
+            let coverage = (rf - (dx * dx + dy * dy).sqrt() + 0.5).clamp(0.0, 1.0);
+            p[3] = (p[3] as f32 * coverage).round() as u8;
+        }
+        Ok(Some(encode_png(&DynamicImage::ImageRgba8(img))?))
+    }
+
     pub fn maybe_thumbnail(&self, bytes:&[u8]) -> Result<Option<Vec<u8>>> {
         if !self.make_thumb { return Ok(None); }
         let img = image::load_from_memory(bytes)?;
         let thumb = img.resize(self.thumb_max, self.thumb_max, FilterType::Lanczos3);
-        let mut buf = Vec::new();
-        thumb.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?;
-        Ok(Some(buf))
+        Ok(Some(encode_png(&thumb)?))
+    }
+
+    /// Derived copies of an image as `(op, png bytes)`, in this order: a horizontal
+    /// flip, a vertical flip (`mirror`), a transpose and a slight (90%) center crop
+    /// scaled back to the original size. They are saved as numbered outputs.
+    pub fn augment(&self, bytes:&[u8]) -> Result<Vec<(&'static str, Vec<u8>)>> {
+        let Some(aug) = &self.augment else { return Ok(vec![]); };
+        if !aug.flip && !aug.mirror && !aug.transpose && !aug.crop { return Ok(vec![]); }
+        let img = image::load_from_memory(bytes)?;
+        let mut out = Vec::new();
+        if aug.flip {
+            out.push(("flip", encode_png(&img.fliph())?));
+        }
+        if aug.mirror {
+            out.push(("mirror", encode_png(&img.flipv())?));
+        }
+        if aug.transpose {
+            // a transpose is a 90° clockwise turn mirrored left to right
+            out.push(("transpose", encode_png(&img.rotate90().fliph())?));
+        }
+        if aug.crop {
+            let (w, h) = (img.width(), img.height());
+            let (cw, ch) = ((w * 9 / 10).max(1), (h * 9 / 10).max(1));
+            let cropped = img.crop_imm((w - cw) / 2, (h - ch) / 2, cw, ch).resize_exact(w, h, FilterType::Lanczos3);
+            out.push(("crop", encode_png(&cropped)?));
+        }
+        Ok(out)
+    }
+
+    /// A white grid, `cols` wide, of `thumb_max` cells: each image shrunk to fit
+    /// its cell with its label centered underneath.
+    pub fn make_contact_sheet(&self, images: &[(String, DynamicImage)], cols: u32) -> Result<DynamicImage> {
+        let font = FontRef::try_from_slice(BUNDLED_FONT).context("bundled font")?;
+        let cell = self.thumb_max.max(1);
+        let cols = cols.clamp(1, images.len().max(1) as u32);
+        let rows = (images.len() as u32).div_ceil(cols).max(1);
+        let label_h = SHEET_LABEL_PX.ceil() as u32 + SHEET_PAD;
+        let (step_x, step_y) = (cell + SHEET_PAD, cell + label_h + SHEET_PAD);
+        let mut sheet = RgbaImage::from_pixel(SHEET_PAD + cols * step_x, SHEET_PAD + rows * step_y, Rgba([255, 255, 255, 255]));
+        for (i, (label, img)) in images.iter().enumerate() {
+            let (x0, y0) = (SHEET_PAD + (i as u32 % cols) * step_x, SHEET_PAD + (i as u32 / cols) * step_y);
+            let thumb = img.thumbnail(cell, cell).to_rgba8();
+            let (tx, ty) = (x0 + (cell - thumb.width()) / 2, y0 + (cell - thumb.height()) / 2);
+            imageops::overlay(&mut sheet, &thumb, tx as i64, ty as i64);
+            let (glyphs, ink) = layout_line(&font, PxScale::from(SHEET_LABEL_PX), label);
+            let x = x0 as i64 + (cell as i64 - ink.width().round() as i64) / 2;
+            let y = (y0 + cell + SHEET_PAD / 2) as i64;
+            draw_glyphs(&mut sheet, &glyphs, (x - ink.min.x.round() as i64, y - ink.min.y.round() as i64), Rgba([40, 40, 40, 255]));
+        }
+        Ok(DynamicImage::ImageRgba8(sheet))
+    }
+}
+
+/// Longest edge the image is shrunk to before clustering its colors.
+const PALETTE_SAMPLE: u32 = 64;
+const PALETTE_ITERS: usize = 12;
+
+/// The `n` main colors of an image as `#rrggbb`, most common first: k-means over
+/// a small downsample, seeded from brightness quantiles so the same image always
+/// gives the same palette. Mostly transparent pixels are left out.
+pub fn dominant_colors(bytes:&[u8], n: usize) -> Result<Vec<String>> {
+    if n == 0 { return Ok(vec![]); }
+    let img = image::load_from_memory(bytes)?.thumbnail(PALETTE_SAMPLE, PALETTE_SAMPLE).to_rgba8();
+    let mut pixels: Vec<[f32; 3]> = img.pixels().filter(|p| p[3] >= 128).map(|p| [p[0] as f32, p[1] as f32, p[2] as f32]).collect();
+    if pixels.is_empty() { return Ok(vec![]); }
+    let luma = |p: &[f32; 3]| 0.299 * p[0] + 0.587 * p[1] + 0.114 * p[2];
+    pixels.sort_by(|a, b| luma(a).total_cmp(&luma(b)));
+    let k = n.min(pixels.len());
+    let mut centers: Vec<[f32; 3]> = (0..k).map(|i| pixels[(2 * i + 1) * pixels.len() / (2 * k)]).collect();
+    let nearest = |centers: &[[f32; 3]], p: &[f32; 3]| {
+        let dist = |c: &[f32; 3]| (0..3).map(|i| (c[i] - p[i]).powi(2)).sum::<f32>();
+        (0..centers.len()).min_by(|&a, &b| dist(&centers[a]).total_cmp(&dist(&centers[b]))).unwrap_or(0)
+    };
+    let mut assigned = vec![usize::MAX; pixels.len()];
+    let mut counts = vec![0usize; k];
+    for _ in 0..PALETTE_ITERS {
+        let mut changed = false;
+        for (p, a) in pixels.iter().zip(assigned.iter_mut()) {
+            let best = nearest(&centers, p);
+            if best != *a { *a = best; changed = true; }
+        }
+        if !changed { break; }
+        let mut sums = vec![[0f32; 3]; k];
+        counts = vec![0; k];
+        for (p, &a) in pixels.iter().zip(&assigned) {
+            for c in 0..3 { sums[a][c] += p[c]; }
+            counts[a] += 1;
+        }
+        for ((center, sum), &count) in centers.iter_mut().zip(&sums).zip(&counts) {
+            if count > 0 { *center = sum.map(|s| s / count as f32); }
+        }
+    }
+    let mut order: Vec<usize> = (0..k).filter(|&i| counts[i] > 0).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(counts[i]));
+    let mut palette: Vec<String> = Vec::with_capacity(order.len());
+    for hex in order.into_iter().map(|i| { let [r, g, b] = centers[i].map(|c| c.round() as u8); format!("#{r:02x}{g:02x}{b:02x}") }) {
+        if !palette.contains(&hex) { palette.push(hex); }
+    }
+    Ok(palette)
+}
+
+/// Written to `out_dir` at the end of a run with `post.contact_sheet`.
+pub const CONTACT_SHEET_FILE: &str = "contact-sheet.png";
+const SHEET_PAD: u32 = 8;
+const SHEET_LABEL_PX: f32 = 14.0;
+
+/// Longest edge of the inline previews pushed to the live thumbnail feed.
+pub const PREVIEW_MAX: u32 = 128;
+
+/// Small PNG preview for streaming, independent of the `post.thumbnail` setting.
+pub fn preview_png(bytes:&[u8]) -> Result<Vec<u8>> {
+    let img = image::load_from_memory(bytes)?;
+    encode_png(&img.thumbnail(PREVIEW_MAX, PREVIEW_MAX))
+}
+
+/// Draw `wm.text` in `color` at `wm.position`.
+fn draw_watermark_text(img: &mut RgbaImage, wm: &WatermarkCfg, color: Rgba<u8>) -> Result<()> {
+    let font = FontRef::try_from_slice(BUNDLED_FONT).context("bundled font")?;
+    let (glyphs, ink) = layout_line(&font, PxScale::from(wm.px), &wm.text);
+    let (x, y) = place(wm.position, img.dimensions(), &ink, wm.margin_px);
+    // shift the ink box from where the layout put it to where it belongs
+    draw_glyphs(img, &glyphs, (x - ink.min.x.round() as i64, y - ink.min.y.round() as i64), color);
+    Ok(())
+}
+
+/// Blend laid-out `glyphs`, offset by `(dx, dy)`, onto `img` in `color`; pixels
+/// off the image are dropped.
+fn draw_glyphs(img: &mut RgbaImage, glyphs: &[OutlinedGlyph], (dx, dy): (i64, i64), color: Rgba<u8>) {
+    let alpha = color[3] as f32 / 255.0;
+    for g in glyphs {
+        let bb = g.px_bounds();
+        g.draw(|gx, gy, coverage| {
+            let px = bb.min.x as i64 + gx as i64 + dx;
+            let py = bb.min.y as i64 + gy as i64 + dy;
+            if px < 0 || py < 0 || px >= img.width() as i64 || py >= img.height() as i64 { return; }
+            let a = coverage.clamp(0.0, 1.0) * alpha;
+            let p = img.get_pixel_mut(px as u32, py as u32);
+            for c in 0..3 { p[c] = (p[c] as f32 * (1.0 - a) + color[c] as f32 * a).round() as u8; }
+            p[3] = (p[3] as f32 + (255.0 - p[3] as f32) * a).round() as u8;
+        });
+    }
+}
+
+/// Blend `logo` (scaled to `cfg.scale` of the image width, or down to fit) at `cfg.position`.
+fn overlay_logo(img: &mut RgbaImage, logo: &RgbaImage, cfg: &WatermarkImage, margin: u32) {
+    let (w, h) = img.dimensions();
+    let (room_w, room_h) = (w.saturating_sub(2 * margin).max(1), h.saturating_sub(2 * margin).max(1));
+    let target_w = ((w as f32 * cfg.scale).round() as u32).clamp(1, room_w);
+    let (lw, lh) = logo.dimensions();
+    // keep the aspect ratio, then shrink again if it's too tall
+    let fit = (target_w as f32 / lw as f32).min(room_h as f32 / lh as f32);
+    let (sw, sh) = (((lw as f32 * fit).round() as u32).max(1), ((lh as f32 * fit).round() as u32).max(1));
+    let mut scaled = imageops::resize(logo, sw, sh, FilterType::Lanczos3);
+    if cfg.opacity < 1.0 {
+        for p in scaled.pixels_mut() { p[3] = (p[3] as f32 * cfg.opacity).round() as u8; }
+    }
+    let box_ = Rect{ min: point(0.0, 0.0), max: point(sw as f32, sh as f32) };
+    let (x, y) = place(cfg.position, (w, h), &box_, margin);
+    imageops::overlay(img, &scaled, x, y);
+}
+
+/// `text` laid out on one line (baseline at the font's ascent, kerning applied),
+/// with the union of the glyphs' pixel bounds: the ink box that gets placed.
+fn layout_line(font: &FontRef, scale: PxScale, text: &str) -> (Vec<OutlinedGlyph>, Rect) {
+    let scaled = font.as_scaled(scale);
+    let (mut x, mut last) = (0.0f32, None);
+    let mut glyphs = Vec::new();
+    let mut ink: Option<Rect> = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(prev) = last { x += scaled.kern(prev, id); }
+        let glyph = id.with_scale_and_position(scale, point(x, scaled.ascent()));
+        x += scaled.h_advance(id);
+        last = Some(id);
+        // spaces have an advance but no outline
+        let Some(g) = font.outline_glyph(glyph) else { continue };
+        let bb = g.px_bounds();
+        ink = Some(match ink {
+            Some(r) => Rect{ min: point(r.min.x.min(bb.min.x), r.min.y.min(bb.min.y)), max: point(r.max.x.max(bb.max.x), r.max.y.max(bb.max.y)) },
+            None => bb,
+        });
+        glyphs.push(g);
+    }
+    (glyphs, ink.unwrap_or_default())
+}
+
+/// Top-left corner for a `box_` sized watermark in a `(w, h)` image, `margin` in
+/// from the edges it's anchored to.
+fn place(position: WatermarkPosition, (w, h): (u32, u32), box_: &Rect, margin: u32) -> (i64, i64) {
+    let (bw, bh) = (box_.width().round() as i64, box_.height().round() as i64);
+    let (w, h, m) = (w as i64, h as i64, margin as i64);
+    let (left, right, top, bottom) = (m, w - m - bw, m, h - m - bh);
+    match position {
+        WatermarkPosition::TopLeft => (left, top),
+        WatermarkPosition::TopRight => (right, top),
+        WatermarkPosition::BottomLeft => (left, bottom),
+        WatermarkPosition::BottomRight => (right, bottom),
+        WatermarkPosition::Center => ((w - bw) / 2, (h - bh) / 2),
+    }
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Signature plus the IHDR chunk, which the spec puts first at a fixed size.
+const PNG_HEADER_LEN: usize = 8 + 4 + 4 + 13 + 4;
+
+/// `bytes` with an uncompressed UTF-8 `iTXt` chunk per `(keyword, text)` right
+/// after the header; `None` if `bytes` isn't a PNG. Pixel data is untouched.
+pub fn embed_png_text(bytes:&[u8], entries:&[(&str, &str)]) -> Option<Vec<u8>> {
+    if !bytes.starts_with(PNG_SIGNATURE) || bytes.get(12..16) != Some(b"IHDR".as_slice()) || bytes.len() < PNG_HEADER_LEN {
+        return None;
+    }
+    let mut out = Vec::with_capacity(bytes.len() + entries.iter().map(|(k, v)| k.len() + v.len() + 17).sum::<usize>());
+    out.extend_from_slice(&bytes[..PNG_HEADER_LEN]);
+    for (keyword, text) in entries {
+        // keyword NUL, not compressed, method 0, empty language tag NUL, empty translated keyword NUL
+        let mut data = Vec::with_capacity(keyword.len() + text.len() + 5);
+        data.extend_from_slice(keyword.as_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0, 0]);
+        data.extend_from_slice(text.as_bytes());
+        write_png_chunk(&mut out, b"iTXt", &data);
+    }
+    out.extend_from_slice(&bytes[PNG_HEADER_LEN..]);
+    Some(out)
+}
+
+/// Chunks that carry text, EXIF or timestamps rather than pixels or color.
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"tEXt", b"zTXt", b"iTXt", b"eXIf", b"tIME"];
+
+/// `bytes` without `PNG_METADATA_CHUNKS`; `None` if `bytes` isn't a well-formed PNG.
+fn strip_png_metadata(bytes:&[u8]) -> Option<Vec<u8>> {
+    if !bytes.starts_with(PNG_SIGNATURE) { return None; }
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+    let mut rest = &bytes[PNG_SIGNATURE.len()..];
+    while !rest.is_empty() {
+        let len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let chunk = rest.get(..12 + len)?;
+        if !PNG_METADATA_CHUNKS.iter().any(|k| &chunk[4..8] == k.as_slice()) {
+            out.extend_from_slice(chunk);
+        }
+        rest = &rest[12 + len..];
+    }
+    Some(out)
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+fn parse_hex_color(s: &str) -> Result<Rgba<u8>> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if !matches!(hex.len(), 6 | 8) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        anyhow::bail!("expected #rrggbb or #rrggbbaa, got `{s}`");
+    }
+    let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(255);
+    Ok(Rgba([byte(0), byte(2), byte(4), if hex.len() == 8 { byte(6) } else { 255 }]))
+}
+
+fn encode_png(img: &DynamicImage) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn augment_numbers_copies_in_op_order() {
+        let post = PostProcessor::new(&serde_yaml::from_str("{ thumbnail: false, thumb_max: 16, augment: { crop: true, transpose: true, mirror: true, flip: true } }").unwrap()).unwrap();
+        // 3x2, red in the top-left corner
+        let mut img = RgbaImage::from_pixel(3, 2, Rgba([0, 0, 0, 255]));
+        img.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        let copies = post.augment(&encode_png(&DynamicImage::ImageRgba8(img)).unwrap()).unwrap();
+        assert_eq!(copies.iter().map(|(op, _)| *op).collect::<Vec<_>>(), ["flip", "mirror", "transpose", "crop"]);
+
+        let decode = |i: usize| image::load_from_memory(&copies[i].1).unwrap().to_rgba8();
+        let red = Rgba([255, 0, 0, 255]);
+        assert_eq!(*decode(0).get_pixel(2, 0), red);
+        assert_eq!(*decode(1).get_pixel(0, 1), red);
+        let transposed = decode(2);
+        assert_eq!(transposed.dimensions(), (2, 3));
+        assert_eq!(*transposed.get_pixel(0, 0), red);
+        assert_eq!(*transposed.get_pixel(1, 2), Rgba([0, 0, 0, 255]));
+        assert_eq!(decode(3).dimensions(), (3, 2));
     }
 }
@@ -1,4 +1,70 @@
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use anyhow::Context;
+use rand::distr::{weighted::WeightedIndex, Distribution};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use crate::config::{Mode, StyleEntry, TemplateYaml, VariantMode};
+
+/// One prompt to generate.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub prompt: String,
+    /// Template choices the prompt was built from, e.g. `style`; recorded in the
+    /// manifest for coverage reporting.
+    pub meta: BTreeMap<String, String>,
+    pub negative_prompt: Option<String>,
+}
+
+/// Anything that can feed prompts to the orchestrator. `None` means the source is exhausted.
+pub trait VariantSource: Send {
+    fn next(&mut self) -> Option<Variant>;
+
+    /// Opaque position in the sequence, for checkpointing. `None` if the source can't be resumed.
+    fn position(&self) -> Option<u128> { None }
+
+    /// Jump back to a position returned by `position`.
+    fn seek(&mut self, _pos: u128) -> anyhow::Result<()> {
+        anyhow::bail!("this prompt source can't be resumed from a checkpoint")
+    }
+
+    /// How many variants the source yields before it's exhausted, if that's known.
+    fn total(&self) -> Option<u64> { None }
+}
+
+/// Build the variant source for a template.
+pub async fn variant_source(tpl: TemplateYaml, seed: u64) -> anyhow::Result<Box<dyn VariantSource>> {
+    if let Some(path) = &tpl.prompts_file {
+        return Ok(Box::new(FileSource::load(path, tpl.negative_prompt.as_deref()).await?));
+    }
+    let style = match tpl.mode {
+        Mode::AdTemplate(tpl) => PromptStyle::AdTemplate(PromptTemplate {
+            brand: tpl.brand,
+            product: tpl.product,
+            // plain lists keep the uniform draw (and the sequence a seed gave before weights existed)
+            style_weights: if tpl.styles.iter().any(|s| matches!(s, StyleEntry::Weighted { .. })) {
+                Some(WeightedIndex::new(tpl.styles.iter().map(StyleEntry::weight))
+                    .map_err(|e| anyhow::anyhow!("invalid style weights: {e}"))?)
+            } else {
+                None
+            },
+            styles: tpl.styles.iter().map(|s| s.value().to_string()).collect(),
+            audiences: tpl.audiences,
+            backgrounds: tpl.backgrounds,
+            ctas: tpl.ctas,
+        }),
+        Mode::GeneralPrompt(prompt) => PromptStyle::GeneralPrompt(PromptGeneral {
+            prompt: prompt.prompt,
+        }),
+    };
+    Ok(match (tpl.variant_mode, style) {
+        (VariantMode::Random, style) => Box::new(VariantGenerator::new(style, seed).with_negative_prompt(tpl.negative_prompt)),
+        (VariantMode::Unique, style) => Box::new(UniqueGenerator::new(VariantGenerator::new(style, seed).with_negative_prompt(tpl.negative_prompt))),
+        (VariantMode::Cartesian, PromptStyle::AdTemplate(t)) => Box::new(CartesianGenerator::new(t).with_negative_prompt(tpl.negative_prompt)),
+        (VariantMode::Cartesian, PromptStyle::GeneralPrompt(_)) => anyhow::bail!("variant_mode: cartesian needs an AdTemplate template"),
+    })
+}
 
 #[derive(Clone)]
 pub enum PromptStyle {
@@ -14,27 +80,242 @@ pub struct PromptTemplate {
     pub brand: String,
     pub product: String,
     pub styles: Vec<String>,
+    /// Draw weights for `styles` in random mode; `None` draws uniformly.
+    pub style_weights: Option<WeightedIndex<f32>>,
+    pub audiences: Vec<String>,
+    pub backgrounds: Vec<String>,
+    pub ctas: Vec<String>,
+}
+
+/// One pick from each of a template's lists (`None` where the list is empty).
+struct Choices { audience: Option<String>, style: String, background: Option<String>, cta: Option<String> }
+
+impl PromptTemplate {
+    /// Size of the product of the choice lists (an empty list counts as one).
+    pub fn combinations(&self) -> u64 {
+        [&self.audiences, &self.styles, &self.backgrounds, &self.ctas]
+            .iter()
+            .map(|l| l.len().max(1) as u64)
+            .product()
+    }
+
+    /// The prompt for one set of choices. Templates with only styles get the same
+    /// prompts as before the other lists existed.
+    fn variant(&self, c: Choices, negative_prompt: Option<String>) -> Variant {
+        let mut prompt = format!("An advertisement image for {} {}", self.brand, self.product);
+        if let Some(audience) = &c.audience { prompt.push_str(&format!(" targeting {audience}")); }
+        prompt.push_str(&format!(" in style: {}", c.style));
+        if let Some(background) = &c.background { prompt.push_str(&format!(", {background} background")); }
+        if let Some(cta) = &c.cta { prompt.push_str(&format!(", with call to action '{cta}'")); }
+        let meta = [("audience", c.audience), ("style", Some(c.style)), ("background", c.background), ("cta", c.cta)]
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v?)))
+            .collect();
+        Variant { prompt, meta, negative_prompt }
+    }
 }
 
+/// Used when a template lists no styles.
+const DEFAULT_STYLE: &str = "clean product photo";
+
 #[derive(Clone)]
-pub struct VariantGenerator { rng: StdRng, prompt_style: PromptStyle }
+pub struct VariantGenerator { rng: ChaCha12Rng, prompt_style: PromptStyle, negative_prompt: Option<String> }
 impl VariantGenerator {
     pub fn new(prompt_style: PromptStyle, seed: u64) -> Self {
-        Self { rng: StdRng::seed_from_u64(seed), prompt_style }
+        // same stream as `StdRng::seed_from_u64`, but with a seekable word position
+        Self { rng: ChaCha12Rng::seed_from_u64(seed), prompt_style, negative_prompt: None }
+    }
+    /// Attach the template's negative prompt to every variant.
+    pub fn with_negative_prompt(mut self, negative_prompt: Option<String>) -> Self {
+        self.negative_prompt = negative_prompt.filter(|n| !n.trim().is_empty());
+        self
     }
-    pub fn next(&mut self) -> String {
+    pub fn next_variant(&mut self) -> Variant {
         match self.prompt_style {
             PromptStyle::AdTemplate(ref tpl) => {
                 let s = if tpl.styles.is_empty() {
-                    "clean product photo".to_string()
+                    DEFAULT_STYLE.to_string()
+                } else if let Some(weights) = &tpl.style_weights {
+                    tpl.styles[weights.sample(&mut self.rng)].clone()
                 } else {
                     tpl.styles[self.rng.random_range(0..tpl.styles.len())].clone()
                 };
-                format!("An advertisement image for {} {} in style: {}", tpl.brand, tpl.product, s)
+                // drawn after the style (and only when listed) so style-only templates keep their sequence
+                let mut pick = |list: &[String]| (!list.is_empty()).then(|| list[self.rng.random_range(0..list.len())].clone());
+                let choices = Choices { audience: pick(&tpl.audiences), style: s, background: pick(&tpl.backgrounds), cta: pick(&tpl.ctas) };
+                tpl.variant(choices, self.negative_prompt.clone())
             }
             PromptStyle::GeneralPrompt(ref prompt) => {
-                prompt.prompt.clone()
+                Variant { prompt: prompt.prompt.clone(), meta: BTreeMap::new(), negative_prompt: self.negative_prompt.clone() }
             }
         }
     }
 }
+
+impl VariantSource for VariantGenerator {
+    fn next(&mut self) -> Option<Variant> {
+        Some(self.next_variant())
+    }
+
+    fn position(&self) -> Option<u128> { Some(self.rng.get_word_pos()) }
+
+    fn seek(&mut self, pos: u128) -> anyhow::Result<()> {
+        self.rng.set_word_pos(pos);
+        Ok(())
+    }
+}
+
+/// Every combination of an ad template's choices, once each, in a fixed order.
+pub struct CartesianGenerator { tpl: PromptTemplate, index: u64, negative_prompt: Option<String> }
+impl CartesianGenerator {
+    pub fn new(tpl: PromptTemplate) -> Self {
+        Self { tpl, index: 0, negative_prompt: None }
+    }
+    pub fn with_negative_prompt(mut self, negative_prompt: Option<String>) -> Self {
+        self.negative_prompt = negative_prompt.filter(|n| !n.trim().is_empty());
+        self
+    }
+    pub fn combinations(&self) -> u64 { self.tpl.combinations() }
+}
+
+impl VariantSource for CartesianGenerator {
+    fn next(&mut self) -> Option<Variant> {
+        if self.index >= self.combinations() { return None; }
+        // mixed radix: audience varies slowest, cta fastest
+        let mut rest = self.index;
+        self.index += 1;
+        let mut pick = |list: &[String]| {
+            let n = list.len().max(1) as u64;
+            let k = (rest % n) as usize;
+            rest /= n;
+            list.get(k).cloned()
+        };
+        let cta = pick(&self.tpl.ctas);
+        let background = pick(&self.tpl.backgrounds);
+        let style = pick(&self.tpl.styles).unwrap_or_else(|| DEFAULT_STYLE.to_string());
+        let audience = pick(&self.tpl.audiences);
+        Some(self.tpl.variant(Choices { audience, style, background, cta }, self.negative_prompt.clone()))
+    }
+
+    fn position(&self) -> Option<u128> { Some(self.index as u128) }
+
+    fn seek(&mut self, pos: u128) -> anyhow::Result<()> {
+        self.index = pos as u64;
+        Ok(())
+    }
+
+    fn total(&self) -> Option<u64> { Some(self.combinations()) }
+}
+
+const MAX_REDRAWS: usize = 100_000;
+
+/// Random draws with repeats skipped: redraws until the prompt is new and ends
+/// once every combination has been produced.
+pub struct UniqueGenerator { start: VariantGenerator, inner: VariantGenerator, seen: HashSet<String>, total: u64 }
+impl UniqueGenerator {
+    pub fn new(inner: VariantGenerator) -> Self {
+        // repeated list entries don't make new prompts
+        let distinct = |l: &[String]| l.iter().collect::<HashSet<_>>().len().max(1) as u64;
+        let total = match &inner.prompt_style {
+            PromptStyle::AdTemplate(t) => {
+                // styles weighted 0 are never drawn
+                let styles: Vec<String> = t.styles.iter().enumerate()
+                    .filter(|(i, _)| t.style_weights.as_ref().is_none_or(|w| w.weight(*i).is_some_and(|w| w > 0.0)))
+                    .map(|(_, s)| s.clone())
+                    .collect();
+                [&t.audiences, &styles, &t.backgrounds, &t.ctas].iter().map(|l| distinct(l)).product()
+            }
+            PromptStyle::GeneralPrompt(_) => 1,
+        };
+        Self { start: inner.clone(), inner, seen: HashSet::new(), total }
+    }
+}
+
+impl VariantSource for UniqueGenerator {
+    fn next(&mut self) -> Option<Variant> {
+        if self.seen.len() as u64 >= self.total { return None; }
+        // bounded in case different choices happen to render the same prompt
+        for _ in 0..MAX_REDRAWS {
+            let v = self.inner.next_variant();
+            if self.seen.insert(v.prompt.clone()) { return Some(v); }
+        }
+        None
+    }
+
+    fn position(&self) -> Option<u128> { self.inner.position() }
+
+    /// Replays the draws up to `pos` so the prompts already used are known again.
+    fn seek(&mut self, pos: u128) -> anyhow::Result<()> {
+        self.inner = self.start.clone();
+        self.seen.clear();
+        while self.inner.position().is_some_and(|p| p < pos) {
+            if self.next().is_none() { break; }
+        }
+        Ok(())
+    }
+
+    fn total(&self) -> Option<u64> { Some(self.total) }
+}
+
+/// Prompts read from `prompts_file`, yielded once each in file order. `.csv` files
+/// need a `prompt` column and `.jsonl` lines a `prompt` field; any other columns
+/// or fields are kept as variant metadata, and a `negative_prompt` one overrides
+/// the template's. Anything else is plain text, one prompt per non-blank line.
+pub struct FileSource { variants: Vec<Variant>, index: usize }
+impl FileSource {
+    pub async fn load(path: &Path, negative_prompt: Option<&str>) -> anyhow::Result<Self> {
+        let txt = tokio::fs::read_to_string(path).await.with_context(|| format!("reading prompts_file {}", path.display()))?;
+        let default_negative = negative_prompt.filter(|n| !n.trim().is_empty()).map(str::to_string);
+        let rows: Vec<BTreeMap<String, String>> = match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => {
+                let mut rdr = csv::Reader::from_reader(txt.as_bytes());
+                let headers = rdr.headers()?.clone();
+                if !headers.iter().any(|h| h == "prompt") {
+                    anyhow::bail!("prompts_file {} has no `prompt` column", path.display());
+                }
+                rdr.records()
+                    .map(|r| Ok(headers.iter().zip(r?.iter()).map(|(h, v)| (h.to_string(), v.to_string())).collect()))
+                    .collect::<anyhow::Result<_>>()?
+            }
+            Some("jsonl") => txt.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()).map(|(i, l)| {
+                let obj: serde_json::Map<String, serde_json::Value> = serde_json::from_str(l)
+                    .with_context(|| format!("prompts_file {} line {}", path.display(), i + 1))?;
+                Ok(obj.into_iter().filter_map(|(k, v)| match v {
+                    serde_json::Value::String(s) => Some((k, s)),
+                    serde_json::Value::Null => None,
+                    other => Some((k, other.to_string())),
+                }).collect())
+            }).collect::<anyhow::Result<_>>()?,
+            _ => txt.lines().map(str::trim).filter(|l| !l.is_empty())
+                .map(|l| BTreeMap::from([("prompt".to_string(), l.to_string())]))
+                .collect(),
+        };
+        let variants: Vec<Variant> = rows.into_iter().filter_map(|mut row| {
+            let prompt = row.remove("prompt").filter(|p| !p.trim().is_empty())?;
+            let negative_prompt = row.remove("negative_prompt").filter(|n| !n.trim().is_empty()).or_else(|| default_negative.clone());
+            row.retain(|_, v| !v.is_empty());
+            Some(Variant { prompt, meta: row, negative_prompt })
+        }).collect();
+        if variants.is_empty() {
+            anyhow::bail!("prompts_file {} has no prompts", path.display());
+        }
+        Ok(Self { variants, index: 0 })
+    }
+}
+
+impl VariantSource for FileSource {
+    fn next(&mut self) -> Option<Variant> {
+        let v = self.variants.get(self.index)?.clone();
+        self.index += 1;
+        Some(v)
+    }
+
+    fn position(&self) -> Option<u128> { Some(self.index as u128) }
+
+    fn seek(&mut self, pos: u128) -> anyhow::Result<()> {
+        self.index = pos as usize;
+        Ok(())
+    }
+
+    fn total(&self) -> Option<u64> { Some(self.variants.len() as u64) }
+}
@@ -1,8 +1,11 @@
 use anyhow::{Context, Result};
 use base64::Engine as _;
 use image::{ImageBuffer, Rgba};
-use rand::Rng;
-use std::{future::Future, pin::Pin};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+use tokio::sync::OnceCell;
+
+use crate::config::{AuthStyle, MockPattern};
 
 
 #[derive(Debug, Clone)]
@@ -14,6 +17,70 @@ pub struct ImageResult {
 
     pub prompt_used: String,
     pub model: String,
+    /// What the provider says this image cost, when its response reports it;
+    /// preferred over the configured `price_usd_per_image`.
+    pub cost_usd: Option<f64>,
+    /// The `provider.fallbacks` entry that served the image; `None` for the primary provider.
+    pub served_by: Option<ServedBy>,
+}
+
+/// Name and price of the fallback provider an image came from.
+#[derive(Debug, Clone)]
+pub struct ServedBy { pub provider: String, pub price_usd_per_image: f64 }
+
+/// Provider failures the orchestrator treats specially when retrying. Anything
+/// else is a plain `anyhow` error and retried with backoff.
+#[derive(Debug)]
+pub enum ProviderError {
+    /// 429: retry, after `retry_after` if the provider said how long.
+    RateLimited { provider: &'static str, retry_after: Option<Duration> },
+    /// Out of credits or quota: retrying won't help.
+    QuotaExceeded { provider: &'static str, message: String },
+    /// The provider's safety filter rejected the prompt up front; retrying won't help.
+    SafetyBlocked { provider: &'static str, reason: String },
+    /// The provider generated images but its moderation withheld every one; retrying won't help.
+    Moderated { provider: &'static str, reason: String },
+}
+
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderError::RateLimited { provider, .. } => write!(f, "{provider} rate limited"),
+            ProviderError::QuotaExceeded { provider, message } => write!(f, "{provider} quota exhausted: {message}"),
+            ProviderError::SafetyBlocked { provider, reason } => write!(f, "{provider} safety filter blocked the request: {reason}"),
+            ProviderError::Moderated { provider, reason } => write!(f, "{provider} moderation withheld the images: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl ProviderError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ProviderError::RateLimited { .. })
+    }
+}
+
+fn retry_after(resp: &reqwest::Response) -> Option<Duration> {
+    resp.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim().parse().ok().map(Duration::from_secs)
+}
+
+static NO_OPTS: GenOpts = GenOpts { negative_prompt: None, seed: None, init_image: None };
+
+/// Per-request options beyond the prompt. Providers ignore what they don't support.
+#[derive(Debug, Clone, Default)]
+pub struct GenOpts {
+    pub negative_prompt: Option<String>,
+    pub seed: Option<u64>,
+    pub init_image: Option<InitImage>,
+}
+
+/// Image-to-image input: the image to vary, loaded once per run and shared by every task.
+#[derive(Debug, Clone)]
+pub struct InitImage {
+    pub bytes: Arc<Vec<u8>>,
+    /// How far to move away from the image: 0 keeps it, 1 ignores it.
+    pub strength: f32,
 }
 
 pub trait ImageProvider: Send + Sync {
@@ -21,75 +88,334 @@ pub trait ImageProvider: Send + Sync {
         &'a self,
         prompt: &'a str,
     ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>>;
+    /// `generate` with options; the default ignores them.
+    fn generate_with_opts<'a>(
+        &'a self,
+        prompt: &'a str,
+        _opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        self.generate(prompt)
+    }
+    /// `n` images for one prompt. Providers that take an image count in a single
+    /// request override this; the default calls `generate_with_opts` `n` times,
+    /// with `seed`, `seed + 1`, ... so the images differ.
+    fn generate_batch<'a>(
+        &'a self,
+        prompt: &'a str,
+        n: u32,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<ImageResult>>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut out = Vec::with_capacity(n as usize);
+            for i in 0..n as u64 {
+                let opts = GenOpts { seed: opts.seed.map(|s| s.wrapping_add(i)), ..opts.clone() };
+                out.push(self.generate_with_opts(prompt, &opts).await?);
+            }
+            Ok(out)
+        })
+    }
+    /// Cheap pre-run check that the provider accepts our credentials, so a bad
+    /// key fails the run up front instead of on every image.
+    fn validate(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async { Ok(()) })
+    }
+    /// Whether `GenOpts::seed` makes this provider's output reproducible.
+    fn supports_seed(&self) -> bool { false }
+    /// Whether `GenOpts::init_image` is used (otherwise a run with one is refused).
+    fn supports_init_image(&self) -> bool { false }
     fn name(&self) -> &str;
-    fn model(&self) -> &str;
+    /// The requested model; `ImageResult::model` is what actually served the image.
     #[allow(dead_code)]
-
+    fn model(&self) -> &str;
+    /// Price of one image, used for cost tracking.
     fn price_usd_per_image(&self) -> f64 { 0.0 }
 }
 
+/// The ratio in `ratios` closest to `w`:`h` (on a log scale, so 2:1 and 1:2 are equally far from 1:1).
+fn closest_aspect_ratio(w: u32, h: u32, ratios: &[(u32, u32)]) -> (u32, u32) {
+    let want = w.max(1) as f64 / h.max(1) as f64;
+    ratios.iter()
+        .min_by(|x, y| {
+            let d = |&(a, b): &(u32, u32)| ((a as f64 / b as f64) / want).ln().abs();
+            d(x).total_cmp(&d(y))
+        })
+        .copied()
+        .unwrap_or((1, 1))
+}
+
+/// Read the real size from the image header (providers may round or ignore the
+/// requested size), warning when it differs from what was asked for.
+fn actual_dimensions(bytes: &[u8], req_w: u32, req_h: u32) -> Result<(u32, u32)> {
+    let (w, h) = image::ImageReader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_dimensions()
+        .context("provider returned an undecodable image")?;
+    if (w, h) != (req_w, req_h) {
+        tracing::warn!("provider returned {w}x{h}, requested {req_w}x{req_h}");
+    }
+    Ok((w, h))
+}
+
 #[derive(Clone)]
-pub struct MockProvider { pub model: String, pub w: u32, pub h: u32 }
+pub struct MockProvider { pub model: String, pub w: u32, pub h: u32, pub pattern: MockPattern, pub render_text: bool, pub seed: Option<u64> }
+
+/// Bundled so mock images and watermarks render the same everywhere (see `assets/fonts/LICENSE-DejaVu.txt`).
+pub(crate) static BUNDLED_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSansMono.ttf");
+
+/// FNV-1a, so a prompt maps to the same colours on every run.
+fn prompt_hash(prompt: &str) -> u64 {
+    prompt.bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// The prompt word-wrapped in dark text on a light background picked by the prompt.
+/// Lines that don't fit the height are cut, the last one ending in "...".
+fn render_prompt(prompt: &str, w: u32, h: u32) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    use ab_glyph::{FontRef, PxScale};
+    use imageproc::drawing::{draw_text_mut, text_size};
+
+    let font = FontRef::try_from_slice(BUNDLED_FONT).context("bundled font")?;
+    let [r, g, b, ..] = prompt_hash(prompt).to_le_bytes();
+    let mut img = ImageBuffer::from_pixel(w, h, Rgba([r / 4 + 180, g / 4 + 180, b / 4 + 180, 255]));
+    let scale = PxScale::from((w.min(h) as f32 / 16.0).max(8.0));
+    let margin = (w.min(h) / 20) as i32;
+    let max_w = w.saturating_sub(2 * margin as u32);
+    let line_h = (scale.y * 1.2).ceil() as i32;
+
+    let mut lines: Vec<String> = vec![];
+    for word in prompt.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if text_size(scale, &font, &format!("{line} {word}")).0 <= max_w => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    let fit = ((h as i32 - 2 * margin) / line_h).max(1) as usize;
+    if lines.len() > fit {
+        lines.truncate(fit);
+        if let Some(last) = lines.last_mut() { last.push_str("..."); }
+    }
+    for (i, line) in lines.iter().enumerate() {
+        draw_text_mut(&mut img, Rgba([20, 20, 20, 255]), margin, margin + i as i32 * line_h, scale, &font, line);
+    }
+    Ok(img)
+}
+
 impl ImageProvider for MockProvider {
     fn generate<'a>(
         &'a self,
         prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        self.generate_with_opts(prompt, &NO_OPTS)
+    }
+    fn generate_with_opts<'a>(
+        &'a self,
+        prompt: &'a str,
+        opts: &'a GenOpts,
     ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
         Box::pin(async move {
-            // Create a simple noise image
-            let mut rng = rand::rng();
-            let mut img: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(self.w, self.h);
-            for p in img.pixels_mut() {
-                *p = Rgba([rng.random::<u8>(), rng.random::<u8>(), rng.random::<u8>(), 255]);
+            // a pure function of prompt, provider seed and image seed: reruns are byte-identical
+            let seed = prompt_hash(prompt) ^ self.seed.unwrap_or(0) ^ opts.seed.unwrap_or(0).wrapping_mul(0x9e3779b97f4a7c15);
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut img: ImageBuffer<Rgba<u8>, Vec<u8>> = match self.pattern {
+                _ if self.render_text => render_prompt(prompt, self.w, self.h)?,
+                MockPattern::Noise => ImageBuffer::from_fn(self.w, self.h, |_, _| {
+                    Rgba([rng.random::<u8>(), rng.random::<u8>(), rng.random::<u8>(), 255])
+                }),
+                MockPattern::Fixed => ImageBuffer::from_fn(self.w, self.h, |x, y| {
+                    let v = if ((x + y) / 16) % 2 == 0 { 220 } else { 40 };
+                    Rgba([v, v, v, 255])
+                }),
+                MockPattern::Gradient => {
+                    let h = prompt_hash(prompt);
+                    let [r0, g0, b0, r1, g1, b1, dir, _] = h.to_le_bytes();
+                    let (w, ht) = (self.w.max(2) - 1, self.h.max(2) - 1);
+                    ImageBuffer::from_fn(self.w, self.h, |x, y| {
+                        let t = if dir % 2 == 0 { x as f32 / w as f32 } else { y as f32 / ht as f32 };
+                        let mut mix = |a: u8, b: u8| {
+                            let v = a as f32 + (b as f32 - a as f32) * t + rng.random_range(-4.0..=4.0);
+                            v.clamp(0.0, 255.0) as u8
+                        };
+                        Rgba([mix(r0, r1), mix(g0, g1), mix(b0, b1), 255])
+                    })
+                }
+            };
+            // image-to-image: the init image (resized to fit) with `strength` of the pattern over it
+            if let Some(init) = &opts.init_image {
+                let base = image::load_from_memory(&init.bytes).context("init image")?
+                    .resize_exact(self.w, self.h, image::imageops::FilterType::Triangle)
+                    .to_rgba8();
+                let t = init.strength.clamp(0.0, 1.0);
+                for (px, b) in img.pixels_mut().zip(base.pixels()) {
+                    for c in 0..3 {
+                        px[c] = (b[c] as f32 * (1.0 - t) + px[c] as f32 * t).round() as u8;
+                    }
+                }
             }
             let mut buf = Vec::new();
             let mut cursor = std::io::Cursor::new(&mut buf);
             img.write_to(&mut cursor, image::ImageFormat::Png)?;
-            Ok(ImageResult { bytes: buf, width: self.w, height: self.h, prompt_used: prompt.to_string(), model: self.model.clone() })
+            Ok(ImageResult { bytes: buf, width: self.w, height: self.h, prompt_used: prompt.to_string(), model: self.model.clone(), cost_usd: None, served_by: None })
         })
     }
+    fn supports_seed(&self) -> bool { true }
+    fn supports_init_image(&self) -> bool { true }
     fn name(&self) -> &str { "mock" }
     fn model(&self) -> &str { &self.model }
 }
 
 #[derive(Clone)]
-pub struct OpenAIProvider { pub client: reqwest::Client, pub model: String, pub api_key: String, pub w:u32, pub h:u32, pub price: f64 }
-impl ImageProvider for OpenAIProvider {
-    fn generate<'a>(
-        &'a self,
-        prompt: &'a str,
-    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
-        Box::pin(async move {
-            #[derive(serde::Serialize)] struct Req<'a>{prompt:&'a str, size:String, model:String, #[serde(skip_serializing_if="Option::is_none")] response_format:Option<&'a str>}
-            #[derive(serde::Deserialize)] struct Resp{data:Vec<Item>}
-            #[derive(serde::Deserialize)] struct Item{b64_json:Option<String>, url:Option<String>}
-            // `response_format` is only supported for DALL-E models.
-            // GPT image models always return base64 and reject this parameter.
-            let response_format = if self.model.starts_with("dall-e-") {
-                Some("b64_json")
-            } else {
-                None
-            };
-            let req = Req{
-                prompt,
-                size: format!("{}x{}", self.w, self.h),
-                model:self.model.clone(),
-                response_format,
-            };
-            let resp = self.client.post("https://api.openai.com/v1/images/generations")
-                .bearer_auth(&self.api_key)
-                .json(&req)
-                .send().await?;
-            if !resp.status().is_success() {
-                let status = resp.status();
-                let body = resp.text().await.unwrap_or_default();
-                anyhow::bail!("OpenAI API error {status}: {body}");
+pub struct OpenAIProvider { pub client: reqwest::Client, pub model: String, pub api_keys: ApiKeys, pub w:u32, pub h:u32, pub price: f64, pub endpoint: OpenAIEndpoint, pub quality: Option<String>, pub style: Option<String> }
+
+/// One or more API keys, used round-robin one request at a time.
+#[derive(Clone)]
+pub struct ApiKeys { keys: Vec<String>, next: Arc<std::sync::atomic::AtomicUsize> }
+
+impl ApiKeys {
+    pub fn new(keys: Vec<String>) -> Result<Self> {
+        anyhow::ensure!(!keys.is_empty(), "at least one API key is required");
+        Ok(Self { keys, next: Default::default() })
+    }
+    fn next(&self) -> &str {
+        let i = self.next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        &self.keys[i % self.keys.len()]
+    }
+}
+
+/// Where `OpenAIProvider` sends requests; the default is api.openai.com.
+#[derive(Clone)]
+pub struct OpenAIEndpoint { pub base_url: String, pub api_version: Option<String>, pub auth: AuthStyle }
+
+impl Default for OpenAIEndpoint {
+    fn default() -> Self {
+        Self { base_url: "https://api.openai.com/v1".into(), api_version: None, auth: AuthStyle::Bearer }
+    }
+}
+
+impl OpenAIEndpoint {
+    fn post(&self, client: &reqwest::Client, path: &str, api_key: &str) -> reqwest::RequestBuilder {
+        self.authed(client.post(self.url(path)), api_key)
+    }
+    fn get(&self, client: &reqwest::Client, path: &str, api_key: &str) -> reqwest::RequestBuilder {
+        self.authed(client.get(self.url(path)), api_key)
+    }
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), path)
+    }
+    fn authed(&self, mut req: reqwest::RequestBuilder, api_key: &str) -> reqwest::RequestBuilder {
+        if let Some(v) = &self.api_version {
+            req = req.query(&[("api-version", v)]);
+        }
+        match self.auth {
+            AuthStyle::Bearer => req.bearer_auth(api_key),
+            AuthStyle::ApiKey => req.header("api-key", api_key),
+            AuthStyle::None => req,
+        }
+    }
+}
+/// Token usage reported by token-billed image models (gpt-image-1).
+#[derive(serde::Deserialize)]
+struct Usage { #[serde(default)] input_tokens: u64, #[serde(default)] output_tokens: u64 }
+
+/// USD for `usage` at list price, for models whose per-token prices we know.
+/// A generation call's input is all text tokens.
+fn usage_cost_usd(model: &str, usage: &Usage) -> Option<f64> {
+    let (input_per_m, output_per_m) = match model {
+        "gpt-image-1" => (5.0, 40.0),
+        m if m.starts_with("gpt-image-1-20") => (5.0, 40.0),
+        _ => return None,
+    };
+    Some((usage.input_tokens as f64 * input_per_m + usage.output_tokens as f64 * output_per_m) / 1_000_000.0)
+}
+
+impl OpenAIProvider {
+    /// Reject `quality`/`style` values the model doesn't accept, before any request is made.
+    /// Models this doesn't know are passed through unchecked.
+    pub fn check_options(model: &str, quality: Option<&str>, style: Option<&str>) -> Result<()> {
+        let (qualities, styles): (&[&str], &[&str]) = if model.starts_with("dall-e-3") {
+            (&["standard", "hd"], &["vivid", "natural"])
+        } else if model.starts_with("gpt-image-") {
+            (&["low", "medium", "high", "auto"], &[])
+        } else if model.starts_with("dall-e-2") {
+            (&[], &[])
+        } else {
+            return Ok(());
+        };
+        for (field, value, allowed) in [("quality", quality, qualities), ("style", style, styles)] {
+            let Some(value) = value else { continue };
+            if allowed.is_empty() {
+                anyhow::bail!("provider.{field} is not supported by {model}");
+            }
+            if !allowed.contains(&value) {
+                anyhow::bail!("provider.{field} `{value}` is not valid for {model} (expected one of: {})", allowed.join(", "));
+            }
+        }
+        Ok(())
+    }
+
+    /// List price per image for dall-e-3, which depends on quality and size.
+    pub fn list_price(model: &str, quality: Option<&str>, w: u32, h: u32) -> Option<f64> {
+        if !model.starts_with("dall-e-3") { return None; }
+        let square = w == h;
+        Some(match (quality == Some("hd"), square) {
+            (false, true) => 0.04,
+            (false, false) | (true, true) => 0.08,
+            (true, false) => 0.12,
+        })
+    }
+
+    async fn request(&self, prompt: &str, n: u32, opts: &GenOpts) -> Result<Vec<ImageResult>> {
+        #[derive(serde::Serialize)] struct Req<'a>{prompt:&'a str, size:String, model:String, #[serde(skip_serializing_if="Option::is_none")] n:Option<u32>, #[serde(skip_serializing_if="Option::is_none")] response_format:Option<&'a str>, #[serde(skip_serializing_if="Option::is_none")] quality:Option<&'a str>, #[serde(skip_serializing_if="Option::is_none")] style:Option<&'a str>}
+        #[derive(serde::Deserialize)] struct Resp{data:Vec<Item>, #[serde(default)] model:Option<String>, #[serde(default)] usage:Option<Usage>}
+        #[derive(serde::Deserialize)] struct Item{b64_json:Option<String>, url:Option<String>}
+        // `response_format` is only supported for DALL-E models.
+        // GPT image models always return base64 and reject this parameter.
+        let response_format = if self.model.starts_with("dall-e-") {
+            Some("b64_json")
+        } else {
+            None
+        };
+        // the Images API has no negative prompt parameter, so it goes in the prompt text
+        let prompt = match &opts.negative_prompt {
+            Some(neg) => format!("{prompt}\n\nAvoid: {neg}"),
+            None => prompt.to_string(),
+        };
+        let req = Req{
+            prompt: &prompt,
+            size: format!("{}x{}", self.w, self.h),
+            model:self.model.clone(),
+            n: (n > 1).then_some(n),
+            response_format,
+            quality: self.quality.as_deref(),
+            style: self.style.as_deref(),
+        };
+        let resp = self.endpoint.post(&self.client, "images/generations", self.api_keys.next())
+            .json(&req)
+            .send().await?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            if status == reqwest::StatusCode::BAD_REQUEST && body.contains("content_policy_violation") {
+                return Err(ProviderError::SafetyBlocked { provider: "openai", reason: body }.into());
             }
-            let parsed = resp.json::<Resp>().await?;
-            let first = parsed.data.get(0).context("OpenAI API returned no image data")?;
-            let bytes = if let Some(b64) = &first.b64_json {
+            anyhow::bail!("OpenAI API error {status}: {body}");
+        }
+        let parsed = resp.json::<Resp>().await?;
+        if parsed.data.is_empty() {
+            anyhow::bail!("OpenAI API returned no image data");
+        }
+        // prefer the model the API says served the request (aliases/snapshots can differ)
+        let model = parsed.model.filter(|m| !m.is_empty()).unwrap_or_else(|| self.model.clone());
+        // token-billed models report usage for the whole call; split it evenly
+        let cost_usd = parsed.usage.as_ref()
+            .and_then(|u| usage_cost_usd(&model, u))
+            .map(|c| c / parsed.data.len() as f64);
+        let mut out = Vec::with_capacity(parsed.data.len());
+        for item in &parsed.data {
+            let bytes = if let Some(b64) = &item.b64_json {
                 base64::engine::general_purpose::STANDARD.decode(b64)?
-            } else if let Some(url) = &first.url {
+            } else if let Some(url) = &item.url {
                 self.client
                     .get(url)
                     .send()
@@ -101,42 +427,740 @@ impl ImageProvider for OpenAIProvider {
             } else {
                 anyhow::bail!("OpenAI API returned image item without b64_json or url");
             };
-            Ok(ImageResult{bytes, width:self.w, height:self.h, prompt_used:prompt.to_string(), model:self.model.clone()})
+            let (width, height) = actual_dimensions(&bytes, self.w, self.h)?;
+            out.push(ImageResult{bytes, width, height, prompt_used:prompt.clone(), model:model.clone(), cost_usd, served_by: None});
+        }
+        Ok(out)
+    }
+}
+
+impl ImageProvider for OpenAIProvider {
+    fn generate<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        self.generate_with_opts(prompt, &NO_OPTS)
+    }
+    fn generate_with_opts<'a>(
+        &'a self,
+        prompt: &'a str,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut images = self.request(prompt, 1, opts).await?;
+            Ok(images.swap_remove(0))
+        })
+    }
+    fn generate_batch<'a>(
+        &'a self,
+        prompt: &'a str,
+        n: u32,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<ImageResult>>> + Send + 'a>> {
+        Box::pin(self.request(prompt, n, opts))
+    }
+    /// Lists models: free, and rejected with 401/403 for a bad key. Anything else
+    /// (an endpoint without `/models`, a network blip) is left for the run to surface.
+    fn validate(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            for (i, key) in self.api_keys.keys.iter().enumerate() {
+                let resp = match self.endpoint.get(&self.client, "models", key).send().await {
+                    Ok(r) => r,
+                    Err(e) => {
+                        tracing::warn!("could not check OpenAI credentials: {e:#}");
+                        return Ok(());
+                    }
+                };
+                let status = resp.status();
+                if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+                    let body = resp.text().await.unwrap_or_default();
+                    let which = if self.api_keys.keys.len() > 1 { format!(" #{}", i + 1) } else { String::new() };
+                    anyhow::bail!("OpenAI rejected the API key{which} ({status}): {body}");
+                }
+            }
+            Ok(())
         })
     }
     fn name(&self) -> &str { "openai" }
     fn model(&self) -> &str { &self.model }
     fn price_usd_per_image(&self) -> f64 { self.price }
 }
-//Double check this endpoint and request format
+/// Google Imagen through the Gemini API (`models/{model}:predict`), keyed with an API key.
 #[derive(Clone)]
-pub struct GeminiProvider { pub client: reqwest::Client, pub model: String, pub api_key: String, pub w:u32, pub h:u32, pub price: f64 }
-impl ImageProvider for GeminiProvider {
+pub struct ImagenProvider { pub client: reqwest::Client, pub model: String, pub api_key: String, pub w:u32, pub h:u32, pub price: f64 }
+
+impl ImagenProvider {
+    const ASPECT_RATIOS: [(u32, u32); 5] = [(16, 9), (4, 3), (1, 1), (3, 4), (9, 16)];
+
+    async fn request(&self, prompt: &str, n: u32) -> Result<Vec<ImageResult>> {
+        #[derive(serde::Serialize)] struct Instance<'a>{prompt:&'a str}
+        #[derive(serde::Serialize)] #[serde(rename_all = "camelCase")] struct Params{sample_count:u32, aspect_ratio:String, include_rai_reason:bool}
+        #[derive(serde::Serialize)] struct Req<'a>{instances:[Instance<'a>; 1], parameters:Params}
+        #[derive(serde::Deserialize)] struct Resp{#[serde(default)] predictions:Vec<Prediction>}
+        #[derive(serde::Deserialize)] #[serde(rename_all = "camelCase")] struct Prediction{#[serde(default)] bytes_base64_encoded:Option<String>, #[serde(default)] rai_filtered_reason:Option<String>}
+
+        let (a, b) = closest_aspect_ratio(self.w, self.h, &Self::ASPECT_RATIOS);
+        let req = Req{instances:[Instance{prompt}], parameters:Params{sample_count:n, aspect_ratio:format!("{a}:{b}"), include_rai_reason:true}};
+        let resp = self.client.post(format!("https://generativelanguage.googleapis.com/v1beta/models/{}:predict", self.model))
+            .header("x-goog-api-key", &self.api_key)
+            .json(&req)
+            .send().await?;
+        let status = resp.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(ProviderError::RateLimited { provider: "imagen", retry_after: retry_after(&resp) }.into());
+        }
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            // a prompt rejected up front comes back as a 400 citing Google's Responsible AI practices
+            if status == reqwest::StatusCode::BAD_REQUEST && body.contains("Responsible AI") {
+                return Err(ProviderError::SafetyBlocked { provider: "imagen", reason: body }.into());
+            }
+            anyhow::bail!("Imagen API error {status}: {body}");
+        }
+        let parsed = resp.json::<Resp>().await?;
+        let mut out = Vec::with_capacity(parsed.predictions.len());
+        let mut filtered = vec![];
+        for p in parsed.predictions {
+            match (p.bytes_base64_encoded, p.rai_filtered_reason) {
+                (Some(b64), _) => {
+                    let bytes = base64::engine::general_purpose::STANDARD.decode(b64)?;
+                    let (width, height) = actual_dimensions(&bytes, self.w, self.h)?;
+                    out.push(ImageResult{bytes, width, height, prompt_used:prompt.to_string(), model:self.model.clone(), cost_usd: None, served_by: None});
+                }
+                (None, reason) => filtered.push(reason.unwrap_or_else(|| "no reason given".into())),
+            }
+        }
+        // filtered images are simply left out; only an all-filtered response is an error
+        if out.is_empty() {
+            if filtered.is_empty() { anyhow::bail!("Imagen API returned no images"); }
+            return Err(ProviderError::Moderated { provider: "imagen", reason: filtered.join("; ") }.into());
+        }
+        Ok(out)
+    }
+}
+
+impl ImageProvider for ImagenProvider {
     fn generate<'a>(
         &'a self,
         prompt: &'a str,
     ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
         Box::pin(async move {
-            #[derive(serde::Serialize)] struct Req<'a>{prompt:&'a str, size:String, model:String, #[serde(skip_serializing_if="Option::is_none")] response_format:Option<&'a str>}
-            #[derive(serde::Deserialize)] struct Resp{data:Vec<Item>}
-            #[derive(serde::Deserialize)] struct Item{b64_json:String}
-            let needs_response_format = self.model.starts_with("gemini-3-pro-image-preview");
-            let req = Req{prompt, size: format!("{}x{}", self.w, self.h), model:self.model.clone(), response_format: if needs_response_format { Some("b64_json") } else { None }};
-            let resp = self.client.post("https://gemini.googleapis.com/v1/images/generations")
+            let mut images = self.request(prompt, 1).await?;
+            Ok(images.swap_remove(0))
+        })
+    }
+    fn generate_batch<'a>(
+        &'a self,
+        prompt: &'a str,
+        n: u32,
+        _opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<ImageResult>>> + Send + 'a>> {
+        Box::pin(self.request(prompt, n))
+    }
+    fn name(&self) -> &str { "imagen" }
+    fn model(&self) -> &str { &self.model }
+    fn price_usd_per_image(&self) -> f64 { self.price }
+}
+
+/// Flux and other models on fal.ai, through the queue API: submit, poll the
+/// status URL until completed, then fetch the result and download the image.
+#[derive(Clone)]
+pub struct FalProvider { pub client: reqwest::Client, pub model: String, pub api_key: String, pub w:u32, pub h:u32, pub price: f64 }
+
+impl FalProvider {
+    const POLL_EVERY: Duration = Duration::from_millis(500);
+    const MAX_WAIT: Duration = Duration::from_secs(300);
+
+    async fn send(&self, req: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let resp = req.header(reqwest::header::AUTHORIZATION, format!("Key {}", self.api_key)).send().await?;
+        let status = resp.status();
+        if status.is_success() { return Ok(resp); }
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(ProviderError::RateLimited { provider: "fal", retry_after: retry_after(&resp) }.into());
+        }
+        let body = resp.text().await.unwrap_or_default();
+        if status == reqwest::StatusCode::PAYMENT_REQUIRED || body.contains("balance") {
+            return Err(ProviderError::QuotaExceeded { provider: "fal", message: body }.into());
+        }
+        anyhow::bail!("fal API error {status}: {body}");
+    }
+}
+
+impl ImageProvider for FalProvider {
+    fn generate<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        self.generate_with_opts(prompt, &NO_OPTS)
+    }
+    fn generate_with_opts<'a>(
+        &'a self,
+        prompt: &'a str,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        Box::pin(async move {
+            #[derive(serde::Serialize)] struct Size{width:u32, height:u32}
+            #[derive(serde::Serialize)] struct Req<'a>{prompt:&'a str, image_size:Size, num_images:u32, #[serde(skip_serializing_if="Option::is_none")] seed:Option<u64>}
+            #[derive(serde::Deserialize)] struct Queued{status_url:String, response_url:String}
+            #[derive(serde::Deserialize)] struct Status{status:String}
+            #[derive(serde::Deserialize)] struct Resp{images:Vec<Item>}
+            #[derive(serde::Deserialize)] struct Item{url:String}
+
+            let queued: Queued = self.send(self.client.post(format!("https://queue.fal.run/{}", self.model))
+                .json(&Req{prompt, image_size: Size{width:self.w, height:self.h}, num_images:1, seed:opts.seed}))
+                .await?.json().await?;
+
+            let started = std::time::Instant::now();
+            loop {
+                let st: Status = self.send(self.client.get(&queued.status_url)).await?.json().await?;
+                match st.status.as_str() {
+                    "COMPLETED" => break,
+                    "IN_QUEUE" | "IN_PROGRESS" => {}
+                    other => anyhow::bail!("fal request ended with status {other}"),
+                }
+                if started.elapsed() > Self::MAX_WAIT {
+                    anyhow::bail!("fal request still pending after {}s", Self::MAX_WAIT.as_secs());
+                }
+                tokio::time::sleep(Self::POLL_EVERY).await;
+            }
+
+            let parsed: Resp = self.send(self.client.get(&queued.response_url)).await?.json().await?;
+            let url = &parsed.images.first().context("fal returned no images")?.url;
+            let bytes = self.client.get(url).send().await?.error_for_status()?.bytes().await?.to_vec();
+            let (width, height) = actual_dimensions(&bytes, self.w, self.h)?;
+            Ok(ImageResult{bytes, width, height, prompt_used:prompt.to_string(), model:self.model.clone(), cost_usd: None, served_by: None})
+        })
+    }
+    fn name(&self) -> &str { "fal" }
+    fn supports_seed(&self) -> bool { true }
+    fn model(&self) -> &str { &self.model }
+    fn price_usd_per_image(&self) -> f64 { self.price }
+}
+
+/// Stable Diffusion through Stability AI's `v2beta/stable-image/generate` API.
+/// `model` is `core`, `ultra`, or an SD3 model id such as `sd3.5-large`. The API
+/// takes an aspect ratio rather than a size, so the closest supported one is sent.
+#[derive(Clone)]
+pub struct StabilityProvider { pub client: reqwest::Client, pub model: String, pub api_key: String, pub w:u32, pub h:u32, pub price: f64 }
+
+impl StabilityProvider {
+    const ASPECT_RATIOS: [(u32, u32); 9] = [(21, 9), (16, 9), (3, 2), (5, 4), (1, 1), (4, 5), (2, 3), (9, 16), (9, 21)];
+
+    fn aspect_ratio(&self) -> String {
+        let (a, b) = closest_aspect_ratio(self.w, self.h, &Self::ASPECT_RATIOS);
+        format!("{a}:{b}")
+    }
+
+    /// Endpoint path segment plus the `model` form field, if the endpoint takes one.
+    fn endpoint(&self) -> (&str, Option<&str>) {
+        match self.model.as_str() {
+            m @ ("core" | "ultra") => (m, None),
+            m => ("sd3", Some(m)),
+        }
+    }
+}
+
+impl ImageProvider for StabilityProvider {
+    fn generate<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        self.generate_with_opts(prompt, &NO_OPTS)
+    }
+    fn generate_with_opts<'a>(
+        &'a self,
+        prompt: &'a str,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        Box::pin(async move {
+            let (path, model_field) = self.endpoint();
+            let mut form = reqwest::multipart::Form::new()
+                .text("prompt", prompt.to_string())
+                .text("output_format", "png");
+            if let Some(m) = model_field {
+                form = form.text("model", m.to_string());
+            }
+            // image-to-image keeps the init image's aspect ratio; core can't do it at all
+            match &opts.init_image {
+                Some(init) => {
+                    if path == "core" {
+                        anyhow::bail!("stability model `core` doesn't take an init image; use `ultra` or an SD3 model");
+                    }
+                    if path == "sd3" {
+                        form = form.text("mode", "image-to-image");
+                    }
+                    let part = reqwest::multipart::Part::bytes(init.bytes.to_vec()).file_name("init.png").mime_str("image/png")?;
+                    form = form.part("image", part).text("strength", init.strength.to_string());
+                }
+                None => form = form.text("aspect_ratio", self.aspect_ratio()),
+            }
+            if let Some(neg) = &opts.negative_prompt {
+                form = form.text("negative_prompt", neg.clone());
+            }
+            if let Some(seed) = opts.seed {
+                // the API takes seeds in 0..=4294967294
+                form = form.text("seed", (seed % 4_294_967_295).to_string());
+            }
+            let resp = self.client.post(format!("https://api.stability.ai/v2beta/stable-image/generate/{path}"))
                 .bearer_auth(&self.api_key)
-                .json(&req)
+                .header(reqwest::header::ACCEPT, "image/*")
+                .multipart(form)
                 .send().await?;
-            if !resp.status().is_success() {
-                let status = resp.status();
+            let status = resp.status();
+            if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Err(ProviderError::RateLimited { provider: "stability", retry_after: retry_after(&resp) }.into());
+            }
+            if !status.is_success() {
                 let body = resp.text().await.unwrap_or_default();
-                anyhow::bail!("Gemini API error {status}: {body}");
+                if status == reqwest::StatusCode::PAYMENT_REQUIRED {
+                    return Err(ProviderError::QuotaExceeded { provider: "stability", message: body }.into());
+                }
+                anyhow::bail!("Stability API error {status}: {body}");
             }
-            let parsed = resp.json::<Resp>().await?;
-            let bytes = base64::engine::general_purpose::STANDARD.decode(&parsed.data[0].b64_json)?;
-            Ok(ImageResult{bytes, width:self.w, height:self.h, prompt_used:prompt.to_string(), model:self.model.clone()})
+            if let Some(reason) = resp.headers().get("finish-reason").and_then(|v| v.to_str().ok()) {
+                if reason == "CONTENT_FILTERED" {
+                    return Err(ProviderError::Moderated { provider: "stability", reason: reason.to_string() }.into());
+                }
+                if reason != "SUCCESS" {
+                    anyhow::bail!("Stability finished with {reason}");
+                }
+            }
+            let bytes = resp.bytes().await?.to_vec();
+            let (width, height) = actual_dimensions(&bytes, self.w, self.h)?;
+            Ok(ImageResult{bytes, width, height, prompt_used:prompt.to_string(), model:self.model.clone(), cost_usd: None, served_by: None})
         })
     }
-    fn name(&self) -> &str { "gemini" }
+    fn name(&self) -> &str { "stability" }
+    fn supports_seed(&self) -> bool { true }
+    fn supports_init_image(&self) -> bool { true }
     fn model(&self) -> &str { &self.model }
     fn price_usd_per_image(&self) -> f64 { self.price }
 }
+
+/// Any model on Replicate: create a prediction, poll `urls.get` until it settles,
+/// then download the first output. `model` is a version id, `owner/name:version`,
+/// or `owner/name` for the model's latest version.
+#[derive(Clone)]
+pub struct ReplicateProvider { pub client: reqwest::Client, pub model: String, pub api_key: String, pub w:u32, pub h:u32, pub price: f64, pub poll_every: Duration, pub max_wait: Duration, pub init_image_url: Arc<OnceCell<String>> }
+
+impl ReplicateProvider {
+    async fn send(&self, req: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let resp = req.bearer_auth(&self.api_key).send().await?;
+        let status = resp.status();
+        if status.is_success() { return Ok(resp); }
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(ProviderError::RateLimited { provider: "replicate", retry_after: retry_after(&resp) }.into());
+        }
+        let body = resp.text().await.unwrap_or_default();
+        if status == reqwest::StatusCode::PAYMENT_REQUIRED {
+            return Err(ProviderError::QuotaExceeded { provider: "replicate", message: body }.into());
+        }
+        anyhow::bail!("Replicate API error {status}: {body}");
+    }
+
+    /// Upload the init image once (multipart, to the files API) and reuse its URL
+    /// for every prediction. A run has a single init image.
+    async fn init_image_url(&self, init: &InitImage) -> Result<&str> {
+        #[derive(serde::Deserialize)] struct Urls{get:String}
+        #[derive(serde::Deserialize)] struct File{urls:Urls}
+        let url = self.init_image_url.get_or_try_init(|| async {
+            let part = reqwest::multipart::Part::bytes(init.bytes.to_vec()).file_name("init.png").mime_str("image/png")?;
+            let form = reqwest::multipart::Form::new().part("content", part);
+            let file: File = self.send(self.client.post("https://api.replicate.com/v1/files").multipart(form)).await?.json().await?;
+            anyhow::Ok(file.urls.get)
+        }).await?;
+        Ok(url)
+    }
+}
+
+impl ImageProvider for ReplicateProvider {
+    fn generate<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        self.generate_with_opts(prompt, &NO_OPTS)
+    }
+    fn generate_with_opts<'a>(
+        &'a self,
+        prompt: &'a str,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        Box::pin(async move {
+            #[derive(serde::Serialize)] struct Input<'a>{prompt:&'a str, width:u32, height:u32, #[serde(skip_serializing_if="Option::is_none")] negative_prompt:Option<&'a str>, #[serde(skip_serializing_if="Option::is_none")] seed:Option<u64>, #[serde(skip_serializing_if="Option::is_none")] image:Option<&'a str>, #[serde(skip_serializing_if="Option::is_none")] prompt_strength:Option<f32>}
+            #[derive(serde::Serialize)] struct Req<'a>{#[serde(skip_serializing_if="Option::is_none")] version:Option<&'a str>, input:Input<'a>}
+            #[derive(serde::Deserialize)] struct Urls{get:String, #[serde(default)] cancel:Option<String>}
+            #[derive(serde::Deserialize)] struct Prediction{status:String, urls:Urls, #[serde(default)] output:serde_json::Value, #[serde(default)] error:Option<serde_json::Value>}
+
+            let (url, version) = match self.model.split_once(':') {
+                Some((_, version)) => ("https://api.replicate.com/v1/predictions".to_string(), Some(version)),
+                None if self.model.contains('/') => (format!("https://api.replicate.com/v1/models/{}/predictions", self.model), None),
+                None => ("https://api.replicate.com/v1/predictions".to_string(), Some(self.model.as_str())),
+            };
+            let image = match &opts.init_image {
+                Some(init) => Some(self.init_image_url(init).await?),
+                None => None,
+            };
+            let input = Input{prompt, width:self.w, height:self.h, negative_prompt: opts.negative_prompt.as_deref(), seed: opts.seed, image, prompt_strength: opts.init_image.as_ref().map(|i| i.strength)};
+            let mut pred: Prediction = self.send(self.client.post(url).json(&Req{version, input})).await?.json().await?;
+
+            let started = std::time::Instant::now();
+            loop {
+                match pred.status.as_str() {
+                    "succeeded" => break,
+                    "starting" | "processing" => {}
+                    other => anyhow::bail!("Replicate prediction {other}: {}", pred.error.as_ref().map_or_else(String::new, |e| e.to_string())),
+                }
+                if started.elapsed() > self.max_wait {
+                    if let Some(cancel) = &pred.urls.cancel {
+                        let _ = self.send(self.client.post(cancel)).await;
+                    }
+                    anyhow::bail!("Replicate prediction still {} after {}s; cancelled", pred.status, self.max_wait.as_secs());
+                }
+                tokio::time::sleep(self.poll_every).await;
+                pred = self.send(self.client.get(&pred.urls.get)).await?.json().await?;
+            }
+
+            // `output` is a URL or a list of them, depending on the model
+            let url = match &pred.output {
+                serde_json::Value::String(u) => Some(u.as_str()),
+                serde_json::Value::Array(items) => items.first().and_then(|v| v.as_str()),
+                _ => None,
+            }.context("Replicate prediction succeeded without an output URL")?;
+            let bytes = self.client.get(url).send().await?.error_for_status()?.bytes().await?.to_vec();
+            let (width, height) = actual_dimensions(&bytes, self.w, self.h)?;
+            Ok(ImageResult{bytes, width, height, prompt_used:prompt.to_string(), model:self.model.clone(), cost_usd: None, served_by: None})
+        })
+    }
+    fn name(&self) -> &str { "replicate" }
+    fn supports_seed(&self) -> bool { true }
+    fn supports_init_image(&self) -> bool { true }
+    fn model(&self) -> &str { &self.model }
+    fn price_usd_per_image(&self) -> f64 { self.price }
+}
+
+type InflightCell = Arc<OnceCell<Result<ImageResult, String>>>;
+
+/// Shares one provider call between concurrent identical requests; only the
+/// caller that made it is charged. Entries live only while the call is in
+/// flight; later requests call again.
+pub struct CoalescingProvider {
+    inner: Arc<dyn ImageProvider>,
+    inflight: parking_lot::Mutex<HashMap<String, InflightCell>>,
+}
+impl CoalescingProvider {
+    pub fn new(inner: Arc<dyn ImageProvider>) -> Self {
+        Self { inner, inflight: parking_lot::Mutex::new(HashMap::new()) }
+    }
+
+    /// Requests share a call only if they'd send the same thing: prompt,
+    /// negative prompt, seed and init image.
+    fn key(prompt: &str, opts: &GenOpts) -> String {
+        use sha2::{Digest, Sha256};
+        let init = opts.init_image.as_ref().map(|i| format!("{:x}@{}", Sha256::digest(&*i.bytes), i.strength));
+        let mut key = prompt.to_string();
+        for part in [opts.negative_prompt.clone(), opts.seed.map(|s| s.to_string()), init] {
+            key.push('\0');
+            key.push_str(part.as_deref().unwrap_or(""));
+        }
+        key
+    }
+}
+impl ImageProvider for CoalescingProvider {
+    fn generate<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        self.generate_with_opts(prompt, &NO_OPTS)
+    }
+    fn generate_with_opts<'a>(
+        &'a self,
+        prompt: &'a str,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        Box::pin(async move {
+            let key = Self::key(prompt, opts);
+            let cell = self.inflight.lock().entry(key.clone()).or_default().clone();
+            let called = std::sync::atomic::AtomicBool::new(false);
+            let res = cell
+                .get_or_init(|| async {
+                    called.store(true, std::sync::atomic::Ordering::Relaxed);
+                    let r = self.inner.generate_with_opts(prompt, opts).await.map_err(|e| format!("{e:#}"));
+                    self.inflight.lock().remove(&key);
+                    r
+                })
+                .await
+                .clone();
+            let mut res = res.map_err(|e| anyhow::anyhow!(e))?;
+            // the caller that made the call pays for it
+            if !called.load(std::sync::atomic::Ordering::Relaxed) { res.cost_usd = Some(0.0); }
+            Ok(res)
+        })
+    }
+    /// Batches aren't coalesced.
+    fn generate_batch<'a>(
+        &'a self,
+        prompt: &'a str,
+        n: u32,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<ImageResult>>> + Send + 'a>> {
+        self.inner.generate_batch(prompt, n, opts)
+    }
+    fn validate(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> { self.inner.validate() }
+    fn name(&self) -> &str { self.inner.name() }
+    fn supports_seed(&self) -> bool { self.inner.supports_seed() }
+    fn supports_init_image(&self) -> bool { self.inner.supports_init_image() }
+    fn model(&self) -> &str { self.inner.model() }
+    fn price_usd_per_image(&self) -> f64 { self.inner.price_usd_per_image() }
+}
+
+/// Holds a permit from a semaphore shared by every provider with the same name
+/// for the length of each call (`orchestrator.provider_concurrency`).
+pub struct LimitedProvider {
+    inner: Arc<dyn ImageProvider>,
+    permits: Arc<tokio::sync::Semaphore>,
+}
+impl LimitedProvider {
+    pub fn new(inner: Arc<dyn ImageProvider>, permits: Arc<tokio::sync::Semaphore>) -> Self {
+        Self { inner, permits }
+    }
+}
+impl ImageProvider for LimitedProvider {
+    fn generate<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        self.generate_with_opts(prompt, &NO_OPTS)
+    }
+    fn generate_with_opts<'a>(
+        &'a self,
+        prompt: &'a str,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        Box::pin(async move {
+            let _permit = self.permits.acquire().await?;
+            self.inner.generate_with_opts(prompt, opts).await
+        })
+    }
+    fn generate_batch<'a>(
+        &'a self,
+        prompt: &'a str,
+        n: u32,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<ImageResult>>> + Send + 'a>> {
+        Box::pin(async move {
+            let _permit = self.permits.acquire().await?;
+            self.inner.generate_batch(prompt, n, opts).await
+        })
+    }
+    fn validate(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> { self.inner.validate() }
+    fn name(&self) -> &str { self.inner.name() }
+    fn supports_seed(&self) -> bool { self.inner.supports_seed() }
+    fn supports_init_image(&self) -> bool { self.inner.supports_init_image() }
+    fn model(&self) -> &str { self.inner.model() }
+    fn price_usd_per_image(&self) -> f64 { self.inner.price_usd_per_image() }
+}
+
+/// Tries each provider in order until one succeeds; the last error is returned if none does.
+/// Named after (and priced as) the first provider; `ImageResult::model` shows which one served.
+pub struct FallbackProvider {
+    providers: Vec<Arc<dyn ImageProvider>>,
+}
+impl FallbackProvider {
+    pub fn new(providers: Vec<Arc<dyn ImageProvider>>) -> Result<Self> {
+        anyhow::ensure!(!providers.is_empty(), "fallback chain needs at least one provider");
+        Ok(Self { providers })
+    }
+}
+impl ImageProvider for FallbackProvider {
+    fn generate<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        self.generate_with_opts(prompt, &NO_OPTS)
+    }
+    fn generate_with_opts<'a>(
+        &'a self,
+        prompt: &'a str,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut images = self.generate_batch(prompt, 1, opts).await?;
+            Ok(images.swap_remove(0))
+        })
+    }
+    fn generate_batch<'a>(
+        &'a self,
+        prompt: &'a str,
+        n: u32,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<ImageResult>>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut last_error = None;
+            for (i, p) in self.providers.iter().enumerate() {
+                let r = if n > 1 { p.generate_batch(prompt, n, opts).await } else { p.generate_with_opts(prompt, opts).await.map(|r| vec![r]) };
+                match r {
+                    Ok(mut images) => {
+                        if i > 0 {
+                            for img in &mut images {
+                                img.served_by = Some(ServedBy { provider: p.name().to_string(), price_usd_per_image: p.price_usd_per_image() });
+                            }
+                        }
+                        return Ok(images);
+                    }
+                    Err(e) => {
+                        if i + 1 < self.providers.len() {
+                            tracing::warn!("provider {} ({}) failed, falling back: {e:#}", p.name(), p.model());
+                        }
+                        last_error = Some(e);
+                    }
+                }
+            }
+            Err(last_error.expect("fallback chain is never empty"))
+        })
+    }
+    /// Every entry must validate: a bad key anywhere in the chain is a config error.
+    fn validate(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            for p in &self.providers {
+                p.validate().await.with_context(|| format!("fallback provider {} ({})", p.name(), p.model()))?;
+            }
+            Ok(())
+        })
+    }
+    fn supports_seed(&self) -> bool { self.providers.iter().any(|p| p.supports_seed()) }
+    /// Every entry must use it, or a fallback would quietly drop it.
+    fn supports_init_image(&self) -> bool { self.providers.iter().all(|p| p.supports_init_image()) }
+    /// The primary's; images a fallback served say so in `ImageResult::served_by`.
+    fn name(&self) -> &str { self.providers[0].name() }
+    fn model(&self) -> &str { self.providers[0].model() }
+    fn price_usd_per_image(&self) -> f64 { self.providers[0].price_usd_per_image() }
+}
+
+/// Disk cache in front of a provider: identical requests (provider, model, size,
+/// quality, style, prompt, negative prompt, init image, seed) are answered from
+/// `dir` at no cost.
+/// Without a seed every repeat of a prompt gets the same cached image.
+pub struct CachingProvider {
+    inner: Arc<dyn ImageProvider>,
+    dir: std::path::PathBuf,
+    /// Requested size, part of the key; `0x0` when the provider's default is used.
+    size: String,
+    /// `provider.quality` and `provider.style`; only hashed when set, so keys
+    /// from before they were part of it stay valid.
+    quality: Option<String>,
+    style: Option<String>,
+}
+impl CachingProvider {
+    pub async fn new(inner: Arc<dyn ImageProvider>, dir: std::path::PathBuf, size: String, quality: Option<String>, style: Option<String>) -> Result<Self> {
+        tokio::fs::create_dir_all(&dir).await.with_context(|| format!("creating provider cache dir {}", dir.display()))?;
+        Ok(Self { inner, dir, size, quality, style })
+    }
+
+    fn key(&self, prompt: &str, opts: &GenOpts, batch_pos: u32) -> String {
+        use sha2::{Digest, Sha256};
+        let mut h = Sha256::new();
+        for part in [self.inner.name(), self.inner.model(), &self.size, prompt, opts.negative_prompt.as_deref().unwrap_or("")] {
+            h.update(part.as_bytes());
+            h.update(b"\x1f");
+        }
+        if let Some(init) = &opts.init_image {
+            h.update(&*init.bytes);
+            h.update(init.strength.to_le_bytes());
+        }
+        h.update(b"\x1f");
+        h.update(opts.seed.map_or_else(String::new, |s| s.to_string()).as_bytes());
+        h.update(b"\x1f");
+        h.update(batch_pos.to_le_bytes());
+        for (field, value) in [("quality", &self.quality), ("style", &self.style)] {
+            if let Some(v) = value {
+                h.update(b"\x1f");
+                h.update(format!("{field}={v}").as_bytes());
+            }
+        }
+        format!("{:x}", h.finalize())
+    }
+
+    async fn get(&self, key: &str, prompt: &str) -> Option<ImageResult> {
+        let bytes = tokio::fs::read(self.dir.join(format!("{key}.png"))).await.ok()?;
+        let (width, height) = image::ImageReader::new(std::io::Cursor::new(&bytes)).with_guessed_format().ok()?.into_dimensions().ok()?;
+        let model = tokio::fs::read_to_string(self.dir.join(format!("{key}.model"))).await.unwrap_or_else(|_| self.inner.model().to_string());
+        // cached images are free, whoever served them
+        let served_by = tokio::fs::read_to_string(self.dir.join(format!("{key}.provider"))).await.ok()
+            .map(|provider| ServedBy { provider, price_usd_per_image: 0.0 });
+        Some(ImageResult { bytes, width, height, prompt_used: prompt.to_string(), model, cost_usd: Some(0.0), served_by })
+    }
+
+    /// Write-then-rename so a concurrent reader never sees a partial file; the
+    /// PNG goes last, since `get` looks for it first.
+    async fn put(&self, key: &str, res: &ImageResult) -> Result<()> {
+        let provider = res.served_by.as_ref().map(|s| ("provider", s.provider.as_bytes()));
+        for (ext, data) in [Some(("model", res.model.as_bytes())), provider, Some(("png", &res.bytes[..]))].into_iter().flatten() {
+            let path = self.dir.join(format!("{key}.{ext}"));
+            let tmp = self.dir.join(format!("{key}.{ext}.tmp"));
+            tokio::fs::write(&tmp, data).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+        }
+        Ok(())
+    }
+
+    async fn store(&self, key: &str, res: &ImageResult) {
+        if let Err(e) = self.put(key, res).await {
+            tracing::warn!("provider cache write failed: {e:#}");
+        }
+    }
+}
+impl ImageProvider for CachingProvider {
+    fn generate<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        self.generate_with_opts(prompt, &NO_OPTS)
+    }
+    fn generate_with_opts<'a>(
+        &'a self,
+        prompt: &'a str,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<ImageResult>> + Send + 'a>> {
+        Box::pin(async move {
+            let key = self.key(prompt, opts, 0);
+            if let Some(hit) = self.get(&key, prompt).await {
+                return Ok(hit);
+            }
+            let res = self.inner.generate_with_opts(prompt, opts).await?;
+            self.store(&key, &res).await;
+            Ok(res)
+        })
+    }
+    /// A batch is served from the cache only when every image of it is cached.
+    fn generate_batch<'a>(
+        &'a self,
+        prompt: &'a str,
+        n: u32,
+        opts: &'a GenOpts,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<ImageResult>>> + Send + 'a>> {
+        Box::pin(async move {
+            let keys: Vec<String> = (0..n).map(|k| self.key(prompt, opts, k)).collect();
+            let mut hits = Vec::with_capacity(n as usize);
+            for key in &keys {
+                match self.get(key, prompt).await {
+                    Some(hit) => hits.push(hit),
+                    None => break,
+                }
+            }
+            if hits.len() == keys.len() {
+                return Ok(hits);
+            }
+            let images = self.inner.generate_batch(prompt, n, opts).await?;
+            for (key, res) in keys.iter().zip(&images) {
+                self.store(key, res).await;
+            }
+            Ok(images)
+        })
+    }
+    fn validate(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> { self.inner.validate() }
+    fn supports_seed(&self) -> bool { self.inner.supports_seed() }
+    fn supports_init_image(&self) -> bool { self.inner.supports_init_image() }
+    fn name(&self) -> &str { self.inner.name() }
+    fn model(&self) -> &str { self.inner.model() }
+    fn price_usd_per_image(&self) -> f64 { self.inner.price_usd_per_image() }
+}
@@ -0,0 +1,72 @@
+use anyhow::{Context, Result};
+use base64::Engine as _;
+use std::time::Duration;
+
+use crate::config::AestheticCfg;
+use crate::secrets::SecretSource;
+
+/// Longest side the scorer looks at; larger images are downscaled first.
+const SCORE_MAX: u32 = 512;
+
+/// Sharpness as the variance of the 4-neighbour Laplacian over a grayscale copy.
+/// Higher is sharper; blank or blurry images score near 0. CPU-bound.
+pub fn sharpness(bytes: &[u8]) -> Result<f64> {
+    let img = image::load_from_memory(bytes)?;
+    let img = if img.width().max(img.height()) > SCORE_MAX { img.thumbnail(SCORE_MAX, SCORE_MAX) } else { img };
+    let gray = img.to_luma8();
+    let (w, h) = gray.dimensions();
+    if w < 3 || h < 3 { return Ok(0.0); }
+
+    let px = |x: u32, y: u32| gray.get_pixel(x, y)[0] as f64;
+    let (mut sum, mut sum_sq, mut n) = (0.0, 0.0, 0.0);
+    for y in 1..h - 1 {
+        for x in 1..w - 1 {
+            let lap = px(x - 1, y) + px(x + 1, y) + px(x, y - 1) + px(x, y + 1) - 4.0 * px(x, y);
+            sum += lap;
+            sum_sq += lap * lap;
+            n += 1.0;
+        }
+    }
+    let mean = sum / n;
+    Ok(sum_sq / n - mean * mean)
+}
+
+/// Client for the configured aesthetic-score endpoint (`qa.aesthetic`).
+pub struct AestheticScorer{
+    client: reqwest::Client,
+    endpoint: String,
+    api_key: Option<String>,
+    scale: f64,
+    pub min_score: Option<f64>,
+}
+
+impl AestheticScorer{
+    /// `None` unless `qa.aesthetic.enabled`.
+    pub fn from_cfg(cfg: Option<&AestheticCfg>, secrets: &dyn SecretSource) -> Result<Option<Self>> {
+        let Some(cfg) = cfg.filter(|c| c.enabled) else { return Ok(None) };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(cfg.timeout_ms.unwrap_or(10_000)))
+            .build()?;
+        let api_key = cfg.api_key_env.as_deref().map(|name| secrets.get(name)).transpose()
+            .context("qa.aesthetic.api_key_env")?;
+        Ok(Some(Self{ client, endpoint: cfg.endpoint.clone(), api_key, scale: cfg.scale, min_score: cfg.min_score }))
+    }
+
+    /// Score on a 0–10 scale.
+    pub async fn score(&self, bytes: &[u8]) -> Result<f64> {
+        #[derive(serde::Serialize)] struct Req{ image_b64: String }
+        #[derive(serde::Deserialize)] struct Resp{ score: f64 }
+        let mut req = self.client.post(&self.endpoint)
+            .json(&Req{ image_b64: base64::engine::general_purpose::STANDARD.encode(bytes) });
+        if let Some(key) = &self.api_key {
+            req = req.bearer_auth(key);
+        }
+        let resp: Resp = req.send().await?.error_for_status()?.json().await?;
+        Ok(normalize(resp.score, self.scale))
+    }
+}
+
+fn normalize(raw: f64, scale: f64) -> f64 {
+    if !raw.is_finite() || scale <= 0.0 { return 0.0; }
+    (raw / scale * 10.0).clamp(0.0, 10.0)
+}
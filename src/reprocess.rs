@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use crate::config::PostCfg;
+use crate::events::RunEvent;
+use crate::io::{image_stem, write_suffixed, SIDECARS_JSONL};
+use crate::post::PostProcessor;
+
+/// Sidecars of one run, as they were found on disk.
+pub struct RunSidecars {
+    /// Per-image `*.json` sidecars with their parsed contents.
+    files: Vec<(PathBuf, Value)>,
+    /// All lines of `sidecars.jsonl`, when it holds any of this run's records.
+    consolidated: Option<Vec<Value>>,
+    run_id: String,
+    total: u64,
+}
+
+impl RunSidecars {
+    pub fn total(&self) -> u64 { self.total }
+}
+
+/// Find every sidecar belonging to `run_id` in `out_dir`.
+pub async fn find_run(out_dir: &Path, run_id: &str) -> Result<RunSidecars> {
+    let belongs = |v: &Value| v.get("run_id").and_then(Value::as_str) == Some(run_id);
+    let mut found = RunSidecars { files: vec![], consolidated: None, run_id: run_id.to_string(), total: 0 };
+    let mut rd = tokio::fs::read_dir(out_dir).await?;
+    while let Some(entry) = rd.next_entry().await? {
+        let path = entry.path();
+        if path.file_name().and_then(|s| s.to_str()) == Some(SIDECARS_JSONL) {
+            let txt = tokio::fs::read_to_string(&path).await?;
+            let lines: Vec<Value> = txt.lines().filter_map(|l| serde_json::from_str(l).ok()).collect();
+            let n = lines.iter().filter(|v| belongs(v)).count() as u64;
+            if n > 0 {
+                found.total += n;
+                found.consolidated = Some(lines);
+            }
+            continue;
+        }
+        if path.extension().and_then(|s| s.to_str()) != Some("json") { continue; }
+        let Ok(bytes) = tokio::fs::read(&path).await else { continue };
+        match serde_json::from_slice::<Value>(&bytes) {
+            Ok(v) if belongs(&v) => {
+                found.total += 1;
+                found.files.push((path, v));
+            }
+            _ => {}
+        }
+    }
+    Ok(found)
+}
+
+/// `post` settings that are baked into saved images and can't be re-applied to
+/// them (the unprocessed originals aren't kept), by config name.
+pub fn baked_in(post: &PostCfg) -> Vec<&'static str> {
+    [
+        ("aspect_ratio", post.aspect_ratio.is_some()),
+        ("brightness", post.brightness.is_some()),
+        ("contrast", post.contrast.is_some()),
+        ("sharpen", post.sharpen.is_some()),
+        ("watermark", post.watermark.is_some()),
+        ("frame", post.frame.is_some()),
+        ("corner_radius", post.corner_radius.is_some()),
+    ].into_iter().filter_map(|(name, set)| set.then_some(name)).collect()
+}
+
+/// Re-run post-processing with `post` over a run's images, rewriting thumbnails and
+/// augmented copies and updating their sidecars. Progress is reported as `job_id`.
+/// Fails if `post` sets anything `baked_in`.
+pub async fn reprocess_run(out_dir: PathBuf, mut run: RunSidecars, post: PostCfg, job_id: String, events: broadcast::Sender<RunEvent>) -> Result<()> {
+    let baked = baked_in(&post);
+    if !baked.is_empty() {
+        anyhow::bail!("post.{} can't be applied to saved images", baked.join(", post."));
+    }
+    let processor = Arc::new(PostProcessor::new(&post)?);
+    let total = run.total;
+    let _ = events.send(RunEvent::Started { run_id: job_id.clone(), total });
+    let log = |msg: String| { let _ = events.send(RunEvent::Log { run_id: job_id.clone(), msg }); };
+    let mut done = 0;
+    let progress = |done: u64| {
+        let _ = events.send(RunEvent::Progress { run_id: job_id.clone(), done, total, cost_so_far: 0.0, queued: 0, in_flight: 0 });
+    };
+
+    for (path, sidecar) in &mut run.files {
+        if let Err(e) = reprocess_one(&out_dir, sidecar, &processor).await {
+            log(format!("{}: {e:#}", path.display()));
+            continue;
+        }
+        let bytes = serde_json::to_vec_pretty(sidecar)?;
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, bytes).await?;
+        tokio::fs::rename(&tmp, &*path).await?;
+        done += 1;
+        progress(done);
+    }
+
+    if let Some(lines) = &mut run.consolidated {
+        for sidecar in lines.iter_mut().filter(|v| v.get("run_id").and_then(Value::as_str) == Some(run.run_id.as_str())) {
+            match reprocess_one(&out_dir, sidecar, &processor).await {
+                Ok(()) => { done += 1; progress(done); }
+                Err(e) => log(format!("{SIDECARS_JSONL} #{}: {e:#}", sidecar.get("id").unwrap_or(&Value::Null))),
+            }
+        }
+        let mut buf = Vec::new();
+        for v in lines.iter() {
+            serde_json::to_writer(&mut buf, v)?;
+            buf.push(b'\n');
+        }
+        let path = out_dir.join(SIDECARS_JSONL);
+        let tmp = out_dir.join(format!("{SIDECARS_JSONL}.tmp"));
+        tokio::fs::write(&tmp, buf).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+    }
+
+    let _ = events.send(RunEvent::Finished { run_id: job_id, outcome: None });
+    Ok(())
+}
+
+async fn reprocess_one(out_dir: &Path, sidecar: &mut Value, processor: &Arc<PostProcessor>) -> Result<()> {
+    let field = |k: &str| sidecar.get(k).and_then(Value::as_str).map(str::to_string).with_context(|| format!("sidecar has no `{k}`"));
+    let id = sidecar.get("id").and_then(Value::as_u64).context("sidecar has no `id`")?;
+    let batch_index = sidecar.get("batch_index").and_then(Value::as_u64).map(|k| k as u32);
+    let stem = image_stem(id, batch_index, &field("provider")?, &field("model")?);
+    let bytes = tokio::fs::read(out_dir.join(format!("{stem}.png"))).await.with_context(|| format!("read {stem}.png"))?;
+
+    let p = processor.clone();
+    let (thumb, derivatives, palette) = tokio::task::spawn_blocking(move || -> Result<_> {
+        Ok((p.maybe_thumbnail(&bytes)?, p.augment(&bytes)?, crate::post::dominant_colors(&bytes, p.palette_size)?))
+    }).await??;
+
+    // old outputs the new settings no longer produce
+    let mut stale: Vec<String> = sidecar.get("derivatives").and_then(Value::as_array).into_iter().flatten()
+        .filter_map(|d| d.get("path").and_then(Value::as_str).map(str::to_string))
+        .collect();
+    stale.extend(sidecar.get("thumbnail_path").and_then(Value::as_str).map(str::to_string));
+
+    let obj = sidecar.as_object_mut().context("sidecar is not an object")?;
+    match thumb {
+        Some(t) => { obj.insert("thumbnail_path".into(), write_suffixed(out_dir, &stem, "thumb", &t).await?.into()); }
+        None => { obj.remove("thumbnail_path"); }
+    }
+    let mut written = Vec::with_capacity(derivatives.len());
+    for (n, (op, bytes)) in derivatives.iter().enumerate() {
+        let path = write_suffixed(out_dir, &stem, &format!("aug{}", n + 1), bytes).await?;
+        written.push(serde_json::json!({ "op": op, "path": path }));
+    }
+    if written.is_empty() { obj.remove("derivatives"); } else { obj.insert("derivatives".into(), written.into()); }
+    if palette.is_empty() { obj.remove("palette"); } else { obj.insert("palette".into(), palette.into()); }
+
+    let kept: Vec<&str> = [obj.get("thumbnail_path")].into_iter().flatten()
+        .chain(obj.get("derivatives").and_then(Value::as_array).into_iter().flatten().filter_map(|d| d.get("path")))
+        .filter_map(Value::as_str)
+        .collect();
+    for name in stale.iter().filter(|n| !kept.contains(&n.as_str()) && !n.contains(['/', '\\'])) {
+        let _ = tokio::fs::remove_file(out_dir.join(name)).await;
+    }
+    Ok(())
+}
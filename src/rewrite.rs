@@ -1,7 +1,7 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{future::Future, path::PathBuf, pin::Pin, sync::Arc};
+use std::{future::Future, path::PathBuf, pin::Pin, sync::{atomic::{AtomicU64, Ordering}, Arc}};
 use tokio::{fs, io::{AsyncBufReadExt, AsyncWriteExt}, sync::Mutex};
 
 pub trait PromptRewriter: Send + Sync {
@@ -9,7 +9,22 @@ pub trait PromptRewriter: Send + Sync {
         &'a self,
         original: &'a str,
     ) -> Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>>;
+    /// Rewrite several prompts, results in the same order. The default makes one
+    /// `rewrite` call per prompt; backends that can answer for all of them in one
+    /// request override it.
+    fn rewrite_batch<'a>(
+        &'a self,
+        originals: &'a [String],
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut out = Vec::with_capacity(originals.len());
+            for original in originals { out.push(self.rewrite(original).await?); }
+            Ok(out)
+        })
+    }
     fn name(&self) -> &'static str;
+    /// Total tokens billed so far across all calls (0 if the backend doesn't report usage).
+    fn tokens_used(&self) -> u64 { 0 }
 }
 
 pub struct NoopRewriter;
@@ -24,15 +39,52 @@ impl PromptRewriter for NoopRewriter {
     fn name(&self) -> &'static str { "noop" }
 }
 
-pub struct OpenAIRewriter{ client: reqwest::Client, api_key: String, model: String, system: String, max_tokens: u32 }
+pub struct OpenAIRewriter{ client: reqwest::Client, api_key: String, model: String, system: String, max_tokens: u32, sampling: Sampling, examples: Vec<(String, String)>, tokens_used: AtomicU64 }
 impl OpenAIRewriter{
-    pub fn new(api_key:String, model:String, system:String, max_tokens:u32)->Self{
-        Self{ client:reqwest::Client::new(), api_key, model, system, max_tokens }
+    pub fn new(client: reqwest::Client, api_key:String, model:String, system:String, max_tokens:u32)->Self{
+        Self{ client, api_key, model, system, max_tokens, sampling: Sampling::default(), examples: Vec::new(), tokens_used: AtomicU64::new(0) }
     }
+    /// Sampling settings sent with every request; unset ones are left to the API default.
+    pub fn with_sampling(mut self, sampling: Sampling)->Self{ self.sampling = sampling; self }
+    /// Few-shot `(before, after)` pairs sent ahead of every prompt.
+    pub fn with_examples(mut self, examples: Vec<(String, String)>)->Self{ self.examples = examples; self }
+}
+
+/// Optional `temperature` / `top_p`, only serialized when set.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Sampling{
+    #[serde(skip_serializing_if = "Option::is_none")] pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub top_p: Option<f32>,
+}
+#[derive(Serialize)] struct ChatReq<'a>{
+    model:&'a str, messages:Vec<Msg<'a>>, max_tokens:u32, #[serde(flatten)] sampling:Sampling,
+    #[serde(skip_serializing_if = "Option::is_none")] response_format:Option<ResponseFormat>,
 }
-#[derive(Serialize)] struct ChatReq<'a>{ model:&'a str, messages:Vec<Msg<'a>>, max_tokens:u32 }
+#[derive(Serialize)] struct ResponseFormat{ r#type:&'static str }
+/// What a batch rewrite must answer with.
+#[derive(Deserialize)] struct BatchOut{ prompts:Vec<String> }
 #[derive(Serialize)] struct Msg<'a>{ role:&'a str, content:&'a str }
-#[derive(Deserialize)] struct ChatResp{ choices:Vec<Choice> }
+
+/// System message, then each example as a user/assistant exchange, then the prompt.
+fn chat_messages<'a>(system:&'a str, examples:&'a [(String, String)], user:&'a str)->Vec<Msg<'a>>{
+    let mut messages = vec![Msg{role:"system", content:system}];
+    for (before, after) in examples {
+        messages.push(Msg{role:"user", content:before});
+        messages.push(Msg{role:"assistant", content:after});
+    }
+    messages.push(Msg{role:"user", content:user});
+    messages
+}
+/// `examples` as a single batch exchange: the befores as a JSON array, answered
+/// with `{"prompts": [afters]}`, so they show the format a batch reply must take.
+fn batch_examples(examples:&[(String, String)])->Result<Vec<(String, String)>>{
+    if examples.is_empty() { return Ok(vec![]); }
+    let before = serde_json::to_string(&examples.iter().map(|e| &e.0).collect::<Vec<_>>())?;
+    let after = serde_json::json!({ "prompts": examples.iter().map(|e| &e.1).collect::<Vec<_>>() }).to_string();
+    Ok(vec![(before, after)])
+}
+#[derive(Deserialize)] struct ChatResp{ choices:Vec<Choice>, #[serde(default)] usage:Option<Usage> }
+#[derive(Deserialize)] struct Usage{ total_tokens:u64 }
 #[derive(Deserialize)] struct Choice{ message: MsgOwned }
 #[derive(Deserialize)] struct MsgOwned{ #[allow(unused)] role:String, content:String }
 
@@ -45,19 +97,98 @@ impl PromptRewriter for OpenAIRewriter {
             let req = ChatReq{
                 model:&self.model,
                 max_tokens:self.max_tokens,
-                messages:vec![
-                    Msg{role:"system", content:&self.system},
-                    Msg{role:"user", content:original},
-                ],
+                sampling:self.sampling,
+                messages:chat_messages(&self.system, &self.examples, original),
+                response_format:None,
             };
             let resp = self.client.post("https://api.openai.com/v1/chat/completions")
                 .bearer_auth(&self.api_key)
                 .json(&req).send().await?.error_for_status()?.json::<ChatResp>().await?;
+            if let Some(usage) = &resp.usage {
+                self.tokens_used.fetch_add(usage.total_tokens, Ordering::Relaxed);
+            }
             Ok(resp.choices.get(0).map(|c| c.message.content.clone()).unwrap_or_else(|| original.to_string()))
         })
     }
 
+    /// One chat call for the whole batch: the prompts go out as a JSON array and
+    /// must come back as `{"prompts": [...]}` of the same length.
+    fn rewrite_batch<'a>(
+        &'a self,
+        originals: &'a [String],
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send + 'a>> {
+        Box::pin(async move {
+            match originals {
+                [] => return Ok(vec![]),
+                [original] => return Ok(vec![self.rewrite(original).await?]),
+                _ => {}
+            }
+            let system = format!(
+                "{}\n\nThe user sends a JSON array of prompts. Apply the instructions above to each one and reply with a JSON object {{\"prompts\": [...]}} holding the rewritten prompts in the same order.",
+                self.system
+            );
+            let user = serde_json::to_string(originals)?;
+            let examples = batch_examples(&self.examples)?;
+            let req = ChatReq{
+                model:&self.model,
+                max_tokens:self.max_tokens.saturating_mul(originals.len() as u32),
+                sampling:self.sampling,
+                messages:chat_messages(&system, &examples, &user),
+                response_format:Some(ResponseFormat{ r#type:"json_object" }),
+            };
+            let resp = self.client.post("https://api.openai.com/v1/chat/completions")
+                .bearer_auth(&self.api_key)
+                .json(&req).send().await?.error_for_status()?.json::<ChatResp>().await?;
+            if let Some(usage) = &resp.usage {
+                self.tokens_used.fetch_add(usage.total_tokens, Ordering::Relaxed);
+            }
+            let content = resp.choices.first().map(|c| c.message.content.as_str()).unwrap_or_default();
+            let out: BatchOut = serde_json::from_str(content).context("batch rewrite reply isn't the requested JSON")?;
+            if out.prompts.len() != originals.len() {
+                anyhow::bail!("batch rewrite returned {} prompts for {}", out.prompts.len(), originals.len());
+            }
+            Ok(out.prompts)
+        })
+    }
+
     fn name(&self) -> &'static str { "openai-rewriter" }
+    fn tokens_used(&self) -> u64 { self.tokens_used.load(Ordering::Relaxed) }
+}
+
+pub struct OllamaRewriter{ client: reqwest::Client, base_url: String, model: String, system: String, max_tokens: u32, sampling: Sampling, examples: Vec<(String, String)>, tokens_used: AtomicU64 }
+impl OllamaRewriter{
+    pub fn new(client: reqwest::Client, base_url:String, model:String, system:String, max_tokens:u32)->Self{
+        Self{ client, base_url, model, system, max_tokens, sampling: Sampling::default(), examples: Vec::new(), tokens_used: AtomicU64::new(0) }
+    }
+    pub fn with_sampling(mut self, sampling: Sampling)->Self{ self.sampling = sampling; self }
+    pub fn with_examples(mut self, examples: Vec<(String, String)>)->Self{ self.examples = examples; self }
+}
+#[derive(Serialize)] struct OllamaReq<'a>{ model:&'a str, messages:Vec<Msg<'a>>, stream:bool, options:OllamaOptions }
+#[derive(Serialize)] struct OllamaOptions{ num_predict:u32, #[serde(flatten)] sampling:Sampling }
+#[derive(Deserialize)] struct OllamaResp{ message: MsgOwned, #[serde(default)] prompt_eval_count:u64, #[serde(default)] eval_count:u64 }
+
+impl PromptRewriter for OllamaRewriter {
+    fn rewrite<'a>(
+        &'a self,
+        original: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>> {
+        Box::pin(async move {
+            let req = OllamaReq{
+                model:&self.model,
+                messages:chat_messages(&self.system, &self.examples, original),
+                stream:false,
+                options:OllamaOptions{ num_predict:self.max_tokens, sampling:self.sampling },
+            };
+            let resp = self.client.post(format!("{}/api/chat", self.base_url.trim_end_matches('/')))
+                .json(&req).send().await?.error_for_status()?.json::<OllamaResp>().await?;
+            self.tokens_used.fetch_add(resp.prompt_eval_count + resp.eval_count, Ordering::Relaxed);
+            let content = resp.message.content.trim();
+            Ok(if content.is_empty() { original.to_string() } else { content.to_string() })
+        })
+    }
+
+    fn name(&self) -> &'static str { "ollama-rewriter" }
+    fn tokens_used(&self) -> u64 { self.tokens_used.load(Ordering::Relaxed) }
 }
 
 pub struct RewriteCache{ path: PathBuf, map: Arc<Mutex<std::collections::HashMap<String,String>>> }
@@ -85,12 +216,59 @@ impl RewriteCache{
     }
 }
 
-pub fn cache_key(original:&str, rewriter_name:&str, model:&str, system:&str)->String{
+pub fn cache_key(original:&str, rewriter_name:&str, model:&str, system:&str, examples:&[(String, String)], sampling: Sampling)->String{
     let mut h = Sha256::new();
     h.update(rewriter_name.as_bytes());
     h.update(model.as_bytes());
     h.update(system.as_bytes());
+    // nothing extra without examples or sampling settings, so existing cache files keep their keys
+    for (before, after) in examples {
+        h.update(b"\x1e");
+        h.update(before.as_bytes());
+        h.update(b"\x1f");
+        h.update(after.as_bytes());
+    }
+    for (name, value) in [("temperature", sampling.temperature), ("top_p", sampling.top_p)] {
+        if let Some(v) = value {
+            h.update(b"\x1d");
+            h.update(format!("{name}={v}").as_bytes());
+        }
+    }
     h.update(b"\x1f");
     h.update(original.as_bytes());
     format!("{:x}", h.finalize())
 }
+
+/// USD spent on rewriting so far, from reported token usage.
+pub fn rewrite_cost(rw: &dyn PromptRewriter, price_per_1k_tokens: f64) -> f64 {
+    rw.tokens_used() as f64 / 1000.0 * price_per_1k_tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling_changes_the_cache_key_only_when_set() {
+        let key = |sampling| cache_key("a soda ad", "openai", "gpt-4o-mini", "be brief", &[], sampling);
+        // the key from before sampling settings were part of it
+        let mut h = Sha256::new();
+        for part in ["openai", "gpt-4o-mini", "be brief", "\x1f", "a soda ad"] { h.update(part.as_bytes()); }
+        assert_eq!(key(Sampling::default()), format!("{:x}", h.finalize()));
+
+        let warm = key(Sampling { temperature: Some(0.9), top_p: None });
+        assert_ne!(warm, key(Sampling::default()));
+        assert_ne!(warm, key(Sampling { temperature: Some(0.2), top_p: None }));
+        assert_ne!(warm, key(Sampling { temperature: None, top_p: Some(0.9) }));
+    }
+
+    #[test]
+    fn batch_examples_answer_in_the_batch_format() {
+        let examples = vec![("soda ad".to_string(), "a \"neon\" soda ad".to_string()), ("shoe ad".to_string(), "a retro shoe ad".to_string())];
+        let [(before, after)] = &batch_examples(&examples).unwrap()[..] else { panic!("want one exchange") };
+        assert_eq!(serde_json::from_str::<Vec<String>>(before).unwrap(), ["soda ad", "shoe ad"]);
+        let out: BatchOut = serde_json::from_str(after).unwrap();
+        assert_eq!(out.prompts, ["a \"neon\" soda ad", "a retro shoe ad"]);
+        assert!(batch_examples(&[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+
+use crate::config::{SecretsBackend, SecretsCfg};
+
+/// Where API keys come from. `name` is what configs call the key, e.g. `OPENAI_API_KEY`.
+pub trait SecretSource: Send + Sync {
+    fn get(&self, name: &str) -> Result<String>;
+}
+
+/// Environment variables (the default).
+pub struct EnvSecrets;
+
+impl SecretSource for EnvSecrets {
+    fn get(&self, name: &str) -> Result<String> {
+        std::env::var(name).with_context(|| format!("environment variable {name} is not set"))
+    }
+}
+
+/// One file per secret, named after it, e.g. `/run/secrets/OPENAI_API_KEY`
+/// (the Docker/Kubernetes layout). Surrounding whitespace is trimmed.
+pub struct FileSecrets { pub dir: PathBuf }
+
+impl SecretSource for FileSecrets {
+    fn get(&self, name: &str) -> Result<String> {
+        if name.contains(['/', '\\']) || name.starts_with('.') {
+            anyhow::bail!("invalid secret name `{name}`");
+        }
+        let path = self.dir.join(name);
+        let value = std::fs::read_to_string(&path).with_context(|| format!("read secret {}", path.display()))?;
+        Ok(value.trim().to_string())
+    }
+}
+
+pub fn from_cfg(cfg: &SecretsCfg) -> Result<Box<dyn SecretSource>> {
+    Ok(match cfg.backend {
+        SecretsBackend::Env => Box::new(EnvSecrets),
+        SecretsBackend::File => Box::new(FileSecrets {
+            dir: cfg.dir.clone().context("secrets.backend: file needs secrets.dir")?,
+        }),
+    })
+}
@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::config::{StorageCfg, StorageKind};
+
+/// Where saved images, their thumbnails/derivatives and per-image sidecars end
+/// up. Run bookkeeping (manifest, checkpoint, snapshots) always stays in `out_dir`.
+pub trait ObjectSink: Send + Sync {
+    /// Store `bytes` as `name`. Returns a URL for remote stores; `None` for the
+    /// local disk, where the file name already locates it.
+    fn put<'a>(
+        &'a self,
+        name: &'a str,
+        bytes: Vec<u8>,
+        content_type: &'static str,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send + 'a>>;
+
+    /// Read back what `put` stored as `name`.
+    fn get<'a>(&'a self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>>;
+}
+
+/// Files in `out_dir`, each written to a `.tmp` and renamed into place.
+pub struct LocalSink{ dir: PathBuf }
+
+impl LocalSink {
+    pub fn new(dir: &Path) -> Self { Self{ dir: dir.to_path_buf() } }
+}
+
+impl ObjectSink for LocalSink {
+    fn put<'a>(&'a self, name: &'a str, bytes: Vec<u8>, _content_type: &'static str) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send + 'a>> {
+        Box::pin(async move {
+            let tmp = self.dir.join(format!("{name}.tmp"));
+            {
+                let mut f = fs::File::create(&tmp).await?;
+                f.write_all(&bytes).await?;
+                let _ = f.sync_all().await;
+            }
+            fs::rename(&tmp, self.dir.join(name)).await?;
+            Ok(None)
+        })
+    }
+
+    fn get<'a>(&'a self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async move {
+            let path = self.dir.join(name);
+            fs::read(&path).await.with_context(|| format!("read {}", path.display()))
+        })
+    }
+}
+
+/// Objects in an S3 (or S3-compatible, e.g. MinIO) bucket under `prefix`.
+/// Credentials come from the usual AWS sources (environment, profile, instance role).
+pub struct S3Sink{
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: String,
+    public_url: Option<String>,
+}
+
+impl S3Sink {
+    pub async fn new(cfg: &StorageCfg) -> Result<Self> {
+        if cfg.bucket.trim().is_empty() { anyhow::bail!("storage.bucket is required for storage.kind: s3"); }
+        let mut loader = aws_config::from_env();
+        if let Some(region) = &cfg.region { loader = loader.region(aws_config::Region::new(region.clone())); }
+        let shared = loader.load().await;
+        let mut s3 = aws_sdk_s3::config::Builder::from(&shared);
+        if let Some(endpoint) = &cfg.endpoint {
+            // MinIO and most self-hosted stores don't do virtual-hosted buckets
+            s3 = s3.endpoint_url(endpoint).force_path_style(true);
+        }
+        Ok(Self{
+            client: aws_sdk_s3::Client::from_conf(s3.build()),
+            bucket: cfg.bucket.clone(),
+            prefix: cfg.prefix.trim_matches('/').to_string(),
+            public_url: cfg.public_url.as_ref().map(|u| u.trim_end_matches('/').to_string()),
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        if self.prefix.is_empty() { name.to_string() } else { format!("{}/{name}", self.prefix) }
+    }
+}
+
+impl ObjectSink for S3Sink {
+    fn put<'a>(&'a self, name: &'a str, bytes: Vec<u8>, content_type: &'static str) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send + 'a>> {
+        Box::pin(async move {
+            let key = self.key(name);
+            self.client.put_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .content_type(content_type)
+                .body(aws_sdk_s3::primitives::ByteStream::from(bytes))
+                .send()
+                .await
+                .with_context(|| format!("upload s3://{}/{key}", self.bucket))?;
+            Ok(Some(match &self.public_url {
+                Some(base) => format!("{base}/{key}"),
+                None => format!("s3://{}/{key}", self.bucket),
+            }))
+        })
+    }
+
+    fn get<'a>(&'a self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async move {
+            let key = self.key(name);
+            let obj = self.client.get_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .send()
+                .await
+                .with_context(|| format!("download s3://{}/{key}", self.bucket))?;
+            let body = obj.body.collect().await.with_context(|| format!("download s3://{}/{key}", self.bucket))?;
+            Ok(body.into_bytes().to_vec())
+        })
+    }
+}
+
+/// The sink `storage` selects; `out_dir` is where the local one writes.
+pub async fn make_sink(storage: &StorageCfg, out_dir: &Path) -> Result<Arc<dyn ObjectSink>> {
+    Ok(match storage.kind {
+        StorageKind::Local => Arc::new(LocalSink::new(out_dir)),
+        StorageKind::S3 => Arc::new(S3Sink::new(storage).await?),
+    })
+}
@@ -0,0 +1,87 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::config::{RunCfg, TemplateYaml};
+
+const POLL: Duration = Duration::from_millis(500);
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+type Stamps = (Option<SystemTime>, Option<SystemTime>);
+
+/// Regenerate a small sample into `scratch_dir` every time the config or
+/// template is saved. A newer save cancels a sample that is still running.
+pub async fn watch(config: PathBuf, template: PathBuf, sample: u64, scratch_dir: PathBuf) -> Result<()> {
+    println!("👀 Watching {} and {} (Ctrl-C to stop)", config.display(), template.display());
+    let mut seen = stamps(&config, &template).await;
+    loop {
+        let run = run_sample(&config, &template, sample, &scratch_dir);
+        tokio::pin!(run);
+        let restart = tokio::select! {
+            res = &mut run => {
+                match res {
+                    Ok(dir) => print_sample(&dir).await,
+                    Err(e) => eprintln!("❌ Sample failed: {e:#}"),
+                }
+                false
+            }
+            _ = wait_for_change(&config, &template, &mut seen) => true,
+        };
+        if restart {
+            println!("\n↻ Change detected, restarting sample");
+            continue;
+        }
+        wait_for_change(&config, &template, &mut seen).await;
+        println!("\n↻ Change detected");
+    }
+}
+
+async fn run_sample(config: &Path, template: &Path, sample: u64, scratch_dir: &Path) -> Result<PathBuf> {
+    let mut cfg: RunCfg = serde_yaml::from_str(&tokio::fs::read_to_string(config).await?)?;
+    let tpl_yaml: TemplateYaml = serde_yaml::from_str(&tokio::fs::read_to_string(template).await?)?;
+    cfg.orchestrator.target_images = sample;
+    let run_id = format!("watch-{}", chrono::Utc::now().format("%Y%m%d-%H%M%S-%3f"));
+    let out_dir = scratch_dir.join(&run_id);
+    let overrides = crate::RunOverrides { out_dir: Some(out_dir.clone()), ..Default::default() };
+    crate::run_loaded(cfg, tpl_yaml, overrides, run_id, None).await?;
+    Ok(out_dir)
+}
+
+async fn print_sample(dir: &Path) {
+    let Ok(txt) = tokio::fs::read_to_string(dir.join(crate::manifest::MANIFEST_FILE)).await else { return; };
+    println!("📂 {}", dir.display());
+    for line in txt.lines() {
+        let Ok(rec) = serde_json::from_str::<serde_json::Value>(line) else { continue; };
+        if rec.get("path_png").is_none() { continue; } // dropped or failed
+        println!(
+            "  {}  {}",
+            rec["path_png"].as_str().unwrap_or("?"),
+            rec["prompt"].as_str().unwrap_or(""),
+        );
+    }
+}
+
+/// Resolve once either file's mtime changes and then holds still for `DEBOUNCE`.
+async fn wait_for_change(config: &Path, template: &Path, seen: &mut Stamps) {
+    loop {
+        tokio::time::sleep(POLL).await;
+        let mut now = stamps(config, template).await;
+        if now == *seen { continue; }
+        loop {
+            tokio::time::sleep(DEBOUNCE).await;
+            let again = stamps(config, template).await;
+            if again == now { break; }
+            now = again;
+        }
+        *seen = now;
+        return;
+    }
+}
+
+async fn stamps(config: &Path, template: &Path) -> Stamps {
+    (mtime(config).await, mtime(template).await)
+}
+
+async fn mtime(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok().and_then(|m| m.modified().ok())
+}